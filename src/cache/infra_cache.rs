@@ -0,0 +1,195 @@
+// Per name server round trip statistics used to pick the upstream server to
+// query, in the spirit of the BIND and Unbound infrastructure caches
+
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use rand::{thread_rng, Rng};
+
+// Servers we know nothing about are assumed to be moderately fast so that
+// they get tried before known slow ones
+const UNKNOWN_SRTT: Duration = Duration::from_millis(376);
+const MAX_SRTT: Duration = Duration::from_secs(10);
+// Statistics are forgotten after a while, giving penalized servers another chance
+const ENTRY_TTL: Duration = Duration::from_secs(900);
+// Share of queries sent to a server other than the fastest one to keep its RTT fresh
+const PROBE_PROBABILITY: f64 = 0.05;
+// Servers tracked at most, so that referrals to ever new addresses cannot
+// grow the cache without bound
+const MAX_ENTRIES: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ServerStats {
+    pub srtt: Duration,
+    pub failures: u32,
+    pub successes: u64,
+    pub last_updated: Instant,
+}
+
+#[derive(Debug)]
+pub struct InfraCache {
+    servers: RwLock<HashMap<Ipv4Addr, ServerStats>>,
+    max_entries: usize,
+}
+
+impl InfraCache {
+    pub fn new() -> InfraCache {
+        InfraCache {
+            servers: RwLock::new(HashMap::new()),
+            max_entries: MAX_ENTRIES,
+        }
+    }
+
+    pub fn get(&self, addr: Ipv4Addr) -> Option<ServerStats> {
+        let stats = *self.servers.read().unwrap().get(&addr)?;
+        if stats.last_updated.elapsed() > ENTRY_TTL {
+            return None;
+        }
+
+        Some(stats)
+    }
+
+    pub fn snapshot(&self) -> Vec<(Ipv4Addr, ServerStats)> {
+        let mut servers: Vec<(Ipv4Addr, ServerStats)> = self
+            .servers
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(_, stats)| stats.last_updated.elapsed() <= ENTRY_TTL)
+            .map(|(addr, stats)| (*addr, *stats))
+            .collect();
+
+        servers.sort_by_key(|(_, stats)| stats.srtt);
+        servers
+    }
+
+    // Folds a new sample into the smoothed RTT using the same 1/8 gain as TCP
+    pub fn record_success(&self, addr: Ipv4Addr, rtt: Duration) {
+        let now = Instant::now();
+        let previous = self.get(addr);
+
+        let stats = ServerStats {
+            srtt: match previous {
+                Some(stats) => (stats.srtt * 7 + rtt) / 8,
+                None => rtt,
+            }
+            .min(MAX_SRTT),
+            failures: 0,
+            successes: previous.map_or(0, |stats| stats.successes) + 1,
+            last_updated: now,
+        };
+
+        self.insert(addr, stats);
+    }
+
+    // Doubles the smoothed RTT of a server that timed out or failed to answer
+    pub fn record_failure(&self, addr: Ipv4Addr) {
+        let now = Instant::now();
+        let previous = self.get(addr);
+
+        let stats = ServerStats {
+            srtt: (previous.map_or(UNKNOWN_SRTT, |stats| stats.srtt) * 2).min(MAX_SRTT),
+            failures: previous.map_or(0, |stats| stats.failures) + 1,
+            successes: previous.map_or(0, |stats| stats.successes),
+            last_updated: now,
+        };
+
+        self.insert(addr, stats);
+    }
+
+    // Forgets expired servers when the cache is full, and the one updated
+    // the longest ago if none expired
+    fn insert(&self, addr: Ipv4Addr, stats: ServerStats) {
+        let mut servers = self.servers.write().unwrap();
+
+        if servers.len() >= self.max_entries && !servers.contains_key(&addr) {
+            servers.retain(|_, stats| stats.last_updated.elapsed() <= ENTRY_TTL);
+
+            if servers.len() >= self.max_entries {
+                let oldest = servers
+                    .iter()
+                    .min_by_key(|(_, stats)| stats.last_updated)
+                    .map(|(addr, _)| *addr);
+                if let Some(oldest) = oldest {
+                    servers.remove(&oldest);
+                }
+            }
+        }
+
+        servers.insert(addr, stats);
+    }
+
+    // Orders servers from the fastest to the slowest, occasionally moving
+    // another one to the front to probe it
+    pub fn order(&self, servers: &[Ipv4Addr]) -> Vec<Ipv4Addr> {
+        let mut rng = thread_rng();
+
+        let mut ordered: Vec<(Ipv4Addr, Duration, u32)> = Vec::new();
+        for addr in servers {
+            if ordered.iter().any(|(known, _, _)| known == addr) {
+                continue;
+            }
+
+            let srtt = self.get(*addr).map_or(UNKNOWN_SRTT, |stats| stats.srtt);
+            ordered.push((*addr, srtt, rng.gen()));
+        }
+
+        // The random tiebreaker spreads load across servers with the same SRTT
+        ordered.sort_by_key(|(_, srtt, tiebreaker)| (*srtt, *tiebreaker));
+
+        if ordered.len() > 1 && rng.gen_bool(PROBE_PROBABILITY) {
+            let idx = rng.gen_range(1..ordered.len());
+            ordered.swap(0, idx);
+        }
+
+        ordered.into_iter().map(|(addr, _, _)| addr).collect()
+    }
+}
+
+impl Default for InfraCache {
+    fn default() -> InfraCache {
+        InfraCache::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stays_within_its_size() {
+        let cache = InfraCache {
+            servers: RwLock::new(HashMap::new()),
+            max_entries: 2,
+        };
+        let addr = |last| Ipv4Addr::new(192, 0, 2, last);
+
+        cache.record_success(addr(1), Duration::from_millis(10));
+        cache.record_failure(addr(2));
+        cache.record_success(addr(1), Duration::from_millis(10));
+        cache.record_success(addr(3), Duration::from_millis(10));
+
+        assert!(cache.get(addr(1)).is_some());
+        assert!(cache.get(addr(2)).is_none());
+        assert!(cache.get(addr(3)).is_some());
+
+        // Expired servers go first
+        let expired = Instant::now().checked_sub(ENTRY_TTL * 2);
+        if let Some(expired) = expired {
+            cache
+                .servers
+                .write()
+                .unwrap()
+                .get_mut(&addr(3))
+                .unwrap()
+                .last_updated = expired;
+            cache.record_failure(addr(4));
+
+            assert!(cache.get(addr(1)).is_some());
+            assert_eq!(cache.servers.read().unwrap().len(), 2);
+            assert!(!cache.servers.read().unwrap().contains_key(&addr(3)));
+        }
+    }
+}
@@ -0,0 +1,2 @@
+pub mod infra_cache;
+pub mod record_cache;
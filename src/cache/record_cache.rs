@@ -0,0 +1,917 @@
+// Shared TTL-aware cache of RRsets keyed by (name, type, class), along with
+// negative answers as per RFC 2308. Each RRset is ranked by where it came
+// from, so that referrals and glue neither replace answers nor are handed
+// out as answers (RFC 2181 5.4.1)
+
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::net::Ipv4Addr;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use crate::models::{
+    dns_packet::DnsPacket,
+    dns_record::{canonical_order, DnsRecord},
+    query_class::QueryClass,
+    query_type::QueryType,
+    result_code::ResultCode,
+};
+use crate::utils::domain_name::{label_count, parent};
+
+const DEFAULT_MAX_ENTRIES: usize = 10_000;
+// RFC 8767 recommends capping TTLs at 7 days
+const DEFAULT_MAX_TTL: u32 = 604_800;
+// RFC 2308 5: negative answers should not be cached for more than a few hours
+const DEFAULT_MAX_NEGATIVE_TTL: u32 = 10_800;
+// TTL of expired data handed out by serve-stale (RFC 8767 4)
+const STALE_TTL: u32 = 30;
+
+// Trustworthiness of cached data by the section and kind of response it came
+// from, from least to most trusted (RFC 2181 5.4.1)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Trust {
+    // Additional section, glue included
+    Additional,
+    // Authority section of referrals and other non-authoritative responses
+    Authority,
+    // Answer section of non-authoritative responses, as sent by forwarders
+    Answer,
+    // Authority section of authoritative responses
+    AuthoritativeAuthority,
+    AuthoritativeAnswer,
+}
+
+impl Trust {
+    // Only data from answers, or the zone itself, is good enough for clients
+    fn is_answer(self) -> bool {
+        self >= Trust::Answer
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    pub name: String,
+    pub qtype: QueryType,
+    pub qclass: QueryClass,
+}
+
+impl CacheKey {
+    pub fn new(name: &str, qtype: QueryType, qclass: QueryClass) -> CacheKey {
+        CacheKey {
+            name: name.to_ascii_lowercase(),
+            qtype,
+            qclass,
+        }
+    }
+}
+
+// NXDOMAIN covers every type at a name (and, as per RFC 8020, every name
+// below it), NODATA covers a single type
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum NegativeKey {
+    NxDomain { name: String, qclass: QueryClass },
+    NoData(CacheKey),
+}
+
+#[derive(Debug, Clone)]
+struct CacheEntry {
+    records: Vec<DnsRecord>,
+    ttl: u32,
+    trust: Trust,
+    expires_at: Instant,
+    // Position in the expiry index of the map holding the entry
+    seq: u64,
+}
+
+// Cache entries along with an index of their keys by expiry, so that the
+// entries to evict once full are found without scanning the whole map
+#[derive(Debug)]
+struct ExpiringMap<K> {
+    entries: HashMap<K, CacheEntry>,
+    expiry: BTreeMap<(Instant, u64), K>,
+    next_seq: u64,
+}
+
+impl CacheEntry {
+    fn new(records: Vec<DnsRecord>, ttl: u32, trust: Trust, now: Instant) -> CacheEntry {
+        CacheEntry {
+            records,
+            ttl,
+            trust,
+            expires_at: now + Duration::from_secs(ttl as u64),
+            seq: 0,
+        }
+    }
+
+    fn remaining_ttl(&self, now: Instant) -> Option<u32> {
+        if self.expires_at <= now {
+            return None;
+        }
+
+        Some((self.expires_at - now).as_secs() as u32)
+    }
+
+    // Expired entries may still be answered for a while when upstreams are
+    // unreachable (RFC 8767 4), with a short TTL so clients ask again soon.
+    // Entries that have not expired yet are not stale
+    fn stale_ttl(&self, now: Instant, stale_window: Duration) -> Option<u32> {
+        if self.expires_at > now || self.expires_at + stale_window <= now {
+            return None;
+        }
+
+        Some(STALE_TTL)
+    }
+
+    fn records_with_ttl(&self, ttl: u32) -> Vec<DnsRecord> {
+        let mut records = self.records.clone();
+        for record in records.iter_mut() {
+            record.set_ttl(ttl);
+        }
+
+        records
+    }
+}
+
+impl<K: Clone + Eq + Hash> ExpiringMap<K> {
+    fn new() -> ExpiringMap<K> {
+        ExpiringMap {
+            entries: HashMap::new(),
+            expiry: BTreeMap::new(),
+            next_seq: 0,
+        }
+    }
+
+    fn get(&self, key: &K) -> Option<&CacheEntry> {
+        self.entries.get(key)
+    }
+
+    fn insert(&mut self, key: K, mut entry: CacheEntry) {
+        entry.seq = self.next_seq;
+        self.next_seq += 1;
+
+        self.expiry
+            .insert((entry.expires_at, entry.seq), key.clone());
+        if let Some(old) = self.entries.insert(key, entry) {
+            self.expiry.remove(&(old.expires_at, old.seq));
+        }
+    }
+
+    fn remove(&mut self, key: &K) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.expiry.remove(&(entry.expires_at, entry.seq));
+        }
+    }
+
+    // Drops entries expired for longer than the stale window when the map is
+    // full, and the entry closest to expiry if that did not free up any space
+    fn make_room(&mut self, key: &K, now: Instant, max_entries: usize, stale_window: Duration) {
+        if self.entries.len() < max_entries || self.entries.contains_key(key) {
+            return;
+        }
+
+        while let Some(entry) = self.expiry.first_entry() {
+            let (expires_at, _) = *entry.key();
+            if expires_at + stale_window > now && self.entries.len() < max_entries {
+                break;
+            }
+
+            let key = entry.remove();
+            self.entries.remove(&key);
+        }
+    }
+}
+
+// The SOA comes first, followed by whatever NSEC or NSEC3 records and
+// signatures proved the denial
+#[derive(Debug, Clone)]
+pub struct NegativeAnswer {
+    pub result_code: ResultCode,
+    pub records: Vec<DnsRecord>,
+}
+
+#[derive(Debug)]
+pub struct RecordCache {
+    entries: RwLock<ExpiringMap<CacheKey>>,
+    negative: RwLock<ExpiringMap<NegativeKey>>,
+    max_entries: usize,
+    max_ttl: u32,
+    max_negative_ttl: u32,
+    // How long entries are kept past their expiry for serve-stale
+    stale_window: Duration,
+}
+
+impl RecordCache {
+    pub fn new(stale_window: Duration) -> RecordCache {
+        RecordCache::with_limits(DEFAULT_MAX_ENTRIES, DEFAULT_MAX_TTL, stale_window)
+    }
+
+    pub fn with_limits(max_entries: usize, max_ttl: u32, stale_window: Duration) -> RecordCache {
+        RecordCache {
+            entries: RwLock::new(ExpiringMap::new()),
+            negative: RwLock::new(ExpiringMap::new()),
+            max_entries,
+            max_ttl,
+            max_negative_ttl: DEFAULT_MAX_NEGATIVE_TTL.min(max_ttl),
+            stale_window,
+        }
+    }
+
+    // Returns the cached RRset with TTLs decremented by the time spent in the
+    // cache, unless it only came from a referral or the additional section
+    pub fn get(&self, name: &str, qtype: QueryType, qclass: QueryClass) -> Option<Vec<DnsRecord>> {
+        self.lookup(name, qtype, qclass, Trust::Answer, Instant::now())
+    }
+
+    fn lookup(
+        &self,
+        name: &str,
+        qtype: QueryType,
+        qclass: QueryClass,
+        min_trust: Trust,
+        now: Instant,
+    ) -> Option<Vec<DnsRecord>> {
+        let key = CacheKey::new(name, qtype, qclass);
+
+        {
+            let entries = self.entries.read().unwrap();
+            let entry = entries.get(&key)?;
+
+            if let Some(ttl) = entry.remaining_ttl(now) {
+                return (entry.trust >= min_trust).then(|| entry.records_with_ttl(ttl));
+            }
+
+            if entry.expires_at + self.stale_window > now {
+                return None;
+            }
+        }
+
+        // The entry may have been refreshed since the read lock was released
+        let mut entries = self.entries.write().unwrap();
+        if entries
+            .get(&key)
+            .is_some_and(|entry| entry.expires_at + self.stale_window <= now)
+        {
+            entries.remove(&key);
+        }
+
+        None
+    }
+
+    // Returns the cached RRset if it expired less than the stale window ago
+    pub fn get_stale(
+        &self,
+        name: &str,
+        qtype: QueryType,
+        qclass: QueryClass,
+    ) -> Option<Vec<DnsRecord>> {
+        let key = CacheKey::new(name, qtype, qclass);
+        let entries = self.entries.read().unwrap();
+        let entry = entries.get(&key).filter(|entry| entry.trust.is_answer())?;
+        let ttl = entry.stale_ttl(Instant::now(), self.stale_window)?;
+
+        Some(entry.records_with_ttl(ttl))
+    }
+
+    // Whether the cached RRset is into the last tenth of its TTL, which is
+    // when refreshing a popular entry ahead of time pays off
+    pub fn is_expiring(&self, name: &str, qtype: QueryType, qclass: QueryClass) -> bool {
+        let key = CacheKey::new(name, qtype, qclass);
+        let now = Instant::now();
+
+        match self.entries.read().unwrap().get(&key) {
+            Some(entry) if entry.expires_at > now && entry.trust.is_answer() => {
+                (entry.expires_at - now) * 10 < Duration::from_secs(entry.ttl as u64)
+            }
+            _ => false,
+        }
+    }
+
+    // Stores a single RRset along with the RRSIGs covering it. All records are
+    // expected to share name, type (or type covered) and class
+    pub fn insert_rrset(&self, records: Vec<DnsRecord>, trust: Trust) {
+        self.store_rrset(records, Vec::new(), trust);
+    }
+
+    fn store_rrset(&self, records: Vec<DnsRecord>, proof: Vec<DnsRecord>, trust: Trust) {
+        let (signatures, records): (Vec<DnsRecord>, Vec<DnsRecord>) = records
+            .into_iter()
+            .partition(|record| matches!(record, DnsRecord::RRSIG { .. }));
+
+        let first = match records.first() {
+            Some(x) => x,
+            None => return,
+        };
+
+        // OPT only concerns a single hop and is never cached (RFC 6891 6.1.1)
+        if let QueryType::UNKNOWN(_) | QueryType::OPT = first.qtype() {
+            return;
+        }
+
+        // RFC 2181 5.2: the TTLs of all records in an RRset must be treated as equal
+        let ttl = records
+            .iter()
+            .chain(signatures.iter())
+            .chain(proof.iter())
+            .map(|record| record.ttl())
+            .min()
+            .unwrap_or(0)
+            .min(self.max_ttl);
+
+        if ttl == 0 {
+            return;
+        }
+
+        let key = rrset_key(first);
+        let qtype = first.qtype();
+        let now = Instant::now();
+
+        // Keep RRsets in canonical order without duplicates (RFC 4034 6.3,
+        // RFC 2181 5) so they are ready for signature verification
+        let mut records = canonical_order(records);
+        records.extend(signatures);
+        records.extend(proof);
+
+        let mut entries = self.entries.write().unwrap();
+        let mut entry = CacheEntry::new(records, ttl, trust, now);
+
+        if let Some(cached) = entries.get(&key).filter(|cached| cached.expires_at > now) {
+            // Less trusted data never replaces what is cached
+            if cached.trust > trust {
+                return;
+            }
+
+            // A name server set replaced by one just as trusted keeps the
+            // expiry of the cached one, so that spoofed referrals cannot keep
+            // a zone pointed elsewhere for good
+            if cached.trust == trust && qtype == QueryType::NS {
+                entry.expires_at = entry.expires_at.min(cached.expires_at);
+            }
+        }
+
+        entries.make_room(&key, now, self.max_entries, self.stale_window);
+        entries.insert(key, entry);
+    }
+
+    // Groups the records into RRsets and stores each of them
+    pub fn insert_records(&self, records: &[DnsRecord], trust: Trust) {
+        for rrset in group_rrsets(records) {
+            self.insert_rrset(rrset, trust);
+        }
+    }
+
+    // Stores the RRsets of a response, ranked by section and by whether the
+    // response is authoritative
+    pub fn insert_packet(&self, packet: &DnsPacket) {
+        let (answer_trust, authority_trust) = match packet.header.authoritative_answer {
+            true => (Trust::AuthoritativeAnswer, Trust::AuthoritativeAuthority),
+            false => (Trust::Answer, Trust::Authority),
+        };
+
+        // Answers synthesized from a wildcard only validate along with the
+        // NSEC or NSEC3 records proving that the name itself does not exist
+        // (RFC 4035 5.3.4), so these are kept right after the signatures
+        let proof: Vec<DnsRecord> = packet
+            .authorities
+            .iter()
+            .filter(|record| is_denial_proof(record))
+            .cloned()
+            .collect();
+
+        for rrset in group_rrsets(&packet.answers) {
+            let proof = if is_wildcard_expansion(&rrset) {
+                proof.clone()
+            } else {
+                Vec::new()
+            };
+            self.store_rrset(rrset, proof, answer_trust);
+        }
+
+        self.insert_records(&packet.authorities, authority_trust);
+        self.insert_records(&packet.additionals, Trust::Additional);
+    }
+
+    // Finds the deepest enclosing zone cut for which both the NS set and the
+    // address of at least one of its name servers are cached. Referrals and
+    // glue are good enough to find the servers with
+    pub fn closest_ns(&self, qname: &str) -> Option<(String, Vec<Ipv4Addr>)> {
+        let mut zone = qname;
+        let now = Instant::now();
+        let get =
+            |name: &str, qtype| self.lookup(name, qtype, QueryClass::IN, Trust::Additional, now);
+
+        loop {
+            if let Some(ns_records) = get(zone, QueryType::NS) {
+                let addrs: Vec<Ipv4Addr> = ns_records
+                    .iter()
+                    .filter_map(|record| match record {
+                        DnsRecord::NS { host, .. } => get(host, QueryType::A),
+                        _ => None,
+                    })
+                    .flatten()
+                    .filter_map(|record| match record {
+                        DnsRecord::A { ip_v4_addr, .. } => Some(ip_v4_addr),
+                        _ => None,
+                    })
+                    .collect();
+
+                if !addrs.is_empty() {
+                    return Some((zone.to_string(), addrs));
+                }
+            }
+
+            zone = parent(zone)?;
+        }
+    }
+
+    // Remembers an NXDOMAIN or NODATA response. Responses without an SOA in
+    // the authority section are not cached (RFC 2308 5)
+    pub fn insert_negative(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        qclass: QueryClass,
+        packet: &DnsPacket,
+    ) {
+        let soa = match packet
+            .authorities
+            .iter()
+            .find(|record| matches!(record, DnsRecord::SOA { .. }))
+        {
+            Some(x) => x,
+            None => return,
+        };
+
+        // The negative answer applies to the end of any CNAME chain in the answer section
+        let name = packet.resolve_cname_chain(qname);
+
+        let key = match packet.header.result_code {
+            ResultCode::NXDOMAIN => NegativeKey::NxDomain {
+                name: name.to_ascii_lowercase(),
+                qclass,
+            },
+            ResultCode::NOERROR
+                if !packet.answers.iter().any(|record| {
+                    record.qtype() == qtype && record.domain().eq_ignore_ascii_case(name)
+                }) =>
+            {
+                NegativeKey::NoData(CacheKey::new(name, qtype, qclass))
+            }
+            _ => return,
+        };
+
+        // RFC 2308 5: the negative TTL is the minimum of the SOA TTL and its MINIMUM field
+        let ttl = match soa {
+            DnsRecord::SOA { ttl, minimum, .. } => (*ttl).min(*minimum),
+            _ => 0,
+        }
+        .min(self.max_negative_ttl);
+
+        if ttl == 0 {
+            return;
+        }
+
+        // Validating resolvers asking us need the proof as well (RFC 4035 3.1.3)
+        let mut records = vec![soa.clone()];
+        records.extend(
+            packet
+                .authorities
+                .iter()
+                .filter(|record| {
+                    matches!(
+                        record,
+                        DnsRecord::RRSIG { .. } | DnsRecord::NSEC { .. } | DnsRecord::NSEC3 { .. }
+                    )
+                })
+                .cloned(),
+        );
+
+        let trust = match packet.header.authoritative_answer {
+            true => Trust::AuthoritativeAuthority,
+            false => Trust::Authority,
+        };
+
+        let now = Instant::now();
+        let mut negative = self.negative.write().unwrap();
+        negative.make_room(&key, now, self.max_entries, self.stale_window);
+        negative.insert(key, CacheEntry::new(records, ttl, trust, now));
+    }
+
+    // Looks up a cached negative answer, with the SOA TTL decremented. An
+    // NXDOMAIN cached for any ancestor also denies the name (RFC 8020)
+    pub fn get_negative(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        qclass: QueryClass,
+    ) -> Option<NegativeAnswer> {
+        self.find_negative(qname, qtype, qclass, false, Instant::now())
+    }
+
+    // Returns the cached negative answer if it expired less than the stale
+    // window ago
+    pub fn get_negative_stale(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        qclass: QueryClass,
+    ) -> Option<NegativeAnswer> {
+        self.find_negative(qname, qtype, qclass, true, Instant::now())
+    }
+
+    fn find_negative(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        qclass: QueryClass,
+        stale: bool,
+        now: Instant,
+    ) -> Option<NegativeAnswer> {
+        let negative = self.negative.read().unwrap();
+        let ttl = |entry: &CacheEntry| match stale {
+            true => entry.stale_ttl(now, self.stale_window),
+            false => entry.remaining_ttl(now),
+        };
+
+        let qname = qname.to_ascii_lowercase();
+        let mut name = qname.as_str();
+
+        loop {
+            let key = NegativeKey::NxDomain {
+                name: name.to_string(),
+                qclass,
+            };
+
+            if let Some(answer) = negative.get(&key).and_then(|entry| {
+                ttl(entry).map(|ttl| negative_answer(entry, ResultCode::NXDOMAIN, ttl))
+            }) {
+                return Some(answer);
+            }
+
+            name = match parent(name) {
+                Some(x) => x,
+                None => break,
+            };
+        }
+
+        let key = NegativeKey::NoData(CacheKey::new(&qname, qtype, qclass));
+
+        negative.get(&key).and_then(|entry| {
+            ttl(entry).map(|ttl| negative_answer(entry, ResultCode::NOERROR, ttl))
+        })
+    }
+
+    // Forgets the RRsets of a response along with the negative answer it gave
+    // for qname, once they turned out not to validate
+    pub fn remove_packet(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        qclass: QueryClass,
+        packet: &DnsPacket,
+    ) {
+        {
+            let mut entries = self.entries.write().unwrap();
+            for record in packet.answers.iter().chain(packet.authorities.iter()) {
+                entries.remove(&rrset_key(record));
+            }
+        }
+
+        let name = packet.resolve_cname_chain(qname).to_ascii_lowercase();
+        let mut negative = self.negative.write().unwrap();
+        negative.remove(&NegativeKey::NoData(CacheKey::new(&name, qtype, qclass)));
+        negative.remove(&NegativeKey::NxDomain { name, qclass });
+    }
+}
+
+fn negative_answer(entry: &CacheEntry, result_code: ResultCode, ttl: u32) -> NegativeAnswer {
+    NegativeAnswer {
+        result_code,
+        records: entry.records_with_ttl(ttl),
+    }
+}
+
+// NSEC and NSEC3 records, and the signatures over them
+pub fn is_denial_proof(record: &DnsRecord) -> bool {
+    match record {
+        DnsRecord::RRSIG { type_covered, .. } => {
+            matches!(type_covered, QueryType::NSEC | QueryType::NSEC3)
+        }
+        _ => matches!(record, DnsRecord::NSEC { .. } | DnsRecord::NSEC3 { .. }),
+    }
+}
+
+// The RRSIG label count is below that of the owner name for RRsets expanded
+// from a wildcard (RFC 4035 5.3.2)
+fn is_wildcard_expansion(rrset: &[DnsRecord]) -> bool {
+    rrset.iter().any(|record| match record {
+        DnsRecord::RRSIG { domain, labels, .. } => (*labels as usize) < label_count(domain),
+        _ => false,
+    })
+}
+
+fn group_rrsets(records: &[DnsRecord]) -> Vec<Vec<DnsRecord>> {
+    let mut rrsets: HashMap<CacheKey, Vec<DnsRecord>> = HashMap::new();
+
+    for record in records {
+        let rrset = rrsets.entry(rrset_key(record)).or_default();
+        if !rrset.contains(record) {
+            rrset.push(record.clone());
+        }
+    }
+
+    rrsets.into_values().collect()
+}
+
+// RRSIGs are stored with the RRset they cover
+fn rrset_key(record: &DnsRecord) -> CacheKey {
+    match record {
+        DnsRecord::RRSIG {
+            domain,
+            type_covered,
+            ..
+        } => CacheKey::new(domain, *type_covered, record.qclass()),
+        _ => CacheKey::new(record.domain(), record.qtype(), record.qclass()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn a(name: &str, last: u8, ttl: u32) -> DnsRecord {
+        DnsRecord::A {
+            domain: name.to_string(),
+            ip_v4_addr: Ipv4Addr::new(192, 0, 2, last),
+            ttl,
+        }
+    }
+
+    fn ns(zone: &str, host: &str, ttl: u32) -> DnsRecord {
+        DnsRecord::NS {
+            domain: zone.to_string(),
+            host: host.to_string(),
+            ttl,
+        }
+    }
+
+    fn packet(
+        authoritative: bool,
+        answers: Vec<DnsRecord>,
+        authorities: Vec<DnsRecord>,
+        additionals: Vec<DnsRecord>,
+    ) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.header.is_response = true;
+        packet.header.authoritative_answer = authoritative;
+        packet.answers = answers;
+        packet.authorities = authorities;
+        packet.additionals = additionals;
+        packet
+    }
+
+    fn cache() -> RecordCache {
+        RecordCache::new(Duration::ZERO)
+    }
+
+    fn addrs(cache: &RecordCache, name: &str) -> Option<Vec<Ipv4Addr>> {
+        let records = cache.get(name, QueryType::A, QueryClass::IN)?;
+        Some(
+            records
+                .iter()
+                .filter_map(|record| match record {
+                    DnsRecord::A { ip_v4_addr, .. } => Some(*ip_v4_addr),
+                    _ => None,
+                })
+                .collect(),
+        )
+    }
+
+    fn soa(zone: &str, minimum: u32) -> DnsRecord {
+        DnsRecord::SOA {
+            domain: zone.to_string(),
+            mname: format!("ns1.{}", zone),
+            rname: format!("hostmaster.{}", zone),
+            serial: 1,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum,
+            ttl: 3600,
+        }
+    }
+
+    fn later(secs: u64) -> Instant {
+        Instant::now() + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn never_answers_from_referrals_or_glue() {
+        let cache = cache();
+        let referral = packet(
+            false,
+            Vec::new(),
+            vec![ns("example.com", "ns1.example.com", 3600)],
+            vec![a("ns1.example.com", 1, 3600)],
+        );
+
+        cache.insert_packet(&referral);
+
+        assert_eq!(
+            cache.get("example.com", QueryType::NS, QueryClass::IN),
+            None
+        );
+        assert_eq!(
+            cache.get("ns1.example.com", QueryType::A, QueryClass::IN),
+            None
+        );
+        // They still lead the way to the servers of the zone
+        assert_eq!(
+            cache.closest_ns("www.example.com"),
+            Some(("example.com".to_string(), vec![Ipv4Addr::new(192, 0, 2, 1)]))
+        );
+    }
+
+    #[test]
+    fn keeps_more_trusted_data() {
+        let cache = cache();
+        let answer = packet(
+            true,
+            vec![a("ns1.example.com", 1, 3600)],
+            Vec::new(),
+            Vec::new(),
+        );
+        let glue = packet(
+            false,
+            Vec::new(),
+            Vec::new(),
+            vec![a("ns1.example.com", 66, 3600)],
+        );
+
+        cache.insert_packet(&answer);
+        cache.insert_packet(&glue);
+
+        assert_eq!(
+            addrs(&cache, "ns1.example.com"),
+            Some(vec![Ipv4Addr::new(192, 0, 2, 1)])
+        );
+
+        // While answers replace glue
+        let cache = self::cache();
+        cache.insert_packet(&glue);
+        cache.insert_packet(&answer);
+
+        assert_eq!(
+            addrs(&cache, "ns1.example.com"),
+            Some(vec![Ipv4Addr::new(192, 0, 2, 1)])
+        );
+    }
+
+    #[test]
+    fn referrals_do_not_extend_cached_name_servers() {
+        let cache = cache();
+        let referral = |host: &str, ttl| {
+            packet(
+                false,
+                Vec::new(),
+                vec![ns("example.com", host, ttl)],
+                vec![a(host, 1, ttl)],
+            )
+        };
+
+        cache.insert_packet(&referral("ns1.example.com", 60));
+        cache.insert_packet(&referral("ns.attacker.example.com", 86400));
+
+        let entries = cache.entries.read().unwrap();
+        let entry = entries
+            .get(&CacheKey::new("example.com", QueryType::NS, QueryClass::IN))
+            .unwrap();
+        assert_eq!(
+            entry.records,
+            vec![ns("example.com", "ns.attacker.example.com", 86400)]
+        );
+        assert!(entry.remaining_ttl(Instant::now()).unwrap() <= 60);
+    }
+
+    #[test]
+    fn decrements_ttls() {
+        let cache = cache();
+        cache.insert_rrset(vec![a("www.example.com", 1, 3600)], Trust::Answer);
+
+        let records = cache
+            .lookup(
+                "www.example.com",
+                QueryType::A,
+                QueryClass::IN,
+                Trust::Answer,
+                later(1000),
+            )
+            .unwrap();
+        assert!(matches!(
+            records[..],
+            [DnsRecord::A {
+                ttl: 2599..=2600,
+                ..
+            }]
+        ));
+
+        assert_eq!(
+            cache.lookup(
+                "www.example.com",
+                QueryType::A,
+                QueryClass::IN,
+                Trust::Answer,
+                later(3601),
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn evicts_expired_entries_past_the_stale_window() {
+        let cache = RecordCache::new(Duration::from_secs(60));
+        cache.insert_rrset(vec![a("www.example.com", 1, 60)], Trust::Answer);
+        let key = CacheKey::new("www.example.com", QueryType::A, QueryClass::IN);
+        let lookup = |now| {
+            cache.lookup(
+                "www.example.com",
+                QueryType::A,
+                QueryClass::IN,
+                Trust::Answer,
+                now,
+            )
+        };
+
+        // Kept around for serve-stale
+        assert_eq!(lookup(later(90)), None);
+        assert!(cache.entries.read().unwrap().get(&key).is_some());
+
+        assert_eq!(lookup(later(121)), None);
+        assert!(cache.entries.read().unwrap().get(&key).is_none());
+    }
+
+    #[test]
+    fn makes_room_by_dropping_the_entry_closest_to_expiry() {
+        let cache = RecordCache::with_limits(2, DEFAULT_MAX_TTL, Duration::ZERO);
+        cache.insert_rrset(vec![a("a.example.com", 1, 60)], Trust::Answer);
+        cache.insert_rrset(vec![a("b.example.com", 2, 3600)], Trust::Answer);
+        cache.insert_rrset(vec![a("c.example.com", 3, 600)], Trust::Answer);
+
+        assert_eq!(addrs(&cache, "a.example.com"), None);
+        assert!(addrs(&cache, "b.example.com").is_some());
+        assert!(addrs(&cache, "c.example.com").is_some());
+    }
+
+    #[test]
+    fn nxdomain_denies_names_below() {
+        let cache = cache();
+        let mut nxdomain = packet(true, Vec::new(), vec![soa("example.com", 300)], Vec::new());
+        nxdomain.header.result_code = ResultCode::NXDOMAIN;
+        cache.insert_negative("sub.example.com", QueryType::A, QueryClass::IN, &nxdomain);
+
+        let answer = cache
+            .get_negative("www.sub.example.com", QueryType::AAAA, QueryClass::IN)
+            .unwrap();
+        assert_eq!(answer.result_code, ResultCode::NXDOMAIN);
+        assert!(matches!(
+            answer.records[..],
+            [DnsRecord::SOA { ttl: 299..=300, .. }]
+        ));
+
+        // Neither siblings nor the parent are denied
+        assert!(cache
+            .get_negative("www.example.com", QueryType::A, QueryClass::IN)
+            .is_none());
+        assert!(cache
+            .get_negative("example.com", QueryType::A, QueryClass::IN)
+            .is_none());
+
+        assert!(cache
+            .find_negative(
+                "www.sub.example.com",
+                QueryType::A,
+                QueryClass::IN,
+                false,
+                later(301),
+            )
+            .is_none());
+    }
+
+    #[test]
+    fn nodata_only_denies_the_type() {
+        let cache = cache();
+        let nodata = packet(true, Vec::new(), vec![soa("example.com", 300)], Vec::new());
+        cache.insert_negative("www.example.com", QueryType::AAAA, QueryClass::IN, &nodata);
+
+        let answer = cache
+            .get_negative("www.example.com", QueryType::AAAA, QueryClass::IN)
+            .unwrap();
+        assert_eq!(answer.result_code, ResultCode::NOERROR);
+
+        assert!(cache
+            .get_negative("www.example.com", QueryType::A, QueryClass::IN)
+            .is_none());
+        assert!(cache
+            .get_negative("a.www.example.com", QueryType::AAAA, QueryClass::IN)
+            .is_none());
+    }
+}
@@ -0,0 +1,227 @@
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::resolver::forwarder::{ForwardPolicy, ForwardStrategy, ForwardZoneConfig};
+use crate::resolver::https::{HttpsUpstreamConfig, DEFAULT_HTTPS_PORT};
+use crate::resolver::tls::{TlsAuth, TlsUpstreamConfig, DEFAULT_TLS_PORT};
+use crate::resolver::transport::Transport;
+use crate::types::Result;
+use crate::utils::encoding::base64_decode;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    pub port: u16,
+    pub root_hints: Option<PathBuf>,
+    pub query_timeout: Duration,
+    pub query_attempts: u32,
+    pub query_budget: Duration,
+    pub infra_dump_interval: Option<Duration>,
+    pub qname_minimisation: bool,
+    pub case_randomization: bool,
+    pub case_randomization_exempt: Vec<Ipv4Addr>,
+    pub upstream_transport: Transport,
+    pub forwarders: Vec<(Ipv4Addr, u16)>,
+    pub forward_strategy: ForwardStrategy,
+    pub forward_zones: Vec<ForwardZoneConfig>,
+    pub tls_upstreams: Vec<TlsUpstreamConfig>,
+    pub https_upstreams: Vec<HttpsUpstreamConfig>,
+    pub tls_ca_file: Option<PathBuf>,
+    pub dnssec_validation: bool,
+    pub trust_anchors: Option<PathBuf>,
+    pub aggressive_nsec: bool,
+    pub serve_stale: Option<Duration>,
+    pub prefetch: bool,
+    pub hosts_files: Vec<PathBuf>,
+    pub max_concurrent_queries: usize,
+    pub verbose: bool,
+}
+
+impl Config {
+    pub fn new() -> Config {
+        Config {
+            port: 2053,
+            root_hints: None,
+            query_timeout: Duration::from_millis(1500),
+            query_attempts: 3,
+            query_budget: Duration::from_secs(10),
+            infra_dump_interval: None,
+            qname_minimisation: true,
+            case_randomization: false,
+            case_randomization_exempt: Vec::new(),
+            upstream_transport: Transport::Udp,
+            forwarders: Vec::new(),
+            forward_strategy: ForwardStrategy::Sequential,
+            forward_zones: Vec::new(),
+            tls_upstreams: Vec::new(),
+            https_upstreams: Vec::new(),
+            tls_ca_file: None,
+            dnssec_validation: false,
+            trust_anchors: None,
+            aggressive_nsec: true,
+            serve_stale: None,
+            prefetch: false,
+            hosts_files: Vec::new(),
+            max_concurrent_queries: 100,
+            verbose: false,
+        }
+    }
+
+    pub fn from_args<I: Iterator<Item = String>>(mut args: I) -> Result<Config> {
+        let mut config = Config::new();
+
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| format!("Missing value for {}", arg))
+            };
+
+            match arg.as_str() {
+                "--port" => config.port = value()?.parse()?,
+                "--root-hints" => config.root_hints = Some(PathBuf::from(value()?)),
+                "--query-timeout" => {
+                    config.query_timeout = Duration::from_millis(value()?.parse()?)
+                }
+                "--query-attempts" => config.query_attempts = value()?.parse()?,
+                "--query-budget" => config.query_budget = Duration::from_millis(value()?.parse()?),
+                "--infra-dump-interval" => {
+                    config.infra_dump_interval = Some(Duration::from_secs(value()?.parse()?))
+                }
+                "--no-qname-minimisation" => config.qname_minimisation = false,
+                "--use-0x20" => config.case_randomization = true,
+                "--no-0x20-server" => config.case_randomization_exempt.push(value()?.parse()?),
+                "--upstream-tcp" => config.upstream_transport = Transport::Tcp,
+                "--forwarder" => {
+                    let upstream = config.parse_upstream(&value()?)?;
+                    config.forwarders.push(upstream);
+                }
+                "--forward-strategy" => config.forward_strategy = value()?.parse()?,
+                "--forward-zone" => {
+                    let zone = config.parse_forward_zone(&value()?, ForwardPolicy::Only)?;
+                    config.forward_zones.push(zone);
+                }
+                "--forward-zone-first" => {
+                    let zone = config.parse_forward_zone(&value()?, ForwardPolicy::First)?;
+                    config.forward_zones.push(zone);
+                }
+                "--tls-ca" => config.tls_ca_file = Some(PathBuf::from(value()?)),
+                "--dnssec" => config.dnssec_validation = true,
+                "--trust-anchors" => config.trust_anchors = Some(PathBuf::from(value()?)),
+                "--no-aggressive-nsec" => config.aggressive_nsec = false,
+                "--serve-stale" => {
+                    config.serve_stale = Some(Duration::from_secs(value()?.parse()?))
+                }
+                "--prefetch" => config.prefetch = true,
+                "--hosts-file" => config.hosts_files.push(PathBuf::from(value()?)),
+                "--max-concurrent-queries" => config.max_concurrent_queries = value()?.parse()?,
+                "--verbose" => config.verbose = true,
+                _ => return Err(format!("Unknown argument {}", arg).into()),
+            }
+        }
+
+        if config.query_attempts == 0 {
+            return Err("--query-attempts must be at least 1".into());
+        }
+        if config.max_concurrent_queries == 0 {
+            return Err("--max-concurrent-queries must be at least 1".into());
+        }
+
+        Ok(config)
+    }
+
+    // Some servers do not preserve the case of the question, so 0x20
+    // encoding can be turned off for them
+    pub fn use_0x20(&self, server: Ipv4Addr) -> bool {
+        self.case_randomization && !self.case_randomization_exempt.contains(&server)
+    }
+
+    // An upstream may be followed by #name or #pin-sha256=<base64>[#...] to be
+    // reached over TLS, verifying the certificate against the name or the key
+    // against one of the pins (RFC 7858 4.2), or by #https://... to be reached
+    // over HTTPS at that URL (RFC 8484)
+    fn parse_upstream(&mut self, value: &str) -> Result<(Ipv4Addr, u16)> {
+        let Some((server, auth)) = value.split_once('#') else {
+            return parse_server(value, 53);
+        };
+
+        if auth.starts_with("https://") {
+            let server = parse_server(server, DEFAULT_HTTPS_PORT)?;
+            let upstream = HttpsUpstreamConfig::from_url(server, auth)?;
+
+            self.tls_upstreams.retain(|x| x.server != server);
+            self.https_upstreams.retain(|x| x.server != server);
+            self.https_upstreams.push(upstream);
+            return Ok(server);
+        }
+
+        let server = parse_server(server, DEFAULT_TLS_PORT)?;
+        let auth = if auth.starts_with("pin-sha256=") {
+            let pins = auth
+                .split('#')
+                .map(|pin| {
+                    let pin = pin
+                        .strip_prefix("pin-sha256=")
+                        .ok_or_else(|| format!("Expected pin-sha256=<base64>, got {}", pin))?;
+                    let digest = base64_decode(pin)?;
+                    if digest.len() != 32 {
+                        return Err(format!("Pin {} is not a SHA-256 digest", pin).into());
+                    }
+                    Ok(digest)
+                })
+                .collect::<Result<Vec<Vec<u8>>>>()?;
+
+            TlsAuth::SpkiPins(pins)
+        } else if !auth.is_empty() {
+            TlsAuth::Hostname(auth.to_string())
+        } else {
+            return Err(format!("Missing TLS name or pin for {}", value).into());
+        };
+
+        self.https_upstreams.retain(|x| x.server != server);
+        self.tls_upstreams.retain(|x| x.server != server);
+        self.tls_upstreams.push(TlsUpstreamConfig { server, auth });
+
+        Ok(server)
+    }
+
+    // Parses zone=server[,server...] as used by the conditional forwarding options
+    fn parse_forward_zone(
+        &mut self,
+        value: &str,
+        policy: ForwardPolicy,
+    ) -> Result<ForwardZoneConfig> {
+        let (zone, servers) = value
+            .split_once('=')
+            .ok_or_else(|| format!("Expected zone=server[,server...], got {}", value))?;
+
+        let upstreams = servers
+            .split(',')
+            .map(|server| self.parse_upstream(server))
+            .collect::<Result<Vec<(Ipv4Addr, u16)>>>()?;
+
+        Ok(ForwardZoneConfig {
+            zone: zone.trim_end_matches('.').to_ascii_lowercase(),
+            upstreams,
+            policy,
+        })
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config::new()
+    }
+}
+
+// Accepts either a bare address, using the given default port, or address:port
+pub fn parse_server(value: &str, default_port: u16) -> Result<(Ipv4Addr, u16)> {
+    if let Ok(addr) = value.parse::<SocketAddrV4>() {
+        return Ok((*addr.ip(), addr.port()));
+    }
+
+    let addr = value
+        .parse::<Ipv4Addr>()
+        .map_err(|e| format!("Invalid server address {}: {}", value, e))?;
+
+    Ok((addr, default_port))
+}
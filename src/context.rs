@@ -0,0 +1,99 @@
+use std::fmt;
+
+use log::debug;
+
+use crate::cache::infra_cache::InfraCache;
+use crate::cache::record_cache::RecordCache;
+use crate::config::Config;
+use crate::dnssec::trust_anchor;
+use crate::dnssec::validator::Validator;
+use crate::resolver::forwarder::{ForwardZone, ForwarderPool};
+use crate::resolver::hosts::HostsFiles;
+use crate::resolver::https::HttpsClient;
+use crate::resolver::in_flight::InFlight;
+use crate::resolver::refresh_queue::RefreshQueue;
+use crate::resolver::root_hints::RootHints;
+use crate::resolver::tls::{load_roots, TlsClient};
+use crate::types::Result;
+
+pub struct ServerContext {
+    pub config: Config,
+    pub cache: RecordCache,
+    pub infra_cache: InfraCache,
+    pub root_hints: RootHints,
+    pub forwarders: Option<ForwarderPool>,
+    pub forward_zones: Vec<ForwardZone>,
+    pub tls: TlsClient,
+    pub https: HttpsClient,
+    pub validator: Option<Validator>,
+    pub refresh_queue: RefreshQueue,
+    pub in_flight: InFlight,
+    pub hosts: HostsFiles,
+}
+
+impl ServerContext {
+    pub fn new(config: Config, cache: RecordCache) -> Result<ServerContext> {
+        let root_hints = match &config.root_hints {
+            Some(path) => RootHints::from_file(path)?,
+            None => RootHints::new(),
+        };
+
+        let forwarders = if config.forwarders.is_empty() {
+            None
+        } else {
+            Some(ForwarderPool::new(
+                config.forwarders.clone(),
+                config.forward_strategy,
+            ))
+        };
+
+        let forward_zones = config
+            .forward_zones
+            .iter()
+            .map(|zone| ForwardZone::new(zone, config.forward_strategy))
+            .collect();
+
+        let hosts = HostsFiles::new(config.hosts_files.clone())?;
+
+        let roots = load_roots(config.tls_ca_file.as_deref())?;
+        let tls = TlsClient::new(&config.tls_upstreams, &roots)?;
+        let https = HttpsClient::new(&config.https_upstreams, &roots)?;
+
+        let validator = if config.dnssec_validation {
+            let anchors = match &config.trust_anchors {
+                Some(path) => trust_anchor::from_file(path)?,
+                None => trust_anchor::default_anchors(),
+            };
+            // Forwarded zones are commonly internal ones the public tree
+            // denies the existence of
+            let insecure = config
+                .forward_zones
+                .iter()
+                .map(|zone| zone.zone.clone())
+                .collect();
+            Some(Validator::new(anchors, insecure, config.aggressive_nsec))
+        } else {
+            None
+        };
+
+        Ok(ServerContext {
+            config,
+            cache,
+            infra_cache: InfraCache::new(),
+            root_hints,
+            forwarders,
+            forward_zones,
+            tls,
+            https,
+            validator,
+            refresh_queue: RefreshQueue::new(),
+            in_flight: InFlight::new(),
+            hosts,
+        })
+    }
+
+    // Progress of a single query, which the server only prints with --verbose
+    pub fn trace(&self, message: fmt::Arguments) {
+        debug!("{}", message);
+    }
+}
@@ -0,0 +1,445 @@
+// Authenticated denial of existence with NSEC (RFC 4035 5.4) and NSEC3
+// (RFC 5155 8) records. The records passed in are expected to have been
+// verified as signed by zone already
+
+use std::cmp::Ordering;
+
+use ring::digest;
+
+use crate::models::{dns_record::DnsRecord, query_type::QueryType};
+use crate::types::Result;
+use crate::utils::domain_name::{
+    canonical_cmp, canonical_wire, common_ancestor, is_subdomain, label_count, last_labels, parent,
+};
+use crate::utils::encoding::base32hex_encode;
+
+const NSEC3_SHA1: u8 = 1;
+const NSEC3_OPT_OUT: u8 = 0x01;
+// RFC 9276 3.2 allows treating anything above this as insecure
+const MAX_NSEC3_ITERATIONS: u16 = 150;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Proof {
+    // The name, or the type at the name, provably does not exist
+    Denied,
+    // The name is a delegation without DS, so the zone below is unsigned
+    InsecureDelegation,
+    // Only proven up to an NSEC3 opt-out span, which may hide unsigned
+    // delegations (RFC 5155 6)
+    OptOut,
+    // NSEC3 iterations too costly to check
+    Unchecked,
+}
+
+pub fn prove_nxdomain(name: &str, zone: &str, records: &[DnsRecord]) -> Result<Proof> {
+    match nsec3_chain(records, zone) {
+        Some(chain) => chain.prove_nxdomain(name),
+        None => NsecChain::new(records, zone).prove_nxdomain(name),
+    }
+}
+
+pub fn prove_nodata(
+    name: &str,
+    qtype: QueryType,
+    zone: &str,
+    records: &[DnsRecord],
+) -> Result<Proof> {
+    match nsec3_chain(records, zone) {
+        Some(chain) => chain.prove_nodata(name, qtype),
+        None => NsecChain::new(records, zone).prove_nodata(name, qtype),
+    }
+}
+
+// An answer synthesized from the wildcard at closest_encloser is only valid
+// if the name asked for does not exist (RFC 4035 5.3.4, RFC 5155 8.8)
+pub fn prove_wildcard(
+    name: &str,
+    closest_encloser: &str,
+    zone: &str,
+    records: &[DnsRecord],
+) -> Result<Proof> {
+    match nsec3_chain(records, zone) {
+        Some(chain) => chain.prove_wildcard(name, closest_encloser),
+        None => NsecChain::new(records, zone).prove_wildcard(name),
+    }
+}
+
+// The NSEC or NSEC3 records matching or covering the name, its ancestors
+// within the zone, or the wildcards right below those. Whatever proves the
+// name or a type at it does not exist is among them
+pub fn relevant_proofs<'a>(
+    name: &str,
+    zone: &'a str,
+    records: &'a [DnsRecord],
+) -> Vec<&'a DnsRecord> {
+    let mut names = Vec::new();
+    for count in label_count(zone)..=label_count(name) {
+        let ancestor = last_labels(name, count);
+        names.push(ancestor.to_string());
+        names.push(wildcard_of(ancestor));
+    }
+
+    match nsec3_chain(records, zone) {
+        Some(chain) => chain
+            .records
+            .iter()
+            .filter(|nsec3| {
+                names
+                    .iter()
+                    .any(|name| nsec3.matches(name) || nsec3.covers(name))
+            })
+            .map(|nsec3| nsec3.record)
+            .collect(),
+        None => {
+            let chain = NsecChain::new(records, zone);
+            chain
+                .records
+                .iter()
+                .filter(|nsec| {
+                    names.iter().any(|name| {
+                        nsec.owner.eq_ignore_ascii_case(name) || chain.covers(nsec, name)
+                    })
+                })
+                .map(|nsec| nsec.record)
+                .collect()
+        }
+    }
+}
+
+fn wildcard_of(closest_encloser: &str) -> String {
+    if closest_encloser.is_empty() {
+        "*".to_string()
+    } else {
+        format!("*.{}", closest_encloser)
+    }
+}
+
+// NS without SOA marks a zone cut, below which the parent proves nothing
+fn is_delegation(types: &[QueryType]) -> bool {
+    types.contains(&QueryType::NS) && !types.contains(&QueryType::SOA)
+}
+
+// Checks the type bitmap of the record matching the name itself
+fn deny_type(types: &[QueryType], name: &str, qtype: QueryType) -> Result<Proof> {
+    if types.contains(&qtype) || types.contains(&QueryType::CNAME) {
+        return Err(format!("type bitmap of {} lists {} or CNAME", name, qtype).into());
+    }
+
+    let delegation = is_delegation(types);
+
+    match qtype {
+        // DS lives on the parent side of a zone cut, so only the parent can deny it
+        QueryType::DS if types.contains(&QueryType::SOA) => {
+            Err(format!("denial of DS at {} comes from the child zone", name).into())
+        }
+        QueryType::DS if delegation => Ok(Proof::InsecureDelegation),
+        // Other types at a delegation point are up to the child zone (RFC 6840 4.1)
+        _ if delegation => Err(format!("denial for {} comes from the parent zone", name).into()),
+        _ => Ok(Proof::Denied),
+    }
+}
+
+struct Nsec<'a> {
+    record: &'a DnsRecord,
+    owner: &'a str,
+    next: &'a str,
+    types: &'a [QueryType],
+}
+
+struct NsecChain<'a> {
+    zone: &'a str,
+    records: Vec<Nsec<'a>>,
+}
+
+impl<'a> NsecChain<'a> {
+    fn new(records: &'a [DnsRecord], zone: &'a str) -> NsecChain<'a> {
+        let records = records
+            .iter()
+            .filter_map(|record| match record {
+                DnsRecord::NSEC {
+                    domain,
+                    next_domain,
+                    types,
+                    ..
+                } if is_subdomain(domain, zone) => Some(Nsec {
+                    record,
+                    owner: domain,
+                    next: next_domain,
+                    types,
+                }),
+                _ => None,
+            })
+            .collect();
+
+        NsecChain { zone, records }
+    }
+
+    // The last NSEC of a zone points back to the apex. Names below a zone cut
+    // sort right after it, but are not covered (RFC 4035 5.4)
+    fn covers(&self, nsec: &Nsec, name: &str) -> bool {
+        is_subdomain(name, self.zone)
+            && !(is_delegation(nsec.types) && is_subdomain(name, nsec.owner))
+            && canonical_cmp(nsec.owner, name) == Ordering::Less
+            && (canonical_cmp(name, nsec.next) == Ordering::Less
+                || nsec.next.eq_ignore_ascii_case(self.zone))
+    }
+
+    fn covering(&self, name: &str) -> Option<&Nsec<'a>> {
+        self.records.iter().find(|nsec| self.covers(nsec, name))
+    }
+
+    fn matching(&self, name: &str) -> Option<&Nsec<'a>> {
+        self.records
+            .iter()
+            .find(|nsec| nsec.owner.eq_ignore_ascii_case(name))
+    }
+
+    // The deepest ancestor of the name that the covering NSEC shows to exist
+    fn closest_encloser<'n>(&self, name: &'n str, nsec: &Nsec) -> &'n str {
+        let owner = common_ancestor(name, nsec.owner);
+        let next = common_ancestor(name, nsec.next);
+
+        if label_count(owner) >= label_count(next) {
+            owner
+        } else {
+            next
+        }
+    }
+
+    fn prove_nxdomain(&self, name: &str) -> Result<Proof> {
+        let nsec = self
+            .covering(name)
+            .ok_or_else(|| format!("no NSEC proves that {} does not exist", name))?;
+
+        let wildcard = wildcard_of(self.closest_encloser(name, nsec));
+        if self.covering(&wildcard).is_none() {
+            return Err(format!("no NSEC proves that {} does not exist", wildcard).into());
+        }
+
+        Ok(Proof::Denied)
+    }
+
+    fn prove_nodata(&self, name: &str, qtype: QueryType) -> Result<Proof> {
+        if let Some(nsec) = self.matching(name) {
+            return deny_type(nsec.types, name, qtype);
+        }
+
+        if let Some(nsec) = self.covering(name) {
+            // An empty non-terminal has no NSEC of its own, but the one
+            // covering it points to a name below it
+            if is_subdomain(nsec.next, name) {
+                return Ok(Proof::Denied);
+            }
+
+            // The type may also be missing at the wildcard the name would
+            // have been synthesized from (RFC 4035 3.1.3.4)
+            let wildcard = wildcard_of(self.closest_encloser(name, nsec));
+            if let Some(nsec) = self.matching(&wildcard) {
+                return deny_type(nsec.types, &wildcard, qtype);
+            }
+        }
+
+        Err(format!("no NSEC proves that {} has no {} records", name, qtype).into())
+    }
+
+    fn prove_wildcard(&self, name: &str) -> Result<Proof> {
+        match self.covering(name) {
+            Some(_) => Ok(Proof::Denied),
+            None => Err(format!("no NSEC proves that {} does not exist", name).into()),
+        }
+    }
+}
+
+struct Nsec3<'a> {
+    record: &'a DnsRecord,
+    hash: String,
+    next: String,
+    opt_out: bool,
+    salt: &'a [u8],
+    iterations: u16,
+    types: &'a [QueryType],
+}
+
+impl Nsec3<'_> {
+    fn hash_of(&self, name: &str) -> String {
+        nsec3_hash(name, self.salt, self.iterations)
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        self.hash == self.hash_of(name)
+    }
+
+    // Hashes sort in the order of their base32hex encoding, the last NSEC3
+    // of the chain points back to the first one
+    fn covers(&self, name: &str) -> bool {
+        let hash = self.hash_of(name);
+
+        match self.hash.cmp(&self.next) {
+            Ordering::Less => self.hash < hash && hash < self.next,
+            _ => hash > self.hash || hash < self.next,
+        }
+    }
+}
+
+// Iterated SHA-1 over the canonical name and the salt (RFC 5155 5)
+fn nsec3_hash(name: &str, salt: &[u8], iterations: u16) -> String {
+    let mut data = canonical_wire(name);
+
+    for _ in 0..=iterations {
+        data.extend_from_slice(salt);
+        data = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, &data)
+            .as_ref()
+            .to_vec();
+    }
+
+    base32hex_encode(&data)
+}
+
+struct Nsec3Chain<'a> {
+    zone: &'a str,
+    records: Vec<Nsec3<'a>>,
+}
+
+// NSEC3 records of the zone with a hash algorithm we know, if there are any
+fn nsec3_chain<'a>(records: &'a [DnsRecord], zone: &'a str) -> Option<Nsec3Chain<'a>> {
+    let records: Vec<Nsec3> = records
+        .iter()
+        .filter_map(|record| match record {
+            DnsRecord::NSEC3 {
+                domain,
+                hash_algorithm: NSEC3_SHA1,
+                flags,
+                iterations,
+                salt,
+                next_hashed_owner,
+                types,
+                ..
+            } if parent(domain).is_some_and(|owner_zone| owner_zone.eq_ignore_ascii_case(zone)) => {
+                let label = domain.split('.').next().unwrap_or_default();
+
+                Some(Nsec3 {
+                    record,
+                    hash: label.to_ascii_uppercase(),
+                    next: base32hex_encode(next_hashed_owner),
+                    opt_out: flags & NSEC3_OPT_OUT != 0,
+                    salt,
+                    iterations: *iterations,
+                    types,
+                })
+            }
+            _ => None,
+        })
+        .collect();
+
+    if records.is_empty() {
+        return None;
+    }
+
+    Some(Nsec3Chain { zone, records })
+}
+
+impl<'a> Nsec3Chain<'a> {
+    fn unchecked(&self) -> bool {
+        self.records
+            .iter()
+            .any(|nsec3| nsec3.iterations > MAX_NSEC3_ITERATIONS)
+    }
+
+    fn matching(&self, name: &str) -> Option<&Nsec3<'a>> {
+        self.records.iter().find(|nsec3| nsec3.matches(name))
+    }
+
+    fn covering(&self, name: &str) -> Option<&Nsec3<'a>> {
+        self.records.iter().find(|nsec3| nsec3.covers(name))
+    }
+
+    // Finds the deepest existing ancestor of the name and checks that the
+    // next closer name below it is covered (RFC 5155 8.3). Returns the
+    // closest encloser and whether the covering NSEC3 has opt-out set
+    fn closest_encloser<'n>(&self, name: &'n str) -> Result<(&'n str, bool)> {
+        for count in (label_count(self.zone)..label_count(name)).rev() {
+            let ancestor = last_labels(name, count);
+            let nsec3 = match self.matching(ancestor) {
+                Some(x) => x,
+                None => continue,
+            };
+
+            // The closest encloser cannot be a zone cut (RFC 5155 8.3)
+            if is_delegation(nsec3.types) {
+                return Err(format!("NSEC3 shows that {} is a delegation", ancestor).into());
+            }
+
+            let next_closer = last_labels(name, count + 1);
+            let nsec3 = self
+                .covering(next_closer)
+                .ok_or_else(|| format!("no NSEC3 proves that {} does not exist", next_closer))?;
+
+            return Ok((ancestor, nsec3.opt_out));
+        }
+
+        Err(format!("no NSEC3 proves a closest encloser of {}", name).into())
+    }
+
+    fn prove_nxdomain(&self, name: &str) -> Result<Proof> {
+        if self.unchecked() {
+            return Ok(Proof::Unchecked);
+        }
+
+        if self.matching(name).is_some() {
+            return Err(format!("NSEC3 shows that {} exists", name).into());
+        }
+
+        let (closest_encloser, opt_out) = self.closest_encloser(name)?;
+
+        let wildcard = wildcard_of(closest_encloser);
+        if self.covering(&wildcard).is_none() {
+            return Err(format!("no NSEC3 proves that {} does not exist", wildcard).into());
+        }
+
+        Ok(if opt_out {
+            Proof::OptOut
+        } else {
+            Proof::Denied
+        })
+    }
+
+    fn prove_nodata(&self, name: &str, qtype: QueryType) -> Result<Proof> {
+        if self.unchecked() {
+            return Ok(Proof::Unchecked);
+        }
+
+        if let Some(nsec3) = self.matching(name) {
+            return deny_type(nsec3.types, name, qtype);
+        }
+
+        let (closest_encloser, opt_out) = self.closest_encloser(name)?;
+
+        // Unsigned delegations in an opt-out span have no NSEC3 of their own
+        // (RFC 5155 8.6)
+        if qtype == QueryType::DS && opt_out {
+            return Ok(Proof::OptOut);
+        }
+
+        // Wildcard NODATA (RFC 5155 8.7)
+        let wildcard = wildcard_of(closest_encloser);
+        if let Some(nsec3) = self.matching(&wildcard) {
+            return deny_type(nsec3.types, &wildcard, qtype);
+        }
+
+        Err(format!("no NSEC3 proves that {} has no {} records", name, qtype).into())
+    }
+
+    fn prove_wildcard(&self, name: &str, closest_encloser: &str) -> Result<Proof> {
+        if self.unchecked() {
+            return Ok(Proof::Unchecked);
+        }
+
+        let next_closer = last_labels(name, label_count(closest_encloser) + 1);
+
+        match self.covering(next_closer) {
+            Some(nsec3) if nsec3.opt_out => Ok(Proof::OptOut),
+            Some(_) => Ok(Proof::Denied),
+            None => Err(format!("no NSEC3 proves that {} does not exist", next_closer).into()),
+        }
+    }
+}
@@ -0,0 +1,188 @@
+// Aggressive use of validated NSEC and NSEC3 records (RFC 8198). The proofs
+// of validated negative answers are kept by zone, so that queries for other
+// names falling into the same gaps are answered without going upstream
+
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use super::denial::{prove_nodata, prove_nxdomain, relevant_proofs, Proof};
+use crate::models::{
+    dns_packet::DnsPacket, dns_record::DnsRecord, query_type::QueryType, result_code::ResultCode,
+};
+use crate::utils::domain_name::{is_subdomain, parent};
+
+const MAX_ZONES: usize = 1000;
+const MAX_PROOFS_PER_ZONE: usize = 1000;
+
+#[derive(Debug)]
+struct CachedRRset {
+    records: Vec<DnsRecord>,
+    expires_at: Instant,
+}
+
+impl CachedRRset {
+    fn fresh(&self, now: Instant) -> Option<Vec<DnsRecord>> {
+        if self.expires_at <= now {
+            return None;
+        }
+
+        let ttl = (self.expires_at - now).as_secs() as u32;
+        let mut records = self.records.clone();
+        for record in records.iter_mut() {
+            record.set_ttl(ttl);
+        }
+
+        Some(records)
+    }
+}
+
+#[derive(Debug, Default)]
+struct ZoneProofs {
+    soa: Option<CachedRRset>,
+    // NSEC and NSEC3 RRsets along with their signatures, by owner name
+    proofs: HashMap<String, CachedRRset>,
+}
+
+impl ZoneProofs {
+    fn expired(&self, now: Instant) -> bool {
+        self.soa.as_ref().is_none_or(|soa| soa.expires_at <= now)
+            && self.proofs.values().all(|proof| proof.expires_at <= now)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct DenialCache {
+    zones: RwLock<HashMap<String, ZoneProofs>>,
+}
+
+impl DenialCache {
+    pub fn new() -> DenialCache {
+        DenialCache::default()
+    }
+
+    // Stores the SOA, NSEC and NSEC3 RRsets signed by zone out of the
+    // authority section of a validated response
+    pub fn insert(&self, zone: &str, authorities: &[DnsRecord]) {
+        let zone = zone.to_ascii_lowercase();
+        let signed_by_zone = |record: &DnsRecord| match record {
+            DnsRecord::RRSIG { signer_name, .. } => signer_name.eq_ignore_ascii_case(&zone),
+            _ => is_subdomain(record.domain(), &zone),
+        };
+
+        let mut rrsets: HashMap<(String, QueryType), Vec<DnsRecord>> = HashMap::new();
+        for record in authorities.iter().filter(|record| signed_by_zone(record)) {
+            let qtype = match record {
+                DnsRecord::RRSIG { type_covered, .. } => *type_covered,
+                _ => record.qtype(),
+            };
+
+            if let QueryType::SOA | QueryType::NSEC | QueryType::NSEC3 = qtype {
+                rrsets
+                    .entry((record.domain().to_ascii_lowercase(), qtype))
+                    .or_default()
+                    .push(record.clone());
+            }
+        }
+
+        let now = Instant::now();
+        let mut zones = self.zones.write().unwrap();
+        if !zones.contains_key(&zone) && zones.len() >= MAX_ZONES {
+            zones.retain(|_, proofs| !proofs.expired(now));
+            if zones.len() >= MAX_ZONES {
+                return;
+            }
+        }
+        let entry = zones.entry(zone.clone()).or_default();
+
+        // Negative answers are not to be used for longer than the SOA allows
+        // (RFC 9077 3)
+        let soa_ttl = rrsets
+            .get(&(zone.clone(), QueryType::SOA))
+            .and_then(|records| {
+                records.iter().find_map(|record| match record {
+                    DnsRecord::SOA { ttl, minimum, .. } => Some((*ttl).min(*minimum)),
+                    _ => None,
+                })
+            });
+
+        for ((owner, qtype), records) in rrsets {
+            let ttl = records
+                .iter()
+                .map(|record| record.ttl())
+                .chain(soa_ttl)
+                .min()
+                .unwrap_or(0);
+            let cached = CachedRRset {
+                records,
+                expires_at: now + Duration::from_secs(ttl as u64),
+            };
+
+            if qtype == QueryType::SOA {
+                if owner == zone {
+                    entry.soa = Some(cached);
+                }
+                continue;
+            }
+
+            if !entry.proofs.contains_key(&owner) && entry.proofs.len() >= MAX_PROOFS_PER_ZONE {
+                entry.proofs.retain(|_, proof| proof.expires_at > now);
+                if entry.proofs.len() >= MAX_PROOFS_PER_ZONE {
+                    continue;
+                }
+            }
+            entry.proofs.insert(owner, cached);
+        }
+    }
+
+    // Builds an NXDOMAIN or NODATA response from the proofs cached for the
+    // zone the name is in, if they cover it. Opt-out spans may hide unsigned
+    // delegations and are not used (RFC 8198 5.1)
+    pub fn synthesize(&self, qname: &str, qtype: QueryType) -> Option<DnsPacket> {
+        let now = Instant::now();
+        let zones = self.zones.read().unwrap();
+
+        // DS records belong to the parent side of a zone cut
+        let mut name = match qtype {
+            QueryType::DS => parent(qname)?,
+            _ => qname,
+        };
+        let (zone, proofs) = loop {
+            if let Some(proofs) = zones.get(&name.to_ascii_lowercase()) {
+                break (name, proofs);
+            }
+            name = parent(name)?;
+        };
+
+        let soa = proofs.soa.as_ref()?.fresh(now)?;
+        let rrsets: Vec<Vec<DnsRecord>> = proofs
+            .proofs
+            .values()
+            .filter_map(|proof| proof.fresh(now))
+            .collect();
+        let records: Vec<DnsRecord> = rrsets.iter().flatten().cloned().collect();
+
+        let result_code = match prove_nxdomain(qname, zone, &records) {
+            Ok(Proof::Denied) => ResultCode::NXDOMAIN,
+            Ok(_) => return None,
+            Err(_) => match prove_nodata(qname, qtype, zone, &records) {
+                Ok(Proof::Denied) => ResultCode::NOERROR,
+                _ => return None,
+            },
+        };
+
+        let relevant = relevant_proofs(qname, zone, &records);
+
+        let mut packet = DnsPacket::new();
+        packet.header.is_response = true;
+        packet.header.result_code = result_code;
+        packet.authorities = soa;
+        for rrset in rrsets {
+            if rrset.iter().any(|record| relevant.contains(&record)) {
+                packet.authorities.extend(rrset);
+            }
+        }
+
+        Some(packet)
+    }
+}
@@ -0,0 +1,7 @@
+pub mod denial;
+pub mod denial_cache;
+pub mod signature;
+#[cfg(test)]
+mod signed_zone;
+pub mod trust_anchor;
+pub mod validator;
@@ -0,0 +1,179 @@
+// Cryptographic primitives of DNSSEC: signature verification for the
+// algorithms we support, key tags and DS digests
+
+use ring::digest;
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey};
+
+use crate::models::dns_record::DnsRecord;
+use crate::utils::domain_name::canonical_wire;
+
+// DNSKEY flags (RFC 4034 2.1.1)
+const ZONE_KEY_FLAG: u16 = 0x0100;
+const DNSSEC_PROTOCOL: u8 = 3;
+
+// Algorithm numbers from the IANA registry, see RFC 8624 3.1 for which of
+// them validators are expected to implement
+const RSASHA256: u8 = 8;
+const ECDSAP256SHA256: u8 = 13;
+const ECDSAP384SHA384: u8 = 14;
+const ED25519: u8 = 15;
+
+// Digest types for DS records (RFC 8624 3.3)
+const DIGEST_SHA1: u8 = 1;
+const DIGEST_SHA256: u8 = 2;
+const DIGEST_SHA384: u8 = 4;
+
+pub fn is_supported_algorithm(algorithm: u8) -> bool {
+    matches!(
+        algorithm,
+        RSASHA256 | ECDSAP256SHA256 | ECDSAP384SHA384 | ED25519
+    )
+}
+
+pub fn is_supported_digest(digest_type: u8) -> bool {
+    matches!(digest_type, DIGEST_SHA1 | DIGEST_SHA256 | DIGEST_SHA384)
+}
+
+// RSA public keys are the exponent length, the exponent and the modulus
+// (RFC 3110 2), the exponent length taking three octets if it exceeds 255
+fn rsa_components(key: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (exponent_len, rest) = match key {
+        [0, high, low, rest @ ..] => ((*high as usize) << 8 | *low as usize, rest),
+        [len, rest @ ..] => (*len as usize, rest),
+        [] => return None,
+    };
+
+    if exponent_len == 0 || rest.len() <= exponent_len {
+        return None;
+    }
+
+    let (exponent, modulus) = rest.split_at(exponent_len);
+    let trim = |x: &[u8]| -> usize { x.iter().take_while(|byte| **byte == 0).count() };
+
+    Some((&exponent[trim(exponent)..], &modulus[trim(modulus)..]))
+}
+
+// ECDSA keys are the bare point coordinates (RFC 6605 4), ring expects the
+// uncompressed SEC1 encoding
+fn ecdsa_point(key: &[u8], size: usize) -> Option<Vec<u8>> {
+    if key.len() != 2 * size {
+        return None;
+    }
+
+    let mut point = Vec::with_capacity(key.len() + 1);
+    point.push(0x04);
+    point.extend_from_slice(key);
+
+    Some(point)
+}
+
+pub fn verify(algorithm: u8, public_key: &[u8], data: &[u8], sig: &[u8]) -> bool {
+    match algorithm {
+        RSASHA256 => match rsa_components(public_key) {
+            // ring insists on at least 2048 bits by default, while plenty of
+            // zones still sign with 1024 bit keys
+            Some((e, n)) => RsaPublicKeyComponents { n, e }
+                .verify(
+                    &signature::RSA_PKCS1_1024_8192_SHA256_FOR_LEGACY_USE_ONLY,
+                    data,
+                    sig,
+                )
+                .is_ok(),
+            None => false,
+        },
+        ECDSAP256SHA256 => match ecdsa_point(public_key, 32) {
+            Some(point) => UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_FIXED, point)
+                .verify(data, sig)
+                .is_ok(),
+            None => false,
+        },
+        ECDSAP384SHA384 => match ecdsa_point(public_key, 48) {
+            Some(point) => UnparsedPublicKey::new(&signature::ECDSA_P384_SHA384_FIXED, point)
+                .verify(data, sig)
+                .is_ok(),
+            None => false,
+        },
+        ED25519 => UnparsedPublicKey::new(&signature::ED25519, public_key)
+            .verify(data, sig)
+            .is_ok(),
+        _ => false,
+    }
+}
+
+// Checksum over the DNSKEY RDATA (RFC 4034 Appendix B)
+pub fn key_tag(key: &DnsRecord) -> Option<u16> {
+    if !matches!(key, DnsRecord::DNSKEY { .. }) {
+        return None;
+    }
+
+    let rdata = key.rdata().ok()?;
+    let mut accumulator: u32 = 0;
+
+    for (index, byte) in rdata.iter().enumerate() {
+        accumulator += if index & 1 == 0 {
+            (*byte as u32) << 8
+        } else {
+            *byte as u32
+        };
+    }
+    accumulator += accumulator >> 16 & 0xffff;
+
+    Some((accumulator & 0xffff) as u16)
+}
+
+// Whether the key can be used to verify signatures of the zone it is in
+pub fn is_zone_key(key: &DnsRecord) -> bool {
+    matches!(
+        key,
+        DnsRecord::DNSKEY { flags, protocol, .. }
+            if flags & ZONE_KEY_FLAG != 0 && *protocol == DNSSEC_PROTOCOL
+    )
+}
+
+// The digest a DS record holds for a key, taken over the owner name and
+// RDATA of the DNSKEY (RFC 4034 5.1.4)
+pub fn ds_digest(digest_type: u8, key: &DnsRecord) -> Option<Vec<u8>> {
+    let algorithm = match digest_type {
+        DIGEST_SHA1 => &digest::SHA1_FOR_LEGACY_USE_ONLY,
+        DIGEST_SHA256 => &digest::SHA256,
+        DIGEST_SHA384 => &digest::SHA384,
+        _ => return None,
+    };
+
+    let mut data = canonical_wire(key.domain());
+    data.extend(key.rdata().ok()?);
+
+    Some(digest::digest(algorithm, &data).as_ref().to_vec())
+}
+
+// A key is vouched for by a DS record, or by a trust anchor given either as
+// a DS record or as the DNSKEY itself
+pub fn matches_key(anchor: &DnsRecord, key: &DnsRecord) -> bool {
+    if !anchor.domain().eq_ignore_ascii_case(key.domain()) {
+        return false;
+    }
+
+    match (anchor, key) {
+        (
+            DnsRecord::DS {
+                key_tag: tag,
+                algorithm,
+                digest_type,
+                digest,
+                ..
+            },
+            DnsRecord::DNSKEY {
+                algorithm: key_algorithm,
+                ..
+            },
+        ) => {
+            algorithm == key_algorithm
+                && key_tag(key) == Some(*tag)
+                && ds_digest(*digest_type, key).as_ref() == Some(digest)
+        }
+        (DnsRecord::DNSKEY { .. }, DnsRecord::DNSKEY { .. }) => {
+            anchor.rdata().ok() == key.rdata().ok()
+        }
+        _ => false,
+    }
+}
@@ -0,0 +1,427 @@
+// Zones signed on the spot for validator tests, with a key signing key
+// vouched for by the DS record in the parent and a zone signing key for
+// everything else. Denial of existence is NSEC or NSEC3 over the names given
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ring::digest;
+use ring::rand::SystemRandom;
+use ring::rsa::PublicKeyComponents;
+use ring::signature::{
+    EcdsaKeyPair, EcdsaSigningAlgorithm, Ed25519KeyPair, KeyPair, RsaKeyPair,
+    ECDSA_P256_SHA256_FIXED_SIGNING, ECDSA_P384_SHA384_FIXED_SIGNING, RSA_PKCS1_SHA256,
+};
+
+use super::signature::{ds_digest, key_tag};
+use crate::models::{
+    dns_packet::DnsPacket, dns_record::DnsRecord, query_type::QueryType, result_code::ResultCode,
+};
+use crate::types::Result;
+use crate::utils::domain_name::{canonical_cmp, canonical_wire, is_subdomain, label_count, parent};
+use crate::utils::encoding::base32hex_encode;
+
+const RSASHA256: u8 = 8;
+const ECDSAP256SHA256: u8 = 13;
+const ECDSAP384SHA384: u8 = 14;
+const ED25519: u8 = 15;
+const DIGEST_SHA256: u8 = 2;
+const TTL: u32 = 3600;
+
+// DNSKEY flags of a zone key, with the secure entry point bit for KSKs
+const ZSK_FLAGS: u16 = 0x0100;
+const KSK_FLAGS: u16 = 0x0101;
+
+// ring does not generate RSA keys, so these are 2048 bit keys made with
+// openssl genpkey and converted to PKCS #8 with openssl pkcs8 -topk8
+const RSA_KSK: &[u8] = include_bytes!("rsa_ksk.pk8");
+const RSA_ZSK: &[u8] = include_bytes!("rsa_zsk.pk8");
+
+#[derive(Clone, Copy)]
+pub enum Algorithm {
+    RsaSha256,
+    EcdsaP256,
+    EcdsaP384,
+    Ed25519,
+}
+
+pub enum Denial {
+    Nsec,
+    Nsec3 { iterations: u16, opt_out: bool },
+}
+
+pub struct Key {
+    pair: SigningPair,
+    pub record: DnsRecord,
+}
+
+enum SigningPair {
+    Rsa(RsaKeyPair),
+    Ecdsa(EcdsaKeyPair),
+    Ed25519(Ed25519KeyPair),
+}
+
+impl Key {
+    fn generate(zone: &str, algorithm: &Algorithm, flags: u16) -> Key {
+        let rng = SystemRandom::new();
+
+        let (pair, number, public_key) = match algorithm {
+            Algorithm::RsaSha256 => {
+                let pkcs8 = match flags {
+                    KSK_FLAGS => RSA_KSK,
+                    _ => RSA_ZSK,
+                };
+                let pair = RsaKeyPair::from_pkcs8(pkcs8).unwrap();
+                let components = PublicKeyComponents::<Vec<u8>>::from(pair.public());
+                // RFC 3110 2: exponent length, exponent and modulus
+                let mut public_key = vec![components.e.len() as u8];
+                public_key.extend(&components.e);
+                public_key.extend(&components.n);
+                (SigningPair::Rsa(pair), RSASHA256, public_key)
+            }
+            Algorithm::EcdsaP256 => {
+                let pair = generate_ecdsa(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng);
+                // DNSKEY holds the point without the uncompressed marker
+                let public_key = pair.public_key().as_ref()[1..].to_vec();
+                (SigningPair::Ecdsa(pair), ECDSAP256SHA256, public_key)
+            }
+            Algorithm::EcdsaP384 => {
+                let pair = generate_ecdsa(&ECDSA_P384_SHA384_FIXED_SIGNING, &rng);
+                let public_key = pair.public_key().as_ref()[1..].to_vec();
+                (SigningPair::Ecdsa(pair), ECDSAP384SHA384, public_key)
+            }
+            Algorithm::Ed25519 => {
+                let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+                let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+                let public_key = pair.public_key().as_ref().to_vec();
+                (SigningPair::Ed25519(pair), ED25519, public_key)
+            }
+        };
+
+        Key {
+            pair,
+            record: DnsRecord::DNSKEY {
+                domain: zone.to_string(),
+                flags,
+                protocol: 3,
+                algorithm: number,
+                public_key,
+                ttl: TTL,
+            },
+        }
+    }
+
+    fn sign(&self, data: &[u8]) -> Vec<u8> {
+        match &self.pair {
+            SigningPair::Rsa(pair) => {
+                let mut signature = vec![0; pair.public().modulus_len()];
+                pair.sign(
+                    &RSA_PKCS1_SHA256,
+                    &SystemRandom::new(),
+                    data,
+                    &mut signature,
+                )
+                .unwrap();
+                signature
+            }
+            SigningPair::Ecdsa(pair) => pair
+                .sign(&SystemRandom::new(), data)
+                .unwrap()
+                .as_ref()
+                .to_vec(),
+            SigningPair::Ed25519(pair) => pair.sign(data).as_ref().to_vec(),
+        }
+    }
+
+    fn algorithm(&self) -> u8 {
+        match self.record {
+            DnsRecord::DNSKEY { algorithm, .. } => algorithm,
+            _ => unreachable!(),
+        }
+    }
+}
+
+fn generate_ecdsa(algorithm: &'static EcdsaSigningAlgorithm, rng: &SystemRandom) -> EcdsaKeyPair {
+    let pkcs8 = EcdsaKeyPair::generate_pkcs8(algorithm, rng).unwrap();
+    EcdsaKeyPair::from_pkcs8(algorithm, pkcs8.as_ref(), rng).unwrap()
+}
+
+pub struct Zone {
+    pub name: String,
+    pub ksk: Key,
+    pub zsk: Key,
+    denial: Denial,
+    // Names in the zone along with the types present at each
+    names: Vec<(String, Vec<QueryType>)>,
+}
+
+impl Zone {
+    pub fn new(
+        name: &str,
+        algorithm: Algorithm,
+        denial: Denial,
+        names: &[(&str, &[QueryType])],
+    ) -> Zone {
+        Zone {
+            name: name.to_string(),
+            ksk: Key::generate(name, &algorithm, KSK_FLAGS),
+            zsk: Key::generate(name, &algorithm, ZSK_FLAGS),
+            denial,
+            names: names
+                .iter()
+                .map(|(name, types)| (name.to_string(), types.to_vec()))
+                .collect(),
+        }
+    }
+
+    // The DS record for the KSK, as published by the parent zone
+    pub fn ds(&self) -> DnsRecord {
+        DnsRecord::DS {
+            domain: self.name.clone(),
+            key_tag: key_tag(&self.ksk.record).unwrap(),
+            algorithm: self.ksk.algorithm(),
+            digest_type: DIGEST_SHA256,
+            digest: ds_digest(DIGEST_SHA256, &self.ksk.record).unwrap(),
+            ttl: TTL,
+        }
+    }
+
+    // The DNSKEY RRset signed by the KSK
+    pub fn keys(&self) -> Vec<DnsRecord> {
+        let keys = vec![self.ksk.record.clone(), self.zsk.record.clone()];
+        self.sign_with(&self.ksk, keys, now() - 3600, now() + 3600)
+    }
+
+    // The RRset along with its RRSIG by the ZSK, valid for an hour either way
+    pub fn sign(&self, records: Vec<DnsRecord>) -> Vec<DnsRecord> {
+        self.sign_with(&self.zsk, records, now() - 3600, now() + 3600)
+    }
+
+    pub fn sign_with(
+        &self,
+        key: &Key,
+        mut records: Vec<DnsRecord>,
+        inception: u32,
+        expiration: u32,
+    ) -> Vec<DnsRecord> {
+        let owner = records[0].domain().to_string();
+
+        // A wildcard RRSIG has the labels of the wildcard's parent
+        let labels = match owner.strip_prefix("*.") {
+            Some(parent) => label_count(parent),
+            None => label_count(&owner),
+        };
+
+        let mut signature = DnsRecord::RRSIG {
+            domain: owner,
+            type_covered: records[0].qtype(),
+            algorithm: key.algorithm(),
+            labels: labels as u8,
+            original_ttl: TTL,
+            expiration,
+            inception,
+            key_tag: key_tag(&key.record).unwrap(),
+            signer_name: self.name.clone(),
+            signature: Vec::new(),
+            ttl: TTL,
+        };
+
+        let data = signature.signed_data(&records).unwrap();
+        if let DnsRecord::RRSIG { signature, .. } = &mut signature {
+            *signature = key.sign(&data);
+        }
+
+        records.push(signature);
+        records
+    }
+
+    // The whole NSEC or NSEC3 chain of the zone, each record signed
+    pub fn denial(&self) -> Vec<DnsRecord> {
+        match self.denial {
+            Denial::Nsec => self.nsec_chain(),
+            Denial::Nsec3 {
+                iterations,
+                opt_out,
+            } => self.nsec3_chain(iterations, opt_out),
+        }
+    }
+
+    fn nsec_chain(&self) -> Vec<DnsRecord> {
+        let mut names = self.names.clone();
+        names.sort_by(|a, b| canonical_cmp(&a.0, &b.0));
+
+        let mut chain = Vec::new();
+        for (idx, (name, types)) in names.iter().enumerate() {
+            let next = &names[(idx + 1) % names.len()].0;
+            let mut types = types.clone();
+            types.extend([QueryType::NSEC, QueryType::RRSIG]);
+
+            chain.extend(self.sign(vec![DnsRecord::NSEC {
+                domain: name.clone(),
+                next_domain: next.clone(),
+                types,
+                ttl: TTL,
+            }]));
+        }
+
+        chain
+    }
+
+    fn nsec3_chain(&self, iterations: u16, opt_out: bool) -> Vec<DnsRecord> {
+        let salt = vec![0xab, 0xcd];
+
+        let mut hashes: Vec<(Vec<u8>, &Vec<QueryType>)> = self
+            .names
+            .iter()
+            .map(|(name, types)| (nsec3_hash(name, &salt, iterations), types))
+            .collect();
+        hashes.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut chain = Vec::new();
+        for (idx, (hash, types)) in hashes.iter().enumerate() {
+            let next = &hashes[(idx + 1) % hashes.len()].0;
+            let mut types = types.to_vec();
+            types.push(QueryType::RRSIG);
+
+            chain.extend(self.sign(vec![DnsRecord::NSEC3 {
+                domain: format!("{}.{}", base32hex_encode(hash), self.name),
+                hash_algorithm: 1,
+                flags: opt_out as u8,
+                iterations,
+                salt: salt.clone(),
+                next_hashed_owner: next.clone(),
+                types,
+                ttl: TTL,
+            }]));
+        }
+
+        chain
+    }
+
+    // Whether the name or anything below it is in the zone, or a wildcard
+    // at its closest encloser stands in for it
+    fn has(&self, name: &str) -> bool {
+        let exists = |name: &str| {
+            self.names
+                .iter()
+                .any(|(owner, _)| is_subdomain(owner, name))
+        };
+
+        let mut ancestor = name;
+        while !exists(ancestor) {
+            ancestor = match parent(ancestor) {
+                Some(x) => x,
+                None => return false,
+            };
+        }
+
+        ancestor == name || exists(&format!("*.{}", ancestor))
+    }
+}
+
+// Signed zones making up the tree below the root, the first one being the
+// root. Queries for DNSKEY and DS records are answered from them, unless
+// replaced by the test
+pub struct Tree {
+    pub zones: Vec<Zone>,
+    pub replaced: Vec<(String, QueryType, DnsPacket)>,
+}
+
+impl Tree {
+    pub fn new(zones: Vec<Zone>) -> Tree {
+        Tree {
+            zones,
+            replaced: Vec::new(),
+        }
+    }
+
+    pub fn zone(&self, name: &str) -> &Zone {
+        self.zones.iter().find(|zone| zone.name == name).unwrap()
+    }
+
+    pub fn anchors(&self) -> Vec<DnsRecord> {
+        vec![self.zones[0].ds()]
+    }
+
+    pub fn replace(&mut self, name: &str, qtype: QueryType, response: DnsPacket) {
+        self.replaced.push((name.to_string(), qtype, response));
+    }
+
+    pub fn fetch(&self, name: &str, qtype: QueryType) -> Result<DnsPacket> {
+        if let Some((_, _, response)) = self
+            .replaced
+            .iter()
+            .find(|(owner, replaced, _)| owner == name && *replaced == qtype)
+        {
+            return Ok(response.clone());
+        }
+
+        match qtype {
+            QueryType::DNSKEY => {
+                let zone = self
+                    .zones
+                    .iter()
+                    .find(|zone| zone.name == name)
+                    .ok_or_else(|| format!("no zone {:?}", name))?;
+                Ok(response(ResultCode::NOERROR, zone.keys(), Vec::new()))
+            }
+            QueryType::DS => {
+                // The DS records of a zone are in its parent, the deepest
+                // zone above it
+                let parent = self
+                    .zones
+                    .iter()
+                    .filter(|zone| zone.name != name && is_subdomain(name, &zone.name))
+                    .max_by_key(|zone| label_count(&zone.name))
+                    .ok_or_else(|| format!("no zone above {:?}", name))?;
+
+                if let Some(child) = self.zones.iter().find(|zone| zone.name == name) {
+                    let answers = parent.sign(vec![child.ds()]);
+                    return Ok(response(ResultCode::NOERROR, answers, Vec::new()));
+                }
+
+                let code = match parent.has(name) {
+                    true => ResultCode::NOERROR,
+                    false => ResultCode::NXDOMAIN,
+                };
+                Ok(response(code, Vec::new(), parent.denial()))
+            }
+            _ => Err(format!("unexpected query for {} {}", name, qtype).into()),
+        }
+    }
+}
+
+pub fn response(
+    code: ResultCode,
+    answers: Vec<DnsRecord>,
+    authorities: Vec<DnsRecord>,
+) -> DnsPacket {
+    let mut packet = DnsPacket::new();
+    packet.header.is_response = true;
+    packet.header.result_code = code;
+    packet.header.answers_count = answers.len() as u16;
+    packet.header.authority_records_count = authorities.len() as u16;
+    packet.answers = answers;
+    packet.authorities = authorities;
+    packet
+}
+
+// Iterated SHA-1 over the canonical name and the salt (RFC 5155 5)
+fn nsec3_hash(name: &str, salt: &[u8], iterations: u16) -> Vec<u8> {
+    let mut data = canonical_wire(name);
+
+    for _ in 0..=iterations {
+        data.extend_from_slice(salt);
+        data = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, &data)
+            .as_ref()
+            .to_vec();
+    }
+
+    data
+}
+
+pub fn now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as u32
+}
@@ -0,0 +1,118 @@
+// Trust anchors for DNSSEC validation, DS or DNSKEY records in zone file
+// format (RFC 4035 4.4). The root zone anchors are bundled
+
+use std::fs;
+use std::path::Path;
+
+use crate::models::dns_record::DnsRecord;
+use crate::types::Result;
+use crate::utils::encoding::{base64_decode, hex_decode};
+
+const DEFAULT_TRUST_ANCHORS: &str = include_str!("root.key");
+
+pub fn default_anchors() -> Vec<DnsRecord> {
+    parse(DEFAULT_TRUST_ANCHORS).expect("bundled trust anchors are valid")
+}
+
+pub fn from_file(path: &Path) -> Result<Vec<DnsRecord>> {
+    let data = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read trust anchors {}: {}", path.display(), e))?;
+
+    parse(&data)
+}
+
+pub fn parse(data: &str) -> Result<Vec<DnsRecord>> {
+    let mut anchors = Vec::new();
+    let mut pending = String::new();
+    let mut start = 0;
+
+    for (idx, line) in data.lines().enumerate() {
+        let line = match line.find(';') {
+            Some(pos) => &line[..pos],
+            None => line,
+        };
+
+        if pending.is_empty() {
+            start = idx + 1;
+        }
+        pending.push_str(line);
+        pending.push(' ');
+
+        // Parentheses let a record span several lines
+        if pending.matches('(').count() > pending.matches(')').count() {
+            continue;
+        }
+
+        let entry = pending.replace(['(', ')'], " ");
+        pending.clear();
+
+        let tokens: Vec<&str> = entry.split_whitespace().collect();
+        if tokens.is_empty() {
+            continue;
+        }
+
+        // owner [ttl] [class] type rdata
+        let type_pos = match tokens
+            .iter()
+            .skip(1)
+            .position(|token| matches!(token.to_ascii_uppercase().as_str(), "DS" | "DNSKEY"))
+        {
+            Some(pos) => pos + 1,
+            None => {
+                return Err(format!(
+                    "Expected a DS or DNSKEY record on trust anchor line {}",
+                    start
+                )
+                .into())
+            }
+        };
+
+        let domain = tokens[0].trim_end_matches('.').to_ascii_lowercase();
+        let rdata = &tokens[type_pos + 1..];
+
+        let anchor = if tokens[type_pos].eq_ignore_ascii_case("DS") {
+            parse_ds(domain, rdata)
+        } else {
+            parse_dnskey(domain, rdata)
+        }
+        .map_err(|e| format!("Invalid trust anchor on line {}: {}", start, e))?;
+
+        anchors.push(anchor);
+    }
+
+    if anchors.is_empty() {
+        return Err("Trust anchors contain no DS or DNSKEY records".into());
+    }
+
+    Ok(anchors)
+}
+
+fn parse_ds(domain: String, rdata: &[&str]) -> Result<DnsRecord> {
+    match rdata {
+        [key_tag, algorithm, digest_type, digest @ ..] if !digest.is_empty() => Ok(DnsRecord::DS {
+            domain,
+            key_tag: key_tag.parse()?,
+            algorithm: algorithm.parse()?,
+            digest_type: digest_type.parse()?,
+            digest: hex_decode(&digest.concat())?,
+            ttl: 0,
+        }),
+        _ => Err("DS needs a key tag, algorithm, digest type and digest".into()),
+    }
+}
+
+fn parse_dnskey(domain: String, rdata: &[&str]) -> Result<DnsRecord> {
+    match rdata {
+        [flags, protocol, algorithm, public_key @ ..] if !public_key.is_empty() => {
+            Ok(DnsRecord::DNSKEY {
+                domain,
+                flags: flags.parse()?,
+                protocol: protocol.parse()?,
+                algorithm: algorithm.parse()?,
+                public_key: base64_decode(&public_key.concat())?,
+                ttl: 0,
+            })
+        }
+        _ => Err("DNSKEY needs flags, protocol, algorithm and a public key".into()),
+    }
+}
@@ -0,0 +1,909 @@
+// DNSSEC validation as per RFC 4035 5. The chain of trust is followed down
+// from a trust anchor through DS and DNSKEY RRsets, and every RRset of a
+// response is checked against the keys of the zone that signed it. The keys
+// of each zone, and where the chain of trust ends, are remembered until
+// their TTL runs out
+
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use super::denial::{prove_nodata, prove_nxdomain, prove_wildcard, Proof};
+use super::denial_cache::DenialCache;
+use super::signature::{
+    is_supported_algorithm, is_supported_digest, is_zone_key, key_tag, matches_key, verify,
+};
+use crate::models::{
+    dns_packet::DnsPacket, dns_record::DnsRecord, query_type::QueryType, result_code::ResultCode,
+};
+use crate::types::Result;
+use crate::utils::domain_name::{is_subdomain, label_count, last_labels, parent};
+
+const MAX_ZONES: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Security {
+    Secure,
+    Insecure,
+}
+
+#[derive(Debug, Clone)]
+enum ZoneTrust {
+    // A signed zone along with its validated DNSKEY RRset
+    Secure(Vec<DnsRecord>),
+    // An unsigned delegation, or one signed with algorithms we do not know
+    Insecure,
+    // The name is not a zone cut
+    NoCut,
+}
+
+#[derive(Debug)]
+struct TrustEntry {
+    trust: ZoneTrust,
+    expires_at: Instant,
+}
+
+struct RRset {
+    records: Vec<DnsRecord>,
+    signatures: Vec<DnsRecord>,
+}
+
+struct Signed {
+    zone: String,
+    // Closest encloser of an RRset synthesized from a wildcard
+    wildcard: Option<String>,
+}
+
+// Looks up a name and type on behalf of the validator
+pub type Fetch<'a> = dyn Fn(&str, QueryType) -> Result<DnsPacket> + 'a;
+
+#[derive(Debug)]
+pub struct Validator {
+    anchors: Vec<DnsRecord>,
+    // Domains treated as unsigned whatever the zones above them say, like
+    // the zones forwarded to resolvers of their own (RFC 7646)
+    insecure: Vec<String>,
+    zones: RwLock<HashMap<String, TrustEntry>>,
+    // Validated NSEC and NSEC3 records, if they may be used aggressively
+    denials: Option<DenialCache>,
+}
+
+impl Validator {
+    pub fn new(anchors: Vec<DnsRecord>, insecure: Vec<String>, aggressive_nsec: bool) -> Validator {
+        Validator {
+            anchors,
+            insecure,
+            zones: RwLock::new(HashMap::new()),
+            denials: aggressive_nsec.then(DenialCache::new),
+        }
+    }
+
+    // A negative answer for the name proven by NSEC or NSEC3 records from an
+    // earlier validated response, see RFC 8198
+    pub fn synthesize(&self, qname: &str, qtype: QueryType) -> Option<DnsPacket> {
+        self.denials.as_ref()?.synthesize(qname, qtype)
+    }
+
+    // Checks every RRset in the answer section, then the proof of
+    // nonexistence for negative answers and wildcard expansions. Bogus
+    // responses are reported as errors
+    pub fn validate(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        response: &DnsPacket,
+        fetch: &Fetch,
+    ) -> Result<Security> {
+        // Signatures are not signed themselves
+        if qtype == QueryType::RRSIG {
+            return Ok(Security::Insecure);
+        }
+
+        let now = unix_time();
+        let mut security = Security::Secure;
+        let mut wildcards = Vec::new();
+
+        for rrset in group_rrsets(&response.answers) {
+            match self.check_rrset(&rrset, now, fetch)? {
+                Some(Signed {
+                    wildcard: Some(closest_encloser),
+                    ..
+                }) => wildcards.push((rrset.records[0].domain().to_string(), closest_encloser)),
+                Some(_) => {}
+                None => security = Security::Insecure,
+            }
+        }
+
+        let name = if qtype == QueryType::CNAME {
+            qname
+        } else {
+            response.resolve_cname_chain(qname)
+        };
+        let nxdomain = response.header.result_code == ResultCode::NXDOMAIN;
+        let nodata = !nxdomain
+            && !response.answers.iter().any(|record| {
+                record.qtype() == qtype && record.domain().eq_ignore_ascii_case(name)
+            });
+
+        if !nxdomain && !nodata && wildcards.is_empty() {
+            return Ok(security);
+        }
+
+        let (zone, records) =
+            match self.check_denial(name, qtype, &response.authorities, now, fetch)? {
+                Some(x) => x,
+                None => return Ok(Security::Insecure),
+            };
+
+        let mut proofs = Vec::new();
+        if nxdomain {
+            proofs.push(prove_nxdomain(name, &zone, &records)?);
+        } else if nodata {
+            proofs.push(prove_nodata(name, qtype, &zone, &records)?);
+        }
+        for (owner, closest_encloser) in wildcards {
+            proofs.push(prove_wildcard(&owner, &closest_encloser, &zone, &records)?);
+        }
+
+        if proofs
+            .iter()
+            .any(|proof| matches!(proof, Proof::OptOut | Proof::Unchecked))
+        {
+            security = Security::Insecure;
+        } else if let Some(denials) = &self.denials {
+            denials.insert(&zone, &response.authorities);
+        }
+
+        Ok(security)
+    }
+
+    // Returns None for data the chain of trust does not reach
+    fn check_rrset(&self, rrset: &RRset, now: u32, fetch: &Fetch) -> Result<Option<Signed>> {
+        let owner = rrset.records[0].domain();
+        let qtype = rrset.records[0].qtype();
+
+        // DS records belong to the parent side of a zone cut
+        let name = match qtype {
+            QueryType::DS => parent(owner).unwrap_or_default(),
+            _ => owner,
+        };
+
+        let signer = rrset
+            .signatures
+            .iter()
+            .find_map(|signature| match signature {
+                DnsRecord::RRSIG { signer_name, .. } if is_subdomain(name, signer_name) => {
+                    Some(signer_name.as_str())
+                }
+                _ => None,
+            });
+
+        if let Some(signer) = signer {
+            if let Some((zone, keys)) = self.find_keys(signer, now, fetch)? {
+                if !zone.eq_ignore_ascii_case(signer) {
+                    return Err(
+                        format!("{} signing {} {} is not a zone", signer, owner, qtype).into(),
+                    );
+                }
+
+                let wildcard = verify_rrset(&rrset.records, &rrset.signatures, &zone, &keys, now)?;
+                return Ok(Some(Signed { zone, wildcard }));
+            }
+        }
+
+        // Unsigned data, or data signed by a zone without a chain of trust, is
+        // only acceptable if the zone of the owner has none either
+        match self.find_keys(name, now, fetch)? {
+            Some((zone, _)) => Err(format!(
+                "{} {} is not signed by secure zone {:?}",
+                owner, qtype, zone
+            )
+            .into()),
+            None => Ok(None),
+        }
+    }
+
+    // Checks the SOA, NSEC and NSEC3 RRsets of a negative answer, returning
+    // the zone that signed them along with the NSEC and NSEC3 records
+    fn check_denial(
+        &self,
+        name: &str,
+        qtype: QueryType,
+        authorities: &[DnsRecord],
+        now: u32,
+        fetch: &Fetch,
+    ) -> Result<Option<(String, Vec<DnsRecord>)>> {
+        let rrsets: Vec<RRset> = group_rrsets(authorities)
+            .into_iter()
+            .filter(|rrset| {
+                matches!(
+                    rrset.records[0].qtype(),
+                    QueryType::SOA | QueryType::NSEC | QueryType::NSEC3
+                )
+            })
+            .collect();
+
+        if rrsets.is_empty() {
+            let name = match qtype {
+                QueryType::DS => parent(name).unwrap_or_default(),
+                _ => name,
+            };
+
+            return match self.find_keys(name, now, fetch)? {
+                Some((zone, _)) => Err(format!(
+                    "no denial of existence for {} from secure zone {:?}",
+                    name, zone
+                )
+                .into()),
+                None => Ok(None),
+            };
+        }
+
+        let mut zone = String::new();
+        let mut records = Vec::new();
+
+        for rrset in rrsets {
+            match self.check_rrset(&rrset, now, fetch)? {
+                Some(signed) => zone = signed.zone,
+                None => return Ok(None),
+            }
+
+            if rrset.records[0].qtype() != QueryType::SOA {
+                records.extend(rrset.records);
+            }
+        }
+
+        Ok(Some((zone, records)))
+    }
+
+    // Follows the chain of trust down to the zone containing name, returning
+    // that zone and its keys, or None where the chain ends above it
+    fn find_keys(
+        &self,
+        name: &str,
+        now: u32,
+        fetch: &Fetch,
+    ) -> Result<Option<(String, Vec<DnsRecord>)>> {
+        let anchor_zone = match self
+            .anchors
+            .iter()
+            .map(|anchor| anchor.domain())
+            .filter(|zone| is_subdomain(name, zone))
+            .max_by_key(|zone| label_count(zone))
+        {
+            Some(x) => x.to_ascii_lowercase(),
+            None => return Ok(None),
+        };
+
+        // A trust anchor below an insecure domain still applies to its zone
+        if self.insecure.iter().any(|domain| {
+            is_subdomain(name, domain) && label_count(domain) > label_count(&anchor_zone)
+        }) {
+            return Ok(None);
+        }
+
+        let mut keys = match self.cached(&anchor_zone) {
+            Some(ZoneTrust::Secure(keys)) => keys,
+            _ => {
+                let anchors: Vec<&DnsRecord> = self
+                    .anchors
+                    .iter()
+                    .filter(|anchor| anchor.domain().eq_ignore_ascii_case(&anchor_zone))
+                    .collect();
+
+                let (keys, ttl) = self.fetch_keys(
+                    &anchor_zone,
+                    |key| anchors.iter().any(|anchor| matches_key(anchor, key)),
+                    now,
+                    fetch,
+                )?;
+                self.remember(&anchor_zone, ZoneTrust::Secure(keys.clone()), ttl);
+
+                keys
+            }
+        };
+        let mut zone = anchor_zone;
+
+        for count in label_count(&zone) + 1..=label_count(name) {
+            let child = last_labels(name, count).to_ascii_lowercase();
+
+            let trust = match self.cached(&child) {
+                Some(x) => x,
+                None => {
+                    let (trust, ttl) = self.probe_cut(&zone, &keys, &child, now, fetch)?;
+                    self.remember(&child, trust.clone(), ttl);
+                    trust
+                }
+            };
+
+            match trust {
+                ZoneTrust::Secure(child_keys) => {
+                    zone = child;
+                    keys = child_keys;
+                }
+                ZoneTrust::Insecure => return Ok(None),
+                ZoneTrust::NoCut => {}
+            }
+        }
+
+        Ok(Some((zone, keys)))
+    }
+
+    // Asks for the DS RRset of child, which zone has to either sign or prove
+    // the absence of
+    fn probe_cut(
+        &self,
+        zone: &str,
+        keys: &[DnsRecord],
+        child: &str,
+        now: u32,
+        fetch: &Fetch,
+    ) -> Result<(ZoneTrust, u32)> {
+        let response = fetch(child, QueryType::DS)?;
+
+        if let Some(ds) = find_rrset(&response.answers, child, QueryType::DS) {
+            verify_rrset(&ds.records, &ds.signatures, zone, keys, now)?;
+
+            // Zones signed only with algorithms we do not implement are
+            // treated as unsigned (RFC 4035 5.2)
+            let usable: Vec<&DnsRecord> = ds
+                .records
+                .iter()
+                .filter(|record| {
+                    matches!(
+                        record,
+                        DnsRecord::DS { algorithm, digest_type, .. }
+                            if is_supported_algorithm(*algorithm) && is_supported_digest(*digest_type)
+                    )
+                })
+                .collect();
+
+            if usable.is_empty() {
+                return Ok((ZoneTrust::Insecure, min_ttl(&ds.records)));
+            }
+
+            let (child_keys, ttl) = self.fetch_keys(
+                child,
+                |key| usable.iter().any(|ds| matches_key(ds, key)),
+                now,
+                fetch,
+            )?;
+
+            return Ok((ZoneTrust::Secure(child_keys), ttl.min(min_ttl(&ds.records))));
+        }
+
+        // An alias cannot be a zone cut
+        if let Some(cname) = find_rrset(&response.answers, child, QueryType::CNAME) {
+            verify_rrset(&cname.records, &cname.signatures, zone, keys, now)?;
+            return Ok((ZoneTrust::NoCut, min_ttl(&cname.records)));
+        }
+
+        let mut records = Vec::new();
+        for rrset in group_rrsets(&response.authorities) {
+            if matches!(rrset.records[0].qtype(), QueryType::NSEC | QueryType::NSEC3) {
+                verify_rrset(&rrset.records, &rrset.signatures, zone, keys, now)?;
+                records.extend(rrset.records);
+            }
+        }
+
+        if records.is_empty() {
+            return Err(format!("{:?} did not prove that {} has no DS", zone, child).into());
+        }
+
+        let proof = if response.header.result_code == ResultCode::NXDOMAIN {
+            prove_nxdomain(child, zone, &records)?
+        } else {
+            prove_nodata(child, QueryType::DS, zone, &records)?
+        };
+
+        let trust = match proof {
+            Proof::Denied => ZoneTrust::NoCut,
+            _ => ZoneTrust::Insecure,
+        };
+
+        Ok((trust, min_ttl(&records)))
+    }
+
+    // Fetches the DNSKEY RRset of zone, which has to be signed by one of the
+    // keys that the DS records or trust anchors vouch for
+    fn fetch_keys(
+        &self,
+        zone: &str,
+        trusted: impl Fn(&DnsRecord) -> bool,
+        now: u32,
+        fetch: &Fetch,
+    ) -> Result<(Vec<DnsRecord>, u32)> {
+        let response = fetch(zone, QueryType::DNSKEY)?;
+
+        let rrset = find_rrset(&response.answers, zone, QueryType::DNSKEY)
+            .ok_or_else(|| format!("no DNSKEY records for {:?}", zone))?;
+
+        let trusted_keys: Vec<DnsRecord> = rrset
+            .records
+            .iter()
+            .filter(|key| trusted(key))
+            .cloned()
+            .collect();
+
+        if trusted_keys.is_empty() {
+            return Err(format!("no DNSKEY of {:?} matches its DS records", zone).into());
+        }
+
+        verify_rrset(&rrset.records, &rrset.signatures, zone, &trusted_keys, now)?;
+
+        let ttl = min_ttl(&rrset.records);
+        Ok((rrset.records, ttl))
+    }
+
+    fn cached(&self, name: &str) -> Option<ZoneTrust> {
+        let zones = self.zones.read().unwrap();
+        let entry = zones.get(name)?;
+
+        if entry.expires_at <= Instant::now() {
+            return None;
+        }
+
+        Some(entry.trust.clone())
+    }
+
+    fn remember(&self, name: &str, trust: ZoneTrust, ttl: u32) {
+        let now = Instant::now();
+        let mut zones = self.zones.write().unwrap();
+
+        if zones.len() >= MAX_ZONES {
+            zones.retain(|_, entry| entry.expires_at > now);
+        }
+
+        zones.insert(
+            name.to_string(),
+            TrustEntry {
+                trust,
+                expires_at: now + Duration::from_secs(ttl as u64),
+            },
+        );
+    }
+}
+
+// Checks that one of the signatures over the RRset was made by one of the keys
+// of zone and is currently valid. Returns the closest encloser if the RRset
+// was synthesized from a wildcard
+fn verify_rrset(
+    records: &[DnsRecord],
+    signatures: &[DnsRecord],
+    zone: &str,
+    keys: &[DnsRecord],
+    now: u32,
+) -> Result<Option<String>> {
+    let owner = records[0].domain();
+    let qtype = records[0].qtype();
+    let mut reason = format!(
+        "no signature by {:?} over {} {} verifies",
+        zone, owner, qtype
+    );
+
+    for signature in signatures {
+        let (algorithm, labels, expiration, inception, tag, sig) = match signature {
+            DnsRecord::RRSIG {
+                type_covered,
+                algorithm,
+                labels,
+                expiration,
+                inception,
+                key_tag,
+                signer_name,
+                signature,
+                ..
+            } if *type_covered == qtype
+                && signer_name.eq_ignore_ascii_case(zone)
+                && is_supported_algorithm(*algorithm)
+                && (*labels as usize) <= label_count(owner) =>
+            {
+                (
+                    *algorithm,
+                    *labels as usize,
+                    *expiration,
+                    *inception,
+                    *key_tag,
+                    signature,
+                )
+            }
+            _ => continue,
+        };
+
+        // Serial number arithmetic, as the times wrap around (RFC 4034 3.1.5)
+        if (now.wrapping_sub(inception) as i32) < 0 || (expiration.wrapping_sub(now) as i32) < 0 {
+            reason = format!(
+                "signature over {} {} is expired or not yet valid",
+                owner, qtype
+            );
+            continue;
+        }
+
+        let data = signature.signed_data(records)?;
+        let verified = keys.iter().any(|key| match key {
+            DnsRecord::DNSKEY {
+                algorithm: key_algorithm,
+                public_key,
+                ..
+            } => {
+                *key_algorithm == algorithm
+                    && is_zone_key(key)
+                    && key_tag(key) == Some(tag)
+                    && verify(algorithm, public_key, &data, sig)
+            }
+            _ => false,
+        });
+
+        if verified {
+            // Fewer labels than the owner name has means the RRset was
+            // expanded from a wildcard (RFC 4035 5.3.2)
+            let expanded = labels < label_count(owner) && !owner.starts_with("*.");
+            return Ok(expanded.then(|| last_labels(owner, labels).to_string()));
+        }
+    }
+
+    Err(reason.into())
+}
+
+// Groups records into RRsets along with the RRSIGs covering them
+fn group_rrsets(records: &[DnsRecord]) -> Vec<RRset> {
+    let mut rrsets: Vec<RRset> = Vec::new();
+
+    for record in records {
+        if matches!(record, DnsRecord::RRSIG { .. } | DnsRecord::OPT { .. }) {
+            continue;
+        }
+
+        match rrsets.iter_mut().find(|rrset| {
+            rrset.records[0].qtype() == record.qtype()
+                && rrset.records[0]
+                    .domain()
+                    .eq_ignore_ascii_case(record.domain())
+        }) {
+            Some(rrset) if !rrset.records.contains(record) => rrset.records.push(record.clone()),
+            Some(_) => {}
+            None => rrsets.push(RRset {
+                records: vec![record.clone()],
+                signatures: Vec::new(),
+            }),
+        }
+    }
+
+    for rrset in rrsets.iter_mut() {
+        let owner = rrset.records[0].domain();
+        let qtype = rrset.records[0].qtype();
+
+        rrset.signatures = records
+            .iter()
+            .filter(|record| {
+                matches!(record, DnsRecord::RRSIG { type_covered, .. } if *type_covered == qtype)
+                    && record.domain().eq_ignore_ascii_case(owner)
+            })
+            .cloned()
+            .collect();
+    }
+
+    rrsets
+}
+
+fn find_rrset(records: &[DnsRecord], name: &str, qtype: QueryType) -> Option<RRset> {
+    group_rrsets(records).into_iter().find(|rrset| {
+        rrset.records[0].qtype() == qtype && rrset.records[0].domain().eq_ignore_ascii_case(name)
+    })
+}
+
+fn min_ttl(records: &[DnsRecord]) -> u32 {
+    records.iter().map(|record| record.ttl()).min().unwrap_or(0)
+}
+
+// Signature times are seconds since the epoch modulo 2^32 (RFC 4034 3.1.5)
+fn unix_time() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs() as u32)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::dnssec::signed_zone::{now, response, Algorithm, Denial, Tree, Zone};
+
+    fn tree(com_denial: Denial) -> Tree {
+        tree_with(Algorithm::EcdsaP256, com_denial, Algorithm::Ed25519)
+    }
+
+    fn tree_with(com: Algorithm, com_denial: Denial, example_com: Algorithm) -> Tree {
+        use QueryType::*;
+
+        Tree::new(vec![
+            Zone::new(
+                "",
+                Algorithm::EcdsaP256,
+                Denial::Nsec,
+                &[("", &[NS, SOA, DNSKEY]), ("com", &[NS, DS])],
+            ),
+            Zone::new(
+                "com",
+                com,
+                com_denial,
+                &[
+                    ("com", &[NS, SOA, DNSKEY, NSEC3PARAM]),
+                    ("example.com", &[NS, DS]),
+                    ("insecure.com", &[NS]),
+                ],
+            ),
+            Zone::new(
+                "example.com",
+                example_com,
+                Denial::Nsec,
+                &[
+                    ("example.com", &[NS, SOA, DNSKEY]),
+                    ("*.example.com", &[A]),
+                    ("lab.example.com", &[NS]),
+                    ("www.example.com", &[A]),
+                ],
+            ),
+        ])
+    }
+
+    fn nsec3(iterations: u16, opt_out: bool) -> Denial {
+        Denial::Nsec3 {
+            iterations,
+            opt_out,
+        }
+    }
+
+    fn a(name: &str) -> DnsRecord {
+        DnsRecord::A {
+            domain: name.to_string(),
+            ip_v4_addr: Ipv4Addr::new(192, 0, 2, 1),
+            ttl: 3600,
+        }
+    }
+
+    fn answer(records: Vec<DnsRecord>) -> DnsPacket {
+        response(ResultCode::NOERROR, records, Vec::new())
+    }
+
+    fn validate(
+        tree: &Tree,
+        qname: &str,
+        qtype: QueryType,
+        response: &DnsPacket,
+    ) -> Result<Security> {
+        let validator = Validator::new(tree.anchors(), Vec::new(), false);
+        validator.validate(qname, qtype, response, &|name, qtype| {
+            tree.fetch(name, qtype)
+        })
+    }
+
+    #[test]
+    fn accepts_a_signed_answer() {
+        let tree = tree(Denial::Nsec);
+        let records = tree.zone("example.com").sign(vec![a("www.example.com")]);
+
+        let security = validate(&tree, "www.example.com", QueryType::A, &answer(records));
+
+        assert_eq!(security.unwrap(), Security::Secure);
+    }
+
+    #[test]
+    fn rejects_a_bogus_signature() {
+        let tree = tree(Denial::Nsec);
+        let mut records = tree.zone("example.com").sign(vec![a("www.example.com")]);
+        if let Some(DnsRecord::RRSIG { signature, .. }) = records.last_mut() {
+            signature[0] ^= 0xff;
+        }
+
+        let security = validate(&tree, "www.example.com", QueryType::A, &answer(records));
+
+        assert!(security.is_err());
+    }
+
+    #[test]
+    fn supports_every_algorithm() {
+        use Algorithm::*;
+
+        for algorithm in [RsaSha256, EcdsaP256, EcdsaP384, Ed25519] {
+            let tree = tree_with(algorithm, Denial::Nsec, algorithm);
+            let zone = tree.zone("example.com");
+
+            let records = zone.sign(vec![a("www.example.com")]);
+            let security = validate(&tree, "www.example.com", QueryType::A, &answer(records));
+            assert_eq!(security.unwrap(), Security::Secure);
+
+            let nxdomain = response(ResultCode::NXDOMAIN, Vec::new(), zone.denial());
+            let security = validate(&tree, "host.www.example.com", QueryType::A, &nxdomain);
+            assert_eq!(security.unwrap(), Security::Secure);
+
+            let mut records = zone.sign(vec![a("www.example.com")]);
+            if let Some(DnsRecord::RRSIG { signature, .. }) = records.last_mut() {
+                signature[0] ^= 0xff;
+            }
+            let security = validate(&tree, "www.example.com", QueryType::A, &answer(records));
+            assert!(security.is_err());
+        }
+    }
+
+    #[test]
+    fn rejects_expired_and_not_yet_valid_signatures() {
+        let tree = tree(Denial::Nsec);
+        let zone = tree.zone("example.com");
+
+        for (inception, expiration) in [(now() - 7200, now() - 3600), (now() + 3600, now() + 7200)]
+        {
+            let records =
+                zone.sign_with(&zone.zsk, vec![a("www.example.com")], inception, expiration);
+
+            let security = validate(&tree, "www.example.com", QueryType::A, &answer(records));
+
+            let error = security.unwrap_err().to_string();
+            assert!(error.contains("expired or not yet valid"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn requires_keys_vouched_for_by_the_ds() {
+        let records = |tree: &Tree| tree.zone("example.com").sign(vec![a("www.example.com")]);
+
+        // The DNSKEY RRset has to be signed by the KSK the DS points at,
+        // the ZSK alone does not do
+        let mut tree = tree(Denial::Nsec);
+        let zone = tree.zone("example.com");
+        let keys = vec![zone.ksk.record.clone(), zone.zsk.record.clone()];
+        let keys = zone.sign_with(&zone.zsk, keys, now() - 3600, now() + 3600);
+        tree.replace("example.com", QueryType::DNSKEY, answer(keys));
+        assert!(validate(
+            &tree,
+            "www.example.com",
+            QueryType::A,
+            &answer(records(&tree))
+        )
+        .is_err());
+
+        // A DS for some other key leaves the zone without a chain of trust
+        let mut tree = self::tree(Denial::Nsec);
+        let other = Zone::new("example.com", Algorithm::Ed25519, Denial::Nsec, &[]);
+        let ds = tree.zone("com").sign(vec![other.ds()]);
+        tree.replace("example.com", QueryType::DS, answer(ds));
+        let error = validate(
+            &tree,
+            "www.example.com",
+            QueryType::A,
+            &answer(records(&tree)),
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(error.contains("matches its DS records"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_signatures_by_the_wrong_signer() {
+        let tree = tree(Denial::Nsec);
+
+        // Neither a name that is not a zone nor one outside the tree above
+        // the owner can sign for it
+        for signer in ["www.example.com", "example.net"] {
+            let zone = Zone::new(signer, Algorithm::Ed25519, Denial::Nsec, &[]);
+            let records = zone.sign(vec![a("www.example.com")]);
+
+            let security = validate(&tree, "www.example.com", QueryType::A, &answer(records));
+
+            assert!(security.is_err(), "{}", signer);
+        }
+    }
+
+    #[test]
+    fn proves_nxdomain_and_nodata_with_nsec() {
+        let tree = tree(Denial::Nsec);
+        let denial = tree.zone("example.com").denial();
+
+        let nxdomain = response(ResultCode::NXDOMAIN, Vec::new(), denial.clone());
+        let nodata = response(ResultCode::NOERROR, Vec::new(), denial);
+
+        let security = validate(&tree, "host.www.example.com", QueryType::A, &nxdomain);
+        assert_eq!(security.unwrap(), Security::Secure);
+        let security = validate(&tree, "www.example.com", QueryType::AAAA, &nodata);
+        assert_eq!(security.unwrap(), Security::Secure);
+
+        // The wildcard would have matched the name, and the bitmap of the
+        // name lists the type
+        assert!(validate(&tree, "host.example.com", QueryType::A, &nxdomain).is_err());
+        assert!(validate(&tree, "www.example.com", QueryType::A, &nodata).is_err());
+    }
+
+    #[test]
+    fn proves_nxdomain_and_nodata_with_nsec3() {
+        let tree = tree(nsec3(1, false));
+        let denial = tree.zone("com").denial();
+
+        let nxdomain = response(ResultCode::NXDOMAIN, Vec::new(), denial.clone());
+        let nodata = response(ResultCode::NOERROR, Vec::new(), denial);
+
+        let security = validate(&tree, "nope.com", QueryType::A, &nxdomain);
+        assert_eq!(security.unwrap(), Security::Secure);
+        let security = validate(&tree, "com", QueryType::AAAA, &nodata);
+        assert_eq!(security.unwrap(), Security::Secure);
+
+        assert!(validate(&tree, "insecure.com", QueryType::A, &nxdomain).is_err());
+        assert!(validate(&tree, "com", QueryType::SOA, &nodata).is_err());
+    }
+
+    #[test]
+    fn treats_opt_out_spans_as_insecure() {
+        let tree = tree(nsec3(1, true));
+        let nxdomain = response(ResultCode::NXDOMAIN, Vec::new(), tree.zone("com").denial());
+
+        let security = validate(&tree, "nope.com", QueryType::A, &nxdomain);
+
+        assert_eq!(security.unwrap(), Security::Insecure);
+    }
+
+    #[test]
+    fn treats_costly_nsec3_iterations_as_insecure() {
+        let tree = tree(nsec3(151, false));
+        let nxdomain = response(ResultCode::NXDOMAIN, Vec::new(), tree.zone("com").denial());
+
+        let security = validate(&tree, "nope.com", QueryType::A, &nxdomain);
+
+        assert_eq!(security.unwrap(), Security::Insecure);
+    }
+
+    #[test]
+    fn requires_a_proof_for_wildcard_expansions() {
+        let tree = tree(Denial::Nsec);
+        let zone = tree.zone("example.com");
+
+        let mut records = zone.sign(vec![a("*.example.com")]);
+        for record in records.iter_mut() {
+            record.set_domain("host.example.com");
+        }
+
+        let mut expanded = answer(records);
+        let security = validate(&tree, "host.example.com", QueryType::A, &expanded);
+        assert!(security.is_err());
+
+        expanded.authorities = zone.denial();
+        let security = validate(&tree, "host.example.com", QueryType::A, &expanded);
+        assert_eq!(security.unwrap(), Security::Secure);
+    }
+
+    #[test]
+    fn accepts_unsigned_data_only_below_insecure_delegations() {
+        // Unsigned delegations proven by NSEC and by NSEC3
+        let tree = tree(nsec3(0, false));
+        for name in ["host.lab.example.com", "www.insecure.com"] {
+            let security = validate(&tree, name, QueryType::A, &answer(vec![a(name)]));
+            assert_eq!(security.unwrap(), Security::Insecure, "{}", name);
+        }
+
+        let unsigned = answer(vec![a("www.example.com")]);
+        assert!(validate(&tree, "www.example.com", QueryType::A, &unsigned).is_err());
+    }
+
+    #[test]
+    fn accepts_unsigned_data_of_forwarded_zones() {
+        let tree = tree(Denial::Nsec);
+        let unsigned = answer(vec![a("dc.corp.internal")]);
+        let fetch = |name: &str, qtype| tree.fetch(name, qtype);
+
+        // The root proves that internal does not exist
+        let validator = Validator::new(tree.anchors(), Vec::new(), false);
+        let security = validator.validate("dc.corp.internal", QueryType::A, &unsigned, &fetch);
+        assert!(security.is_err());
+
+        let insecure = vec!["corp.internal".to_string()];
+        let validator = Validator::new(tree.anchors(), insecure, false);
+        let security = validator.validate("dc.corp.internal", QueryType::A, &unsigned, &fetch);
+        assert_eq!(security.unwrap(), Security::Insecure);
+
+        // Names outside of the forwarded zone are still checked
+        let unsigned = answer(vec![a("www.example.com")]);
+        assert!(validator
+            .validate("www.example.com", QueryType::A, &unsigned, &fetch)
+            .is_err());
+    }
+}
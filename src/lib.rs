@@ -0,0 +1,15 @@
+#![allow(clippy::upper_case_acronyms)]
+
+pub mod cache;
+pub mod config;
+pub mod context;
+pub mod dnssec;
+pub mod models;
+pub mod resolver;
+pub mod server;
+pub mod types;
+pub mod utils;
+
+pub use resolver::api::{Resolver, ResolverBuilder};
+pub use resolver::stub::StubResolver;
+pub use utils::byte_packet_buffer::BytePacketBuffer;
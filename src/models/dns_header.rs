@@ -2,7 +2,7 @@
 // +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
 // |                      ID                       |
 // +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
-// |QR|   Opcode  |AA|TC|RD|RA|   Z    |   RCODE   |
+// |QR|   Opcode  |AA|TC|RD|RA| Z|AD|CD|   RCODE   |
 // +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
 // |                    QDCOUNT                    |
 // +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
@@ -12,6 +12,8 @@
 // +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
 // |                    ARCOUNT                    |
 // +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
+//
+// AD and CD take two of the former Z bits for DNSSEC (RFC 4035 3.2)
 
 use super::result_code::ResultCode;
 use crate::types::Result;
@@ -23,7 +25,9 @@ const AA_MASK: u16 = 0b00000100_00000000;
 const TC_MASK: u16 = 0b00000010_00000000;
 const RD_MASK: u16 = 0b00000001_00000000;
 const RA_MASK: u16 = 0b00000000_10000000;
-const Z_MASK: u16 = 0b00000000_01110000;
+const Z_MASK: u16 = 0b00000000_01000000;
+const AD_MASK: u16 = 0b00000000_00100000;
+const CD_MASK: u16 = 0b00000000_00010000;
 const RCODE_MASK: u16 = 0b00000000_00001111;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
@@ -36,7 +40,9 @@ pub struct DnsHeader {
     pub truncated_message: bool,
     pub recursion_desired: bool,
     pub recursion_available: bool,
-    pub z: u8,
+    pub z: bool,
+    pub authed_data: bool,
+    pub checking_disabled: bool,
     pub result_code: ResultCode,
 
     pub questions_count: u16,
@@ -56,7 +62,9 @@ impl DnsHeader {
             truncated_message: false,
             recursion_desired: false,
             recursion_available: false,
-            z: 0,
+            z: false,
+            authed_data: false,
+            checking_disabled: false,
             result_code: ResultCode::NOERROR,
 
             questions_count: 0,
@@ -77,7 +85,9 @@ impl DnsHeader {
         self.truncated_message = flags & TC_MASK > 0;
         self.recursion_desired = flags & RD_MASK > 0;
         self.recursion_available = flags & RA_MASK > 0;
-        self.z = ((flags & Z_MASK) >> 4) as u8;
+        self.z = flags & Z_MASK > 0;
+        self.authed_data = flags & AD_MASK > 0;
+        self.checking_disabled = flags & CD_MASK > 0;
         self.result_code = ResultCode::from_num((flags & RCODE_MASK) as u8);
 
         self.questions_count = bufer.read_u16()?;
@@ -88,14 +98,16 @@ impl DnsHeader {
         Ok(())
     }
 
-    pub fn write(&mut self, bufer: &mut BytePacketBuffer) -> Result<()> {
+    pub fn write(&self, bufer: &mut BytePacketBuffer) -> Result<()> {
         let flags = ((self.is_response as u16) << 15)
             | ((self.opcode as u16) << 11)
             | ((self.authoritative_answer as u16) << 10)
             | ((self.truncated_message as u16) << 9)
             | ((self.recursion_desired as u16) << 8)
-            | ((self.recursion_desired as u16) << 7)
-            | ((self.z as u16) << 4)
+            | ((self.recursion_available as u16) << 7)
+            | ((self.z as u16) << 6)
+            | ((self.authed_data as u16) << 5)
+            | ((self.checking_disabled as u16) << 4)
             | (self.result_code as u16);
 
         bufer.write_u16(self.id)?;
@@ -125,3 +137,9 @@ impl DnsHeader {
         self.additional_records_count
     }
 }
+
+impl Default for DnsHeader {
+    fn default() -> DnsHeader {
+        DnsHeader::new()
+    }
+}
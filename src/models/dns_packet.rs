@@ -1,14 +1,14 @@
 use std::net::Ipv4Addr;
 
-use rand::seq::SliceRandom;
-use rand::thread_rng;
-
 use super::{
     dns_header::DnsHeader, dns_question::DnsQuestion, dns_record::DnsRecord,
     query_class::QueryClass, query_type::QueryType,
 };
 use crate::types::Result;
-use crate::utils::byte_packet_buffer::BytePacketBuffer;
+use crate::utils::byte_packet_buffer::{BytePacketBuffer, MAX_UDP_SIZE};
+use crate::utils::domain_name::is_subdomain;
+
+const EDNS_DO_FLAG: u16 = 0x8000;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct DnsPacket {
@@ -60,7 +60,7 @@ impl DnsPacket {
         Ok(dns_packet)
     }
 
-    pub fn to_buffer(&mut self, buffer: &mut BytePacketBuffer) -> Result<()> {
+    pub fn to_buffer(&self, buffer: &mut BytePacketBuffer) -> Result<()> {
         self.header.write(buffer)?;
         for record in &self.questions {
             record.write(buffer)?;
@@ -78,47 +78,173 @@ impl DnsPacket {
         Ok(())
     }
 
-    pub fn get_random_a(&self) -> Option<Ipv4Addr> {
-        let a_records: Vec<Ipv4Addr> = self
-            .answers
+    pub fn edns(&self) -> Option<&DnsRecord> {
+        self.additionals
+            .iter()
+            .find(|record| matches!(record, DnsRecord::OPT { .. }))
+    }
+
+    // Adds an OPT record advertising the UDP payload size we accept, with the
+    // DO bit asking for DNSSEC records (RFC 6891 6, RFC 3225 3)
+    pub fn set_edns(&mut self, packet_size: usize, dnssec_ok: bool) {
+        self.additionals
+            .retain(|record| !matches!(record, DnsRecord::OPT { .. }));
+        self.additionals.push(DnsRecord::OPT {
+            domain: String::new(),
+            packet_size: packet_size as u16,
+            extended_rcode: 0,
+            version: 0,
+            flags: if dnssec_ok { EDNS_DO_FLAG } else { 0 },
+            data: Vec::new(),
+        });
+        self.header.additional_records_count = self.additionals.len() as u16;
+    }
+
+    pub fn dnssec_ok(&self) -> bool {
+        matches!(self.edns(), Some(DnsRecord::OPT { flags, .. }) if flags & EDNS_DO_FLAG != 0)
+    }
+
+    // Largest UDP reply the sender accepts, 512 bytes without EDNS (RFC 6891 6.2.5)
+    pub fn udp_payload_size(&self) -> usize {
+        match self.edns() {
+            Some(DnsRecord::OPT { packet_size, .. }) => (*packet_size as usize).max(MAX_UDP_SIZE),
+            _ => MAX_UDP_SIZE,
+        }
+    }
+
+    pub fn get_a_addrs(&self) -> Vec<Ipv4Addr> {
+        self.answers
             .iter()
             .filter_map(|record| match record {
                 DnsRecord::A { ip_v4_addr, .. } => Some(*ip_v4_addr),
                 _ => None,
             })
-            .collect();
+            .collect()
+    }
+
+    // Collects the CNAME records in the answer section that form a chain
+    // starting at qname, in order
+    pub fn get_cname_chain<'a>(&'a self, qname: &str) -> Vec<&'a DnsRecord> {
+        let mut chain = Vec::new();
+        let mut name = qname;
+
+        while chain.len() < self.answers.len() {
+            let next = self.answers.iter().find(|record| match record {
+                DnsRecord::CNAME { domain, .. } => domain.eq_ignore_ascii_case(name),
+                _ => false,
+            });
 
-        let mut rng = thread_rng();
-        a_records.choose(&mut rng).copied()
+            match next {
+                Some(record @ DnsRecord::CNAME { host, .. }) => {
+                    chain.push(record);
+                    name = host;
+                }
+                _ => break,
+            }
+        }
+
+        chain
+    }
+
+    // Returns the name at the end of the CNAME chain starting at qname
+    pub fn resolve_cname_chain<'a>(&'a self, qname: &'a str) -> &'a str {
+        match self.get_cname_chain(qname).last() {
+            Some(DnsRecord::CNAME { host, .. }) => host,
+            _ => qname,
+        }
     }
 
-    fn get_ns<'a>(&'a self, qname: &'a str) -> impl Iterator<Item = (&'a str, &'a str)> {
+    // Puts back the original casing of a name sent with randomized case,
+    // wherever the server echoed it
+    pub fn restore_name_case(&mut self, sent_name: &str, name: &str) {
+        for question in self.questions.iter_mut() {
+            if question.name == sent_name {
+                question.name = name.to_string();
+            }
+        }
+
+        for record in self
+            .answers
+            .iter_mut()
+            .chain(self.authorities.iter_mut())
+            .chain(self.additionals.iter_mut())
+        {
+            if record.domain() == sent_name {
+                record.set_domain(name);
+            }
+        }
+    }
+
+    // Drops every record that the server authoritative for zone has no
+    // business answering with, so that it never reaches the cache
+    pub fn scrub_out_of_bailiwick(&mut self, zone: &str) {
+        let in_bailiwick = |record: &DnsRecord| is_subdomain(record.domain(), zone);
+
+        self.answers.retain(in_bailiwick);
+        self.authorities.retain(in_bailiwick);
+        self.additionals.retain(in_bailiwick);
+    }
+
+    // Returns the deepest delegation in the authority section that covers
+    // qname and lies strictly below the zone of the server that sent it
+    pub fn get_delegation<'a>(&'a self, qname: &str, zone: &str) -> Option<&'a str> {
         self.authorities
             .iter()
             .filter_map(|record| match record {
-                DnsRecord::NS { domain, host, .. } => Some((domain.as_str(), host.as_str())),
+                DnsRecord::NS { domain, .. } => Some(domain.as_str()),
+                _ => None,
+            })
+            .filter(|domain| {
+                is_subdomain(qname, domain)
+                    && is_subdomain(domain, zone)
+                    && !domain.eq_ignore_ascii_case(zone)
+            })
+            .max_by_key(|domain| domain.len())
+    }
+
+    fn get_ns<'a>(&'a self, zone: &'a str) -> impl Iterator<Item = &'a str> {
+        self.authorities
+            .iter()
+            .filter_map(move |record| match record {
+                DnsRecord::NS { domain, host, .. } if domain.eq_ignore_ascii_case(zone) => {
+                    Some(host.as_str())
+                }
                 _ => None,
             })
-            .filter(move |(domain, _)| qname.ends_with(*domain))
     }
 
-    pub fn get_resolved_ns(&self, qname: &str) -> Option<Ipv4Addr> {
-        self.get_ns(qname)
-            .flat_map(|(_, host)| {
+    // Addresses of the name servers for zone found as glue in the additional
+    // section, which is expected to have been scrubbed already
+    pub fn get_resolved_ns(&self, zone: &str) -> Vec<Ipv4Addr> {
+        self.get_ns(zone)
+            .flat_map(|host| {
                 self.additionals
                     .iter()
                     .filter_map(move |record| match record {
                         DnsRecord::A {
                             domain, ip_v4_addr, ..
-                        } if domain == host => Some(ip_v4_addr),
+                        } if domain.eq_ignore_ascii_case(host) => Some(ip_v4_addr),
                         _ => None,
                     })
             })
-            .map(|addr| *addr)
-            .next()
+            .copied()
+            .collect()
     }
 
-    pub fn get_unresolved_ns<'a>(&'a self, qname: &'a str) -> Option<&'a str> {
-        self.get_ns(qname).map(|(_, host)| host).next()
+    // Name servers for zone without glue, whose addresses have to be looked up
+    pub fn get_unresolved_ns<'a>(&'a self, zone: &'a str) -> Vec<&'a str> {
+        self.get_ns(zone)
+            .filter(|host| {
+                !self.additionals.iter().any(|record| {
+                    matches!(record, DnsRecord::A { domain, .. } if domain.eq_ignore_ascii_case(host))
+                })
+            })
+            .collect()
+    }
+}
+
+impl Default for DnsPacket {
+    fn default() -> DnsPacket {
+        DnsPacket::new()
     }
 }
@@ -13,9 +13,9 @@ pub struct DnsQuestion {
 impl DnsQuestion {
     pub fn new(name: String, qtype: QueryType, qclass: QueryClass) -> DnsQuestion {
         DnsQuestion {
-            name: name,
-            qtype: qtype,
-            qclass: qclass,
+            name,
+            qtype,
+            qclass,
         }
     }
 
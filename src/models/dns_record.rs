@@ -1,4 +1,10 @@
-use crate::{types::Result, utils::byte_packet_buffer::BytePacketBuffer};
+use crate::{
+    types::Result,
+    utils::byte_packet_buffer::{BytePacketBuffer, MAX_TCP_SIZE},
+    utils::domain_name::{label_count, last_labels},
+    utils::encoding::{base32hex_encode, base64_encode, hex_encode},
+};
+use std::fmt;
 use std::net::{Ipv4Addr, Ipv6Addr};
 
 use super::{query_class::QueryClass, query_type::QueryType};
@@ -20,6 +26,22 @@ pub enum DnsRecord {
         host: String,
         ttl: u32,
     },
+    SOA {
+        domain: String,
+        mname: String,
+        rname: String,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,
+        ttl: u32,
+    },
+    PTR {
+        domain: String,
+        host: String,
+        ttl: u32,
+    },
     MX {
         domain: String,
         priority: u16,
@@ -31,15 +53,163 @@ pub enum DnsRecord {
         ip_v6_addr: Ipv6Addr,
         ttl: u32,
     },
+    // EDNS pseudo-record (RFC 6891 6.1.2), the class field carries the UDP
+    // payload size and the TTL field the extended RCODE, version and flags
+    OPT {
+        domain: String,
+        packet_size: u16,
+        extended_rcode: u8,
+        version: u8,
+        flags: u16,
+        data: Vec<u8>,
+    },
+    DS {
+        domain: String,
+        key_tag: u16,
+        algorithm: u8,
+        digest_type: u8,
+        digest: Vec<u8>,
+        ttl: u32,
+    },
+    RRSIG {
+        domain: String,
+        type_covered: QueryType,
+        algorithm: u8,
+        labels: u8,
+        original_ttl: u32,
+        expiration: u32,
+        inception: u32,
+        key_tag: u16,
+        signer_name: String,
+        signature: Vec<u8>,
+        ttl: u32,
+    },
+    NSEC {
+        domain: String,
+        next_domain: String,
+        types: Vec<QueryType>,
+        ttl: u32,
+    },
+    DNSKEY {
+        domain: String,
+        flags: u16,
+        protocol: u8,
+        algorithm: u8,
+        public_key: Vec<u8>,
+        ttl: u32,
+    },
+    NSEC3 {
+        domain: String,
+        hash_algorithm: u8,
+        flags: u8,
+        iterations: u16,
+        salt: Vec<u8>,
+        next_hashed_owner: Vec<u8>,
+        types: Vec<QueryType>,
+        ttl: u32,
+    },
+    NSEC3PARAM {
+        domain: String,
+        hash_algorithm: u8,
+        flags: u8,
+        iterations: u16,
+        salt: Vec<u8>,
+        ttl: u32,
+    },
+    // The RDATA of types we do not know is passed along as is (RFC 3597)
     UNKNOWN {
         domain: String,
         qtype: QueryType,
         qclass: QueryClass,
         ttl: u32,
-        rdlength: u16,
+        data: Vec<u8>,
     },
 }
 
+// Reads whatever is left of the RDATA ending at end
+fn read_rest(buffer: &mut BytePacketBuffer, end: usize) -> Result<Vec<u8>> {
+    let start = buffer.pos();
+    if end < start {
+        return Err("RDATA shorter than its fields".into());
+    }
+
+    let data = buffer.get_range(start, end - start)?.to_vec();
+    buffer.step(end - start)?;
+
+    Ok(data)
+}
+
+// Reads a field prefixed with its length, which has to end within the RDATA
+// ending at end
+fn read_sized(buffer: &mut BytePacketBuffer, end: usize) -> Result<Vec<u8>> {
+    let len = buffer.read_u8()? as usize;
+    if buffer.pos() + len > end {
+        return Err("Field longer than its RDATA".into());
+    }
+
+    read_rest(buffer, buffer.pos() + len)
+}
+
+// The types present at an NSEC or NSEC3 owner are encoded as bitmaps of up to
+// 32 bytes for each window of 256 types that is not empty (RFC 4034 4.1.2)
+fn read_type_bitmap(data: &[u8]) -> Result<Vec<QueryType>> {
+    let mut types = Vec::new();
+    let mut rest = data;
+
+    while !rest.is_empty() {
+        let (window, len) = match rest {
+            [window, len, ..] if (1..=32).contains(len) => (*window as u16, *len as usize),
+            _ => return Err("Malformed type bitmap".into()),
+        };
+        let bitmap = rest.get(2..2 + len).ok_or("Malformed type bitmap")?;
+
+        for (index, byte) in bitmap.iter().enumerate() {
+            for bit in 0..8 {
+                if byte & (0x80 >> bit) != 0 {
+                    types.push(QueryType::from_num(window << 8 | (index * 8 + bit) as u16));
+                }
+            }
+        }
+
+        rest = &rest[2 + len..];
+    }
+
+    Ok(types)
+}
+
+fn write_type_bitmap(buffer: &mut BytePacketBuffer, types: &[QueryType]) -> Result<()> {
+    let mut types: Vec<u16> = types.iter().map(|qtype| qtype.to_num()).collect();
+    types.sort_unstable();
+    types.dedup();
+
+    for window in types.chunk_by(|a, b| a >> 8 == b >> 8) {
+        let mut bitmap = [0u8; 32];
+        for qtype in window {
+            let low = (qtype & 0xff) as usize;
+            bitmap[low / 8] |= 0x80 >> (low % 8);
+        }
+
+        let len = window
+            .last()
+            .map_or(0, |qtype| (qtype & 0xff) as usize / 8 + 1);
+        buffer.write_u8((window[0] >> 8) as u8)?;
+        buffer.write_u8(len as u8)?;
+        for byte in &bitmap[..len] {
+            buffer.write_u8(*byte)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn write_bytes(buffer: &mut BytePacketBuffer, data: &[u8]) -> Result<()> {
+    for byte in data {
+        buffer.write_u8(*byte)?;
+    }
+
+    Ok(())
+}
+
 impl DnsRecord {
     pub fn read(buffer: &mut BytePacketBuffer) -> Result<DnsRecord> {
         let domain = buffer.read_name()?;
@@ -47,36 +217,53 @@ impl DnsRecord {
         let qclass = QueryClass::from_num(buffer.read_u16()?);
         let ttl = buffer.read_u32()?;
         let rdlength = buffer.read_u16()?;
+        let end = buffer.pos() + rdlength as usize;
 
         match qtype {
             QueryType::A => Ok(DnsRecord::A {
-                domain: (domain),
+                domain,
                 ip_v4_addr: (Ipv4Addr::new(
                     buffer.read_u8()?,
                     buffer.read_u8()?,
                     buffer.read_u8()?,
                     buffer.read_u8()?,
                 )),
-                ttl: (ttl),
+                ttl,
             }),
             QueryType::NS => Ok(DnsRecord::NS {
-                domain: domain,
+                domain,
                 host: buffer.read_name()?,
-                ttl: ttl,
+                ttl,
             }),
             QueryType::CNAME => Ok(DnsRecord::CNAME {
-                domain: domain,
+                domain,
                 host: buffer.read_name()?,
-                ttl: ttl,
+                ttl,
+            }),
+            QueryType::SOA => Ok(DnsRecord::SOA {
+                domain,
+                mname: buffer.read_name()?,
+                rname: buffer.read_name()?,
+                serial: buffer.read_u32()?,
+                refresh: buffer.read_u32()?,
+                retry: buffer.read_u32()?,
+                expire: buffer.read_u32()?,
+                minimum: buffer.read_u32()?,
+                ttl,
+            }),
+            QueryType::PTR => Ok(DnsRecord::PTR {
+                domain,
+                host: buffer.read_name()?,
+                ttl,
             }),
             QueryType::MX => Ok(DnsRecord::MX {
-                domain: domain,
+                domain,
                 priority: buffer.read_u16()?,
                 host: buffer.read_name()?,
-                ttl: ttl,
+                ttl,
             }),
             QueryType::AAAA => Ok(DnsRecord::AAAA {
-                domain: domain,
+                domain,
                 ip_v6_addr: Ipv6Addr::new(
                     buffer.read_u16()?,
                     buffer.read_u16()?,
@@ -87,117 +274,777 @@ impl DnsRecord {
                     buffer.read_u16()?,
                     buffer.read_u16()?,
                 ),
-                ttl: ttl,
+                ttl,
+            }),
+            QueryType::OPT => Ok(DnsRecord::OPT {
+                domain,
+                packet_size: qclass.to_num(),
+                extended_rcode: (ttl >> 24) as u8,
+                version: (ttl >> 16) as u8,
+                flags: ttl as u16,
+                data: read_rest(buffer, end)?,
+            }),
+            QueryType::DS => Ok(DnsRecord::DS {
+                domain,
+                key_tag: buffer.read_u16()?,
+                algorithm: buffer.read_u8()?,
+                digest_type: buffer.read_u8()?,
+                digest: read_rest(buffer, end)?,
+                ttl,
+            }),
+            QueryType::RRSIG => Ok(DnsRecord::RRSIG {
+                domain,
+                type_covered: QueryType::from_num(buffer.read_u16()?),
+                algorithm: buffer.read_u8()?,
+                labels: buffer.read_u8()?,
+                original_ttl: buffer.read_u32()?,
+                expiration: buffer.read_u32()?,
+                inception: buffer.read_u32()?,
+                key_tag: buffer.read_u16()?,
+                signer_name: buffer.read_name()?,
+                signature: read_rest(buffer, end)?,
+                ttl,
+            }),
+            QueryType::NSEC => Ok(DnsRecord::NSEC {
+                domain,
+                next_domain: buffer.read_name()?,
+                types: read_type_bitmap(&read_rest(buffer, end)?)?,
+                ttl,
+            }),
+            QueryType::DNSKEY => Ok(DnsRecord::DNSKEY {
+                domain,
+                flags: buffer.read_u16()?,
+                protocol: buffer.read_u8()?,
+                algorithm: buffer.read_u8()?,
+                public_key: read_rest(buffer, end)?,
+                ttl,
+            }),
+            QueryType::NSEC3 => Ok(DnsRecord::NSEC3 {
+                domain,
+                hash_algorithm: buffer.read_u8()?,
+                flags: buffer.read_u8()?,
+                iterations: buffer.read_u16()?,
+                salt: read_sized(buffer, end)?,
+                next_hashed_owner: read_sized(buffer, end)?,
+                types: read_type_bitmap(&read_rest(buffer, end)?)?,
+                ttl,
+            }),
+            QueryType::NSEC3PARAM => Ok(DnsRecord::NSEC3PARAM {
+                domain,
+                hash_algorithm: buffer.read_u8()?,
+                flags: buffer.read_u8()?,
+                iterations: buffer.read_u16()?,
+                salt: read_sized(buffer, end)?,
+                ttl,
+            }),
+            QueryType::UNKNOWN(_) => Ok(DnsRecord::UNKNOWN {
+                domain,
+                qtype,
+                qclass,
+                ttl,
+                data: read_rest(buffer, end)?,
             }),
-            QueryType::UNKNOWN(_) => {
-                buffer.step(rdlength as usize)?;
-
-                Ok(DnsRecord::UNKNOWN {
-                    domain: domain,
-                    qtype: qtype,
-                    qclass: qclass,
-                    ttl: ttl,
-                    rdlength: rdlength,
-                })
-            }
         }
     }
 
     pub fn write(&self, buffer: &mut BytePacketBuffer) -> Result<usize> {
         let start_pos = buffer.pos();
 
-        match self {
-            DnsRecord::A {
-                domain,
-                ip_v4_addr,
-                ttl,
-            } => {
-                buffer.write_qname(domain)?;
-                buffer.write_u16(QueryType::A.to_num())?;
-                buffer.write_u16(QueryClass::IN.to_num())?;
-                buffer.write_u32(*ttl)?;
-                buffer.write_u16(4)?;
-
-                buffer.write_u32(ip_v4_addr.to_bits())?;
-            }
-            DnsRecord::NS { domain, host, ttl } => {
-                buffer.write_qname(domain)?;
-                buffer.write_u16(QueryType::NS.to_num())?;
-                buffer.write_u16(QueryClass::IN.to_num())?;
-                buffer.write_u32(*ttl)?;
+        buffer.write_qname(self.domain())?;
+        buffer.write_u16(self.qtype().to_num())?;
+        buffer.write_u16(self.qclass().to_num())?;
+        buffer.write_u32(self.ttl())?;
 
-                let pos = buffer.pos();
-                buffer.write_u16(0)?;
+        let pos = buffer.pos();
+        buffer.write_u16(0)?;
 
-                buffer.write_qname(host)?;
+        self.write_rdata(buffer)?;
 
-                let size = buffer.pos() - (pos + 2);
-                buffer.set_u16(pos, size as u16)?;
-            }
-            DnsRecord::CNAME { domain, host, ttl } => {
-                buffer.write_qname(domain)?;
-                buffer.write_u16(QueryType::NS.to_num())?;
-                buffer.write_u16(QueryClass::IN.to_num())?;
-                buffer.write_u32(*ttl)?;
+        let size = buffer.pos() - (pos + 2);
+        buffer.set_u16(pos, size as u16)?;
 
-                let pos = buffer.pos();
-                buffer.write_u16(0)?;
+        Ok(buffer.pos() - start_pos)
+    }
 
+    fn write_rdata(&self, buffer: &mut BytePacketBuffer) -> Result<()> {
+        match self {
+            DnsRecord::A { ip_v4_addr, .. } => {
+                buffer.write_u32(ip_v4_addr.to_bits())?;
+            }
+            DnsRecord::NS { host, .. }
+            | DnsRecord::CNAME { host, .. }
+            | DnsRecord::PTR { host, .. } => {
                 buffer.write_qname(host)?;
-
-                let size = buffer.pos() - (pos + 2);
-                buffer.set_u16(pos, size as u16)?;
             }
-            DnsRecord::MX {
-                domain,
-                priority,
-                host,
-                ttl,
+            DnsRecord::SOA {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                ..
             } => {
-                buffer.write_qname(domain)?;
-                buffer.write_u16(QueryType::MX.to_num())?;
-                buffer.write_u16(QueryClass::IN.to_num())?;
-                buffer.write_u32(*ttl)?;
-
-                let pos = buffer.pos();
-                buffer.write_u16(0)?;
-
+                buffer.write_qname(mname)?;
+                buffer.write_qname(rname)?;
+                buffer.write_u32(*serial)?;
+                buffer.write_u32(*refresh)?;
+                buffer.write_u32(*retry)?;
+                buffer.write_u32(*expire)?;
+                buffer.write_u32(*minimum)?;
+            }
+            DnsRecord::MX { priority, host, .. } => {
                 buffer.write_u16(*priority)?;
                 buffer.write_qname(host)?;
-
-                let size = buffer.pos() - (pos + 2);
-                buffer.set_u16(pos, size as u16)?;
             }
-            DnsRecord::AAAA {
-                domain,
-                ip_v6_addr,
-                ttl,
-            } => {
-                buffer.write_qname(domain)?;
-                buffer.write_u16(QueryType::A.to_num())?;
-                buffer.write_u16(QueryClass::IN.to_num())?;
-                buffer.write_u32(*ttl)?;
-                buffer.write_u16(16)?;
-
+            DnsRecord::AAAA { ip_v6_addr, .. } => {
                 for segment in ip_v6_addr.segments() {
                     buffer.write_u16(segment)?;
                 }
             }
-            DnsRecord::UNKNOWN {
-                domain,
-                qtype,
-                qclass,
-                ttl,
-                rdlength,
+            DnsRecord::DS {
+                key_tag,
+                algorithm,
+                digest_type,
+                digest,
+                ..
             } => {
-                buffer.write_qname(domain)?;
-                buffer.write_u16(qtype.to_num())?;
-                buffer.write_u16(qclass.to_num())?;
-                buffer.write_u32(*ttl)?;
-                buffer.write_u16(*rdlength)?;
+                buffer.write_u16(*key_tag)?;
+                buffer.write_u8(*algorithm)?;
+                buffer.write_u8(*digest_type)?;
+                write_bytes(buffer, digest)?;
+            }
+            DnsRecord::RRSIG { signature, .. } => {
+                self.write_rrsig_fields(buffer)?;
+                write_bytes(buffer, signature)?;
+            }
+            DnsRecord::NSEC {
+                next_domain, types, ..
+            } => {
+                buffer.write_qname(next_domain)?;
+                write_type_bitmap(buffer, types)?;
+            }
+            DnsRecord::DNSKEY {
+                flags,
+                protocol,
+                algorithm,
+                public_key,
+                ..
+            } => {
+                buffer.write_u16(*flags)?;
+                buffer.write_u8(*protocol)?;
+                buffer.write_u8(*algorithm)?;
+                write_bytes(buffer, public_key)?;
+            }
+            DnsRecord::NSEC3 {
+                hash_algorithm,
+                flags,
+                iterations,
+                salt,
+                next_hashed_owner,
+                types,
+                ..
+            } => {
+                buffer.write_u8(*hash_algorithm)?;
+                buffer.write_u8(*flags)?;
+                buffer.write_u16(*iterations)?;
+                buffer.write_u8(salt.len() as u8)?;
+                write_bytes(buffer, salt)?;
+                buffer.write_u8(next_hashed_owner.len() as u8)?;
+                write_bytes(buffer, next_hashed_owner)?;
+                write_type_bitmap(buffer, types)?;
+            }
+            DnsRecord::NSEC3PARAM {
+                hash_algorithm,
+                flags,
+                iterations,
+                salt,
+                ..
+            } => {
+                buffer.write_u8(*hash_algorithm)?;
+                buffer.write_u8(*flags)?;
+                buffer.write_u16(*iterations)?;
+                buffer.write_u8(salt.len() as u8)?;
+                write_bytes(buffer, salt)?;
+            }
+            DnsRecord::OPT { data, .. } | DnsRecord::UNKNOWN { data, .. } => {
+                write_bytes(buffer, data)?;
             }
         }
 
-        Ok(buffer.pos() - start_pos)
+        Ok(())
+    }
+
+    // Everything in the RDATA of an RRSIG but the signature, which is the
+    // start of the data it signs (RFC 4034 3.1.8.1)
+    fn write_rrsig_fields(&self, buffer: &mut BytePacketBuffer) -> Result<()> {
+        if let DnsRecord::RRSIG {
+            type_covered,
+            algorithm,
+            labels,
+            original_ttl,
+            expiration,
+            inception,
+            key_tag,
+            signer_name,
+            ..
+        } = self
+        {
+            buffer.write_u16(type_covered.to_num())?;
+            buffer.write_u8(*algorithm)?;
+            buffer.write_u8(*labels)?;
+            buffer.write_u32(*original_ttl)?;
+            buffer.write_u32(*expiration)?;
+            buffer.write_u32(*inception)?;
+            buffer.write_u16(*key_tag)?;
+            buffer.write_qname(signer_name)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn rdata(&self) -> Result<Vec<u8>> {
+        let mut buffer = BytePacketBuffer::with_size(MAX_TCP_SIZE);
+        self.write_rdata(&mut buffer)?;
+
+        Ok(buffer.buf[..buffer.pos()].to_vec())
+    }
+
+    // The data an RRSIG signs (RFC 4034 3.1.8.1): its own RDATA without the
+    // signature, then the RRset in canonical form and order with the original
+    // TTL. An RRset expanded from a wildcard is signed under the wildcard
+    // owner name (RFC 4035 5.3.2)
+    pub fn signed_data(&self, rrset: &[DnsRecord]) -> Result<Vec<u8>> {
+        let (labels, original_ttl) = match self {
+            DnsRecord::RRSIG {
+                labels,
+                original_ttl,
+                ..
+            } => (*labels as usize, *original_ttl),
+            _ => return Err("Only RRSIG records sign data".into()),
+        };
+
+        let mut buffer = BytePacketBuffer::with_size(MAX_TCP_SIZE);
+        self.to_canonical().write_rrsig_fields(&mut buffer)?;
+
+        for record in canonical_order(rrset.to_vec()) {
+            let mut record = record.to_canonical();
+            if label_count(record.domain()) > labels {
+                let wildcard = format!("*.{}", last_labels(record.domain(), labels));
+                record.set_domain(&wildcard);
+            }
+            record.set_ttl(original_ttl);
+            record.write(&mut buffer)?;
+        }
+
+        Ok(buffer.buf[..buffer.pos()].to_vec())
+    }
+
+    // Canonical form of the record (RFC 4034 6.2): the owner name and the
+    // names in the RDATA of the types listed there are lowercased. The next
+    // domain name of NSEC is not among them anymore (RFC 6840 5.1)
+    pub fn to_canonical(&self) -> DnsRecord {
+        let mut record = self.clone();
+        record.set_domain(&self.domain().to_ascii_lowercase());
+
+        match &mut record {
+            DnsRecord::NS { host, .. }
+            | DnsRecord::CNAME { host, .. }
+            | DnsRecord::PTR { host, .. }
+            | DnsRecord::MX { host, .. } => host.make_ascii_lowercase(),
+            DnsRecord::SOA { mname, rname, .. } => {
+                mname.make_ascii_lowercase();
+                rname.make_ascii_lowercase();
+            }
+            DnsRecord::RRSIG { signer_name, .. } => signer_name.make_ascii_lowercase(),
+            _ => {}
+        }
+
+        record
+    }
+
+    pub fn domain(&self) -> &str {
+        match self {
+            DnsRecord::A { domain, .. }
+            | DnsRecord::NS { domain, .. }
+            | DnsRecord::CNAME { domain, .. }
+            | DnsRecord::SOA { domain, .. }
+            | DnsRecord::PTR { domain, .. }
+            | DnsRecord::MX { domain, .. }
+            | DnsRecord::AAAA { domain, .. }
+            | DnsRecord::OPT { domain, .. }
+            | DnsRecord::DS { domain, .. }
+            | DnsRecord::RRSIG { domain, .. }
+            | DnsRecord::NSEC { domain, .. }
+            | DnsRecord::DNSKEY { domain, .. }
+            | DnsRecord::NSEC3 { domain, .. }
+            | DnsRecord::NSEC3PARAM { domain, .. }
+            | DnsRecord::UNKNOWN { domain, .. } => domain,
+        }
+    }
+
+    pub fn set_domain(&mut self, name: &str) {
+        match self {
+            DnsRecord::A { domain, .. }
+            | DnsRecord::NS { domain, .. }
+            | DnsRecord::CNAME { domain, .. }
+            | DnsRecord::SOA { domain, .. }
+            | DnsRecord::PTR { domain, .. }
+            | DnsRecord::MX { domain, .. }
+            | DnsRecord::AAAA { domain, .. }
+            | DnsRecord::OPT { domain, .. }
+            | DnsRecord::DS { domain, .. }
+            | DnsRecord::RRSIG { domain, .. }
+            | DnsRecord::NSEC { domain, .. }
+            | DnsRecord::DNSKEY { domain, .. }
+            | DnsRecord::NSEC3 { domain, .. }
+            | DnsRecord::NSEC3PARAM { domain, .. }
+            | DnsRecord::UNKNOWN { domain, .. } => *domain = name.to_string(),
+        }
+    }
+
+    pub fn qtype(&self) -> QueryType {
+        match self {
+            DnsRecord::A { .. } => QueryType::A,
+            DnsRecord::NS { .. } => QueryType::NS,
+            DnsRecord::CNAME { .. } => QueryType::CNAME,
+            DnsRecord::SOA { .. } => QueryType::SOA,
+            DnsRecord::PTR { .. } => QueryType::PTR,
+            DnsRecord::MX { .. } => QueryType::MX,
+            DnsRecord::AAAA { .. } => QueryType::AAAA,
+            DnsRecord::OPT { .. } => QueryType::OPT,
+            DnsRecord::DS { .. } => QueryType::DS,
+            DnsRecord::RRSIG { .. } => QueryType::RRSIG,
+            DnsRecord::NSEC { .. } => QueryType::NSEC,
+            DnsRecord::DNSKEY { .. } => QueryType::DNSKEY,
+            DnsRecord::NSEC3 { .. } => QueryType::NSEC3,
+            DnsRecord::NSEC3PARAM { .. } => QueryType::NSEC3PARAM,
+            DnsRecord::UNKNOWN { qtype, .. } => *qtype,
+        }
+    }
+
+    pub fn qclass(&self) -> QueryClass {
+        match self {
+            DnsRecord::OPT { packet_size, .. } => QueryClass::from_num(*packet_size),
+            DnsRecord::UNKNOWN { qclass, .. } => *qclass,
+            _ => QueryClass::IN,
+        }
+    }
+
+    pub fn ttl(&self) -> u32 {
+        match self {
+            DnsRecord::A { ttl, .. }
+            | DnsRecord::NS { ttl, .. }
+            | DnsRecord::CNAME { ttl, .. }
+            | DnsRecord::SOA { ttl, .. }
+            | DnsRecord::PTR { ttl, .. }
+            | DnsRecord::MX { ttl, .. }
+            | DnsRecord::AAAA { ttl, .. }
+            | DnsRecord::DS { ttl, .. }
+            | DnsRecord::RRSIG { ttl, .. }
+            | DnsRecord::NSEC { ttl, .. }
+            | DnsRecord::DNSKEY { ttl, .. }
+            | DnsRecord::NSEC3 { ttl, .. }
+            | DnsRecord::NSEC3PARAM { ttl, .. }
+            | DnsRecord::UNKNOWN { ttl, .. } => *ttl,
+            DnsRecord::OPT {
+                extended_rcode,
+                version,
+                flags,
+                ..
+            } => (*extended_rcode as u32) << 24 | (*version as u32) << 16 | *flags as u32,
+        }
+    }
+
+    pub fn set_ttl(&mut self, new_ttl: u32) {
+        match self {
+            DnsRecord::A { ttl, .. }
+            | DnsRecord::NS { ttl, .. }
+            | DnsRecord::CNAME { ttl, .. }
+            | DnsRecord::SOA { ttl, .. }
+            | DnsRecord::PTR { ttl, .. }
+            | DnsRecord::MX { ttl, .. }
+            | DnsRecord::AAAA { ttl, .. }
+            | DnsRecord::DS { ttl, .. }
+            | DnsRecord::RRSIG { ttl, .. }
+            | DnsRecord::NSEC { ttl, .. }
+            | DnsRecord::DNSKEY { ttl, .. }
+            | DnsRecord::NSEC3 { ttl, .. }
+            | DnsRecord::NSEC3PARAM { ttl, .. }
+            | DnsRecord::UNKNOWN { ttl, .. } => *ttl = new_ttl,
+            // The OPT TTL field is not a TTL and never ages
+            DnsRecord::OPT { .. } => {}
+        }
+    }
+}
+
+// Sorts an RRset into canonical order, by the RDATA of the canonical form
+// compared as left-justified octet strings, and drops duplicates (RFC 4034 6.3)
+pub fn canonical_order(records: Vec<DnsRecord>) -> Vec<DnsRecord> {
+    let mut keyed: Vec<(Vec<u8>, DnsRecord)> = records
+        .into_iter()
+        .map(|record| (record.to_canonical().rdata().unwrap_or_default(), record))
+        .collect();
+
+    keyed.sort_by(|a, b| a.0.cmp(&b.0));
+    keyed.dedup_by(|a, b| a.0 == b.0);
+
+    keyed.into_iter().map(|(_, record)| record).collect()
+}
+
+fn fqdn(name: &str) -> String {
+    format!("{}.", name.trim_end_matches('.'))
+}
+
+// Seconds since the epoch as YYYYMMDDHHmmSS (RFC 4034 3.2), using the
+// days-to-civil conversion for the proleptic Gregorian calendar
+fn timestamp(seconds: u32) -> String {
+    let days = (seconds / 86_400) as i64;
+    let time = seconds % 86_400;
+
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}{:02}{:02}{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+fn type_list(types: &[QueryType]) -> String {
+    types
+        .iter()
+        .map(|qtype| qtype.to_string())
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+fn salt(salt: &[u8]) -> String {
+    if salt.is_empty() {
+        "-".to_string()
+    } else {
+        hex_encode(salt)
+    }
+}
+
+// Presentation format as used in zone files
+impl fmt::Display for DnsRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {} {} ",
+            fqdn(self.domain()),
+            self.ttl(),
+            self.qclass(),
+            self.qtype()
+        )?;
+
+        match self {
+            DnsRecord::A { ip_v4_addr, .. } => write!(f, "{}", ip_v4_addr),
+            DnsRecord::NS { host, .. }
+            | DnsRecord::CNAME { host, .. }
+            | DnsRecord::PTR { host, .. } => write!(f, "{}", fqdn(host)),
+            DnsRecord::SOA {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                ..
+            } => write!(
+                f,
+                "{} {} {} {} {} {} {}",
+                fqdn(mname),
+                fqdn(rname),
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum
+            ),
+            DnsRecord::MX { priority, host, .. } => write!(f, "{} {}", priority, fqdn(host)),
+            DnsRecord::AAAA { ip_v6_addr, .. } => write!(f, "{}", ip_v6_addr),
+            DnsRecord::DS {
+                key_tag,
+                algorithm,
+                digest_type,
+                digest,
+                ..
+            } => write!(
+                f,
+                "{} {} {} {}",
+                key_tag,
+                algorithm,
+                digest_type,
+                hex_encode(digest)
+            ),
+            DnsRecord::RRSIG {
+                type_covered,
+                algorithm,
+                labels,
+                original_ttl,
+                expiration,
+                inception,
+                key_tag,
+                signer_name,
+                signature,
+                ..
+            } => write!(
+                f,
+                "{} {} {} {} {} {} {} {} {}",
+                type_covered,
+                algorithm,
+                labels,
+                original_ttl,
+                timestamp(*expiration),
+                timestamp(*inception),
+                key_tag,
+                fqdn(signer_name),
+                base64_encode(signature)
+            ),
+            DnsRecord::NSEC {
+                next_domain, types, ..
+            } => write!(f, "{} {}", fqdn(next_domain), type_list(types)),
+            DnsRecord::DNSKEY {
+                flags,
+                protocol,
+                algorithm,
+                public_key,
+                ..
+            } => write!(
+                f,
+                "{} {} {} {}",
+                flags,
+                protocol,
+                algorithm,
+                base64_encode(public_key)
+            ),
+            DnsRecord::NSEC3 {
+                hash_algorithm,
+                flags,
+                iterations,
+                salt: nsec3_salt,
+                next_hashed_owner,
+                types,
+                ..
+            } => write!(
+                f,
+                "{} {} {} {} {} {}",
+                hash_algorithm,
+                flags,
+                iterations,
+                salt(nsec3_salt),
+                base32hex_encode(next_hashed_owner),
+                type_list(types)
+            ),
+            DnsRecord::NSEC3PARAM {
+                hash_algorithm,
+                flags,
+                iterations,
+                salt: nsec3_salt,
+                ..
+            } => write!(
+                f,
+                "{} {} {} {}",
+                hash_algorithm,
+                flags,
+                iterations,
+                salt(nsec3_salt)
+            ),
+            // RFC 3597 5
+            DnsRecord::OPT { data, .. } | DnsRecord::UNKNOWN { data, .. } => {
+                write!(f, "\\# {} {}", data.len(), hex_encode(data))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::dns_packet::DnsPacket;
+    use crate::utils::encoding::hex_decode;
+
+    fn round_trip(records: Vec<DnsRecord>) -> Vec<DnsRecord> {
+        let mut packet = DnsPacket::new();
+        packet.header.answers_count = records.len() as u16;
+        packet.answers = records;
+
+        let mut buffer = BytePacketBuffer::new();
+        packet.to_buffer(&mut buffer).unwrap();
+        buffer.seek(0).unwrap();
+
+        DnsPacket::from_buffer(&mut buffer).unwrap().answers
+    }
+
+    fn read_rdata(qtype: QueryType, rdata: &[u8]) -> Result<DnsRecord> {
+        let mut buffer = BytePacketBuffer::new();
+        buffer.write_qname("example.com")?;
+        buffer.write_u16(qtype.to_num())?;
+        buffer.write_u16(QueryClass::IN.to_num())?;
+        buffer.write_u32(3600)?;
+        buffer.write_u16(rdata.len() as u16)?;
+        write_bytes(&mut buffer, rdata)?;
+        buffer.seek(0)?;
+
+        DnsRecord::read(&mut buffer)
+    }
+
+    #[test]
+    fn round_trips_dnssec_records() {
+        let records = vec![
+            DnsRecord::DS {
+                domain: "example.com".to_string(),
+                key_tag: 60485,
+                algorithm: 8,
+                digest_type: 2,
+                digest: vec![0x2b; 32],
+                ttl: 3600,
+            },
+            DnsRecord::RRSIG {
+                domain: "www.example.com".to_string(),
+                type_covered: QueryType::A,
+                algorithm: 13,
+                labels: 3,
+                original_ttl: 300,
+                expiration: 1_700_086_400,
+                inception: 1_700_000_000,
+                key_tag: 2371,
+                signer_name: "example.com".to_string(),
+                signature: (0..64).collect(),
+                ttl: 300,
+            },
+            DnsRecord::DNSKEY {
+                domain: "example.com".to_string(),
+                flags: 257,
+                protocol: 3,
+                algorithm: 15,
+                public_key: (0..32).rev().collect(),
+                ttl: 3600,
+            },
+            DnsRecord::NSEC {
+                domain: "alfa.example.com".to_string(),
+                next_domain: "host.example.com".to_string(),
+                types: vec![QueryType::A, QueryType::RRSIG, QueryType::NSEC],
+                ttl: 3600,
+            },
+            DnsRecord::NSEC3 {
+                domain: "0p9mhaveqvm6t7vbl5lop2u3t2rp3tom.example.com".to_string(),
+                hash_algorithm: 1,
+                flags: 1,
+                iterations: 12,
+                salt: vec![0xaa, 0xbb, 0xcc, 0xdd],
+                next_hashed_owner: vec![0x35; 20],
+                types: vec![QueryType::NS, QueryType::DS, QueryType::RRSIG],
+                ttl: 3600,
+            },
+            // An empty salt is a zero length field
+            DnsRecord::NSEC3PARAM {
+                domain: "example.com".to_string(),
+                hash_algorithm: 1,
+                flags: 0,
+                iterations: 0,
+                salt: Vec::new(),
+                ttl: 0,
+            },
+        ];
+
+        assert_eq!(round_trip(records.clone()), records);
+    }
+
+    #[test]
+    fn round_trips_type_bitmaps_over_several_windows() {
+        let types = vec![
+            QueryType::A,
+            QueryType::MX,
+            QueryType::RRSIG,
+            QueryType::NSEC,
+            QueryType::UNKNOWN(257),
+            QueryType::UNKNOWN(1234),
+            QueryType::UNKNOWN(65535),
+        ];
+        let records = vec![
+            DnsRecord::NSEC {
+                domain: "alfa.example.com".to_string(),
+                next_domain: "host.example.com".to_string(),
+                types: types.clone(),
+                ttl: 3600,
+            },
+            DnsRecord::NSEC3 {
+                domain: "0p9mhaveqvm6t7vbl5lop2u3t2rp3tom.example.com".to_string(),
+                hash_algorithm: 1,
+                flags: 0,
+                iterations: 0,
+                salt: Vec::new(),
+                next_hashed_owner: vec![0x35; 20],
+                types,
+                ttl: 3600,
+            },
+        ];
+
+        assert_eq!(round_trip(records.clone()), records);
+    }
+
+    // The example of RFC 4034 4.3
+    #[test]
+    fn writes_type_bitmaps_as_in_the_rfc() {
+        let types = vec![
+            QueryType::A,
+            QueryType::MX,
+            QueryType::RRSIG,
+            QueryType::NSEC,
+            QueryType::UNKNOWN(1234),
+        ];
+        let record = DnsRecord::NSEC {
+            domain: "alfa.example.com".to_string(),
+            next_domain: "host.example.com".to_string(),
+            types: types.clone(),
+            ttl: 86400,
+        };
+
+        let bitmap = hex_decode(concat!(
+            "0006400100000003",
+            "041b000000000000000000000000000000000000000000000000000020"
+        ))
+        .unwrap();
+
+        assert!(record.rdata().unwrap().ends_with(&bitmap));
+        assert_eq!(read_type_bitmap(&bitmap).unwrap(), types);
+    }
+
+    #[test]
+    fn rejects_malformed_type_bitmaps() {
+        // Empty window, window longer than 32 bytes and a truncated bitmap
+        for bitmap in ["0000", "0021", "000240"] {
+            assert!(read_type_bitmap(&hex_decode(bitmap).unwrap()).is_err());
+        }
+        assert_eq!(read_type_bitmap(&[]).unwrap(), Vec::new());
+    }
+
+    #[test]
+    fn rejects_sized_fields_past_the_rdata() {
+        // NSEC3PARAM claiming an 8 byte salt with only 2 present
+        let rdata = hex_decode("0100000008abcd").unwrap();
+        assert!(read_rdata(QueryType::NSEC3PARAM, &rdata).is_err());
     }
 }
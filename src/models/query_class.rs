@@ -1,3 +1,5 @@
+use std::fmt;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub enum QueryClass {
     IN,
@@ -8,8 +10,8 @@ pub enum QueryClass {
 }
 
 impl QueryClass {
-    pub fn to_num(&self) -> u16 {
-        match *self {
+    pub fn to_num(self) -> u16 {
+        match self {
             QueryClass::IN => 1,
             QueryClass::CH => 3,
             QueryClass::HS => 4,
@@ -28,3 +30,13 @@ impl QueryClass {
         }
     }
 }
+
+// Unknown classes are written as CLASS<n> (RFC 3597 5)
+impl fmt::Display for QueryClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QueryClass::UNKNOWN(qclass) => write!(f, "CLASS{}", qclass),
+            _ => write!(f, "{:?}", self),
+        }
+    }
+}
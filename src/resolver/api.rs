@@ -0,0 +1,176 @@
+// Entry point for embedding the resolver. A Resolver owns the caches and
+// upstream clients and can be shared between threads, every call to resolve
+// running the whole lookup on the calling thread
+
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use log::warn;
+
+use super::forwarder::ForwardStrategy;
+use super::lookup::{prime_root_hints, refresh_cache, resolve};
+use crate::cache::record_cache::RecordCache;
+use crate::config::Config;
+use crate::context::ServerContext;
+use crate::models::{dns_packet::DnsPacket, query_class::QueryClass, query_type::QueryType};
+use crate::types::Result;
+
+pub struct Resolver {
+    context: Arc<ServerContext>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ResolverBuilder {
+    config: Config,
+    cache_limits: Option<(usize, u32)>,
+}
+
+impl Resolver {
+    pub fn builder() -> ResolverBuilder {
+        ResolverBuilder::new()
+    }
+
+    pub fn context(&self) -> &ServerContext {
+        &self.context
+    }
+
+    pub fn resolve(&self, qname: &str, qtype: QueryType, qclass: QueryClass) -> Result<DnsPacket> {
+        self.resolve_with(qname, qtype, qclass, false)
+    }
+
+    // Resolves the query the way a client setting the CD bit asks for, which
+    // skips DNSSEC validation when checking_disabled is set. Identical queries
+    // made meanwhile by other threads share a single resolution
+    pub fn resolve_with(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        qclass: QueryClass,
+        checking_disabled: bool,
+    ) -> Result<DnsPacket> {
+        let context = &*self.context;
+
+        context
+            .in_flight
+            .resolve(qname, qtype, qclass, checking_disabled, context, || {
+                resolve(qname, qtype, qclass, checking_disabled, context)
+            })
+    }
+}
+
+impl Drop for Resolver {
+    fn drop(&mut self) {
+        self.context.refresh_queue.close();
+    }
+}
+
+impl ResolverBuilder {
+    pub fn new() -> ResolverBuilder {
+        ResolverBuilder::from_config(Config::new())
+    }
+
+    pub fn from_config(config: Config) -> ResolverBuilder {
+        ResolverBuilder {
+            config,
+            cache_limits: None,
+        }
+    }
+
+    // Root hints file in named.root format, replacing the bundled IANA copy
+    pub fn root_hints(mut self, path: impl Into<PathBuf>) -> ResolverBuilder {
+        self.config.root_hints = Some(path.into());
+        self
+    }
+
+    // Upstream resolver to forward queries to instead of recursing from the
+    // root, may be called repeatedly
+    pub fn forwarder(mut self, addr: Ipv4Addr, port: u16) -> ResolverBuilder {
+        self.config.forwarders.push((addr, port));
+        self
+    }
+
+    pub fn forward_strategy(mut self, strategy: ForwardStrategy) -> ResolverBuilder {
+        self.config.forward_strategy = strategy;
+        self
+    }
+
+    pub fn cache_limits(mut self, max_entries: usize, max_ttl: u32) -> ResolverBuilder {
+        self.cache_limits = Some((max_entries, max_ttl));
+        self
+    }
+
+    pub fn serve_stale(mut self, window: Duration) -> ResolverBuilder {
+        self.config.serve_stale = Some(window);
+        self
+    }
+
+    pub fn prefetch(mut self, prefetch: bool) -> ResolverBuilder {
+        self.config.prefetch = prefetch;
+        self
+    }
+
+    pub fn query_timeout(mut self, timeout: Duration) -> ResolverBuilder {
+        self.config.query_timeout = timeout;
+        self
+    }
+
+    pub fn query_attempts(mut self, attempts: u32) -> ResolverBuilder {
+        self.config.query_attempts = attempts;
+        self
+    }
+
+    pub fn query_budget(mut self, budget: Duration) -> ResolverBuilder {
+        self.config.query_budget = budget;
+        self
+    }
+
+    // Validates answers against the given trust anchors file, or the bundled
+    // root zone anchors when there is none
+    pub fn dnssec(mut self, trust_anchors: Option<PathBuf>) -> ResolverBuilder {
+        self.config.dnssec_validation = true;
+        self.config.trust_anchors = trust_anchors;
+        self
+    }
+
+    // Sets up the resolver, priming the root hints unless forwarding, and
+    // starts refreshing the cache in the background if serve-stale or
+    // prefetching is enabled
+    pub fn build(self) -> Result<Resolver> {
+        let config = self.config;
+        if config.query_attempts == 0 {
+            return Err("Query attempts must be at least 1".into());
+        }
+
+        let stale_window = config.serve_stale.unwrap_or_default();
+        let cache = match self.cache_limits {
+            Some((max_entries, max_ttl)) => {
+                RecordCache::with_limits(max_entries, max_ttl, stale_window)
+            }
+            None => RecordCache::new(stale_window),
+        };
+
+        let context = Arc::new(ServerContext::new(config, cache)?);
+
+        if context.forwarders.is_none() {
+            if let Err(e) = prime_root_hints(&context) {
+                warn!("Priming query failed, using root hints as is: {}", e);
+            }
+        }
+
+        if context.config.serve_stale.is_some() || context.config.prefetch {
+            let context = context.clone();
+            thread::spawn(move || refresh_cache(&context));
+        }
+
+        Ok(Resolver { context })
+    }
+}
+
+impl Default for ResolverBuilder {
+    fn default() -> ResolverBuilder {
+        ResolverBuilder::new()
+    }
+}
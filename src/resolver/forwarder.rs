@@ -0,0 +1,173 @@
+// Upstream resolvers used in forwarding mode, along with the strategy used to
+// pick between them and their health
+
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+use crate::types::{Error, Result};
+use crate::utils::domain_name::is_subdomain;
+
+// An upstream is considered down after this many failures in a row and is
+// only used as a last resort until the hold down period has passed
+const MAX_CONSECUTIVE_FAILURES: u32 = 3;
+const HOLD_DOWN: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForwardStrategy {
+    Sequential,
+    RoundRobin,
+    Fastest,
+}
+
+impl FromStr for ForwardStrategy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ForwardStrategy> {
+        match s {
+            "sequential" => Ok(ForwardStrategy::Sequential),
+            "round-robin" => Ok(ForwardStrategy::RoundRobin),
+            "fastest" => Ok(ForwardStrategy::Fastest),
+            _ => Err(format!("Unknown forwarding strategy {}", s).into()),
+        }
+    }
+}
+
+// Whether a conditional forwarding rule falls back to normal resolution when
+// none of its upstreams answer, as BIND's "forward first" and "forward only"
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForwardPolicy {
+    First,
+    Only,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ForwardZoneConfig {
+    pub zone: String,
+    pub upstreams: Vec<(Ipv4Addr, u16)>,
+    pub policy: ForwardPolicy,
+}
+
+#[derive(Debug)]
+pub struct ForwardZone {
+    pub zone: String,
+    pub policy: ForwardPolicy,
+    pub pool: ForwarderPool,
+}
+
+impl ForwardZone {
+    pub fn new(config: &ForwardZoneConfig, strategy: ForwardStrategy) -> ForwardZone {
+        ForwardZone {
+            zone: config.zone.clone(),
+            policy: config.policy,
+            pool: ForwarderPool::new(config.upstreams.clone(), strategy),
+        }
+    }
+}
+
+// Picks the rule with the longest zone suffix matching qname
+pub fn find_forward_zone<'a>(zones: &'a [ForwardZone], qname: &str) -> Option<&'a ForwardZone> {
+    zones
+        .iter()
+        .filter(|zone| is_subdomain(qname, &zone.zone))
+        .max_by_key(|zone| zone.zone.len())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UpstreamHealth {
+    pub srtt: Option<Duration>,
+    pub consecutive_failures: u32,
+    pub down_until: Option<Instant>,
+}
+
+impl UpstreamHealth {
+    fn new() -> UpstreamHealth {
+        UpstreamHealth {
+            srtt: None,
+            consecutive_failures: 0,
+            down_until: None,
+        }
+    }
+
+    pub fn is_down(&self, now: Instant) -> bool {
+        self.down_until.is_some_and(|until| until > now)
+    }
+}
+
+#[derive(Debug)]
+pub struct ForwarderPool {
+    upstreams: Vec<(Ipv4Addr, u16)>,
+    strategy: ForwardStrategy,
+    next: AtomicUsize,
+    health: RwLock<HashMap<(Ipv4Addr, u16), UpstreamHealth>>,
+}
+
+impl ForwarderPool {
+    pub fn new(upstreams: Vec<(Ipv4Addr, u16)>, strategy: ForwardStrategy) -> ForwarderPool {
+        ForwarderPool {
+            upstreams,
+            strategy,
+            next: AtomicUsize::new(0),
+            health: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn health(&self, upstream: (Ipv4Addr, u16)) -> UpstreamHealth {
+        self.health
+            .read()
+            .unwrap()
+            .get(&upstream)
+            .copied()
+            .unwrap_or_else(UpstreamHealth::new)
+    }
+
+    // Orders the upstreams according to the strategy, healthy ones first
+    pub fn order(&self) -> Vec<(Ipv4Addr, u16)> {
+        let mut upstreams = self.upstreams.clone();
+
+        match self.strategy {
+            ForwardStrategy::Sequential => {}
+            ForwardStrategy::RoundRobin => {
+                let len = upstreams.len().max(1);
+                let start = self.next.fetch_add(1, Ordering::Relaxed) % len;
+                upstreams.rotate_left(start);
+            }
+            // Upstreams without a measurement yet go first so that they get one
+            ForwardStrategy::Fastest => {
+                upstreams.sort_by_key(|upstream| self.health(*upstream).srtt.unwrap_or_default())
+            }
+        }
+
+        let now = Instant::now();
+        upstreams.sort_by_key(|upstream| self.health(*upstream).is_down(now));
+        upstreams
+    }
+
+    pub fn record_success(&self, upstream: (Ipv4Addr, u16), rtt: Duration) {
+        let mut health = self.health.write().unwrap();
+        let entry = health.entry(upstream).or_insert_with(UpstreamHealth::new);
+
+        entry.srtt = Some(match entry.srtt {
+            Some(srtt) => (srtt * 7 + rtt) / 8,
+            None => rtt,
+        });
+        entry.consecutive_failures = 0;
+        entry.down_until = None;
+    }
+
+    pub fn record_failure(&self, upstream: (Ipv4Addr, u16)) {
+        let mut health = self.health.write().unwrap();
+        let entry = health.entry(upstream).or_insert_with(UpstreamHealth::new);
+
+        entry.consecutive_failures += 1;
+        if entry.consecutive_failures >= MAX_CONSECUTIVE_FAILURES {
+            warn!("marking upstream {}:{} down", upstream.0, upstream.1);
+            entry.down_until = Some(Instant::now() + HOLD_DOWN);
+        }
+    }
+}
@@ -0,0 +1,274 @@
+// Static host names in /etc/hosts format: an address followed by its
+// canonical name and aliases, # starting a comment. Lines with an invalid
+// address are skipped like the C library does
+
+use std::collections::HashMap;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use log::{info, warn};
+
+use crate::models::{
+    dns_packet::DnsPacket, dns_question::DnsQuestion, dns_record::DnsRecord,
+    query_class::QueryClass, query_type::QueryType, result_code::ResultCode,
+};
+use crate::types::Result;
+
+// Answers change as soon as the file does, so they are not to be cached
+const HOSTS_TTL: u32 = 0;
+// How often the files are checked for changes
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Hosts {
+    // Addresses by lowercased name, in file order
+    addrs: HashMap<String, Vec<IpAddr>>,
+    // Canonical name of every address, from the first line listing it
+    names: HashMap<IpAddr, String>,
+}
+
+#[derive(Debug)]
+struct LoadedHosts {
+    hosts: Hosts,
+    modified: Vec<Option<SystemTime>>,
+}
+
+// Hosts files the server answers from, read again once any of them changes
+// on disk
+#[derive(Debug)]
+pub struct HostsFiles {
+    paths: Vec<PathBuf>,
+    loaded: RwLock<LoadedHosts>,
+    checked_at: Mutex<Instant>,
+}
+
+impl Hosts {
+    pub fn new() -> Hosts {
+        Hosts::default()
+    }
+
+    pub fn from_file(path: &Path) -> Result<Hosts> {
+        load(&[path.to_path_buf()])
+    }
+
+    pub fn parse(data: &str) -> Hosts {
+        let mut hosts = Hosts::new();
+        hosts.extend(data);
+        hosts
+    }
+
+    // Adds the entries of another hosts file, earlier entries taking
+    // precedence for reverse lookups
+    pub fn extend(&mut self, data: &str) {
+        for line in data.lines() {
+            let line = match line.find('#') {
+                Some(pos) => &line[..pos],
+                None => line,
+            };
+
+            let mut tokens = line.split_whitespace();
+            let addr: IpAddr = match tokens.next().map(|token| token.parse()) {
+                Some(Ok(x)) => x,
+                _ => continue,
+            };
+
+            for (idx, name) in tokens.enumerate() {
+                let name = name.trim_end_matches('.').to_ascii_lowercase();
+                if idx == 0 {
+                    self.names.entry(addr).or_insert_with(|| name.clone());
+                }
+
+                let addrs = self.addrs.entry(name).or_default();
+                if !addrs.contains(&addr) {
+                    addrs.push(addr);
+                }
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.addrs.is_empty()
+    }
+
+    pub fn lookup(&self, name: &str) -> &[IpAddr] {
+        self.addrs
+            .get(&name.trim_end_matches('.').to_ascii_lowercase())
+            .map(|addrs| addrs.as_slice())
+            .unwrap_or_default()
+    }
+
+    pub fn reverse(&self, addr: IpAddr) -> Option<&str> {
+        self.names.get(&addr).map(|name| name.as_str())
+    }
+
+    // Answers A and AAAA queries for listed names, empty when only addresses
+    // of the other family are listed, and PTR queries for listed addresses
+    pub fn answer(&self, qname: &str, qtype: QueryType, qclass: QueryClass) -> Option<DnsPacket> {
+        if qclass != QueryClass::IN {
+            return None;
+        }
+
+        let domain = qname.trim_end_matches('.').to_string();
+        let answers: Vec<DnsRecord> = match qtype {
+            QueryType::A | QueryType::AAAA => {
+                let addrs = self.lookup(qname);
+                if addrs.is_empty() {
+                    return None;
+                }
+
+                addrs
+                    .iter()
+                    .filter_map(|addr| match (addr, qtype) {
+                        (IpAddr::V4(addr), QueryType::A) => Some(DnsRecord::A {
+                            domain: domain.clone(),
+                            ip_v4_addr: *addr,
+                            ttl: HOSTS_TTL,
+                        }),
+                        (IpAddr::V6(addr), QueryType::AAAA) => Some(DnsRecord::AAAA {
+                            domain: domain.clone(),
+                            ip_v6_addr: *addr,
+                            ttl: HOSTS_TTL,
+                        }),
+                        _ => None,
+                    })
+                    .collect()
+            }
+            QueryType::PTR => {
+                let host = self.reverse(reverse_name_addr(qname)?)?;
+                vec![DnsRecord::PTR {
+                    domain: domain.clone(),
+                    host: host.to_string(),
+                    ttl: HOSTS_TTL,
+                }]
+            }
+            _ => return None,
+        };
+
+        let mut packet = DnsPacket::new();
+        packet.header.is_response = true;
+        packet.header.authoritative_answer = true;
+        packet.header.result_code = ResultCode::NOERROR;
+        packet
+            .questions
+            .push(DnsQuestion::new(domain, qtype, qclass));
+        packet.answers = answers;
+
+        Some(packet)
+    }
+}
+
+impl HostsFiles {
+    pub fn new(paths: Vec<PathBuf>) -> Result<HostsFiles> {
+        let modified = modified_times(&paths);
+        let hosts = load(&paths)?;
+
+        Ok(HostsFiles {
+            paths,
+            loaded: RwLock::new(LoadedHosts { hosts, modified }),
+            checked_at: Mutex::new(Instant::now()),
+        })
+    }
+
+    pub fn answer(&self, qname: &str, qtype: QueryType, qclass: QueryClass) -> Option<DnsPacket> {
+        if self.paths.is_empty() {
+            return None;
+        }
+
+        self.reload_if_changed();
+
+        self.loaded
+            .read()
+            .unwrap()
+            .hosts
+            .answer(qname, qtype, qclass)
+    }
+
+    // A file that cannot be read, say while being replaced, leaves the
+    // previous entries in place until it changes again
+    fn reload_if_changed(&self) {
+        {
+            let mut checked_at = self.checked_at.lock().unwrap();
+            if checked_at.elapsed() < RELOAD_CHECK_INTERVAL {
+                return;
+            }
+            *checked_at = Instant::now();
+        }
+
+        let modified = modified_times(&self.paths);
+        if self.loaded.read().unwrap().modified == modified {
+            return;
+        }
+
+        let mut loaded = self.loaded.write().unwrap();
+        loaded.modified = modified;
+        match load(&self.paths) {
+            Ok(hosts) => {
+                info!("reloaded hosts files {:?}", self.paths);
+                loaded.hosts = hosts;
+            }
+            Err(e) => warn!("Failed to reload hosts files: {}", e),
+        }
+    }
+}
+
+fn load(paths: &[PathBuf]) -> Result<Hosts> {
+    let mut hosts = Hosts::new();
+    for path in paths {
+        let data = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read hosts file {}: {}", path.display(), e))?;
+        hosts.extend(&data);
+    }
+
+    Ok(hosts)
+}
+
+fn modified_times(paths: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    paths
+        .iter()
+        .map(|path| fs::metadata(path).and_then(|meta| meta.modified()).ok())
+        .collect()
+}
+
+// Address named by a reverse lookup name under in-addr.arpa (RFC 1035 3.5)
+// or ip6.arpa (RFC 3596 2.5)
+fn reverse_name_addr(name: &str) -> Option<IpAddr> {
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+
+    if let Some(labels) = name.strip_suffix(".in-addr.arpa") {
+        let mut octets: Vec<u8> = labels
+            .split('.')
+            .map(|label| label.parse().ok())
+            .collect::<Option<_>>()?;
+        if octets.len() != 4 {
+            return None;
+        }
+        octets.reverse();
+
+        return Some(IpAddr::V4(Ipv4Addr::new(
+            octets[0], octets[1], octets[2], octets[3],
+        )));
+    }
+
+    let labels = name.strip_suffix(".ip6.arpa")?;
+    let nibbles: Vec<u8> = labels
+        .split('.')
+        .rev()
+        .map(|label| match label.len() {
+            1 => u8::from_str_radix(label, 16).ok(),
+            _ => None,
+        })
+        .collect::<Option<_>>()?;
+    if nibbles.len() != 32 {
+        return None;
+    }
+
+    let mut octets = [0u8; 16];
+    for (idx, pair) in nibbles.chunks(2).enumerate() {
+        octets[idx] = (pair[0] << 4) | pair[1];
+    }
+
+    Some(IpAddr::V6(Ipv6Addr::from(octets)))
+}
@@ -0,0 +1,681 @@
+// HPACK header compression for HTTP/2 (RFC 7541). Requests are encoded with
+// literals only, responses may use everything the format offers
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::OnceLock;
+
+use crate::types::Result;
+
+// SETTINGS_HEADER_TABLE_SIZE is left at its initial value (RFC 9113 6.5.2)
+const MAX_TABLE_SIZE: usize = 4096;
+// Every dynamic table entry counts for 32 bytes on top of its name and value
+const ENTRY_OVERHEAD: usize = 32;
+// Symbol of the end of string marker, which must not occur in a string
+const EOS: u16 = 256;
+
+// Static table (RFC 7541 Appendix A), index 1 being the first entry
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+// Code and length in bits of every symbol (RFC 7541 Appendix B)
+const HUFFMAN_CODES: [(u32, u8); 257] = [
+    (0x1ff8, 13),
+    (0x7fffd8, 23),
+    (0xfffffe2, 28),
+    (0xfffffe3, 28),
+    (0xfffffe4, 28),
+    (0xfffffe5, 28),
+    (0xfffffe6, 28),
+    (0xfffffe7, 28),
+    (0xfffffe8, 28),
+    (0xffffea, 24),
+    (0x3ffffffc, 30),
+    (0xfffffe9, 28),
+    (0xfffffea, 28),
+    (0x3ffffffd, 30),
+    (0xfffffeb, 28),
+    (0xfffffec, 28),
+    (0xfffffed, 28),
+    (0xfffffee, 28),
+    (0xfffffef, 28),
+    (0xffffff0, 28),
+    (0xffffff1, 28),
+    (0xffffff2, 28),
+    (0x3ffffffe, 30),
+    (0xffffff3, 28),
+    (0xffffff4, 28),
+    (0xffffff5, 28),
+    (0xffffff6, 28),
+    (0xffffff7, 28),
+    (0xffffff8, 28),
+    (0xffffff9, 28),
+    (0xffffffa, 28),
+    (0xffffffb, 28),
+    (0x14, 6),
+    (0x3f8, 10),
+    (0x3f9, 10),
+    (0xffa, 12),
+    (0x1ff9, 13),
+    (0x15, 6),
+    (0xf8, 8),
+    (0x7fa, 11),
+    (0x3fa, 10),
+    (0x3fb, 10),
+    (0xf9, 8),
+    (0x7fb, 11),
+    (0xfa, 8),
+    (0x16, 6),
+    (0x17, 6),
+    (0x18, 6),
+    (0x0, 5),
+    (0x1, 5),
+    (0x2, 5),
+    (0x19, 6),
+    (0x1a, 6),
+    (0x1b, 6),
+    (0x1c, 6),
+    (0x1d, 6),
+    (0x1e, 6),
+    (0x1f, 6),
+    (0x5c, 7),
+    (0xfb, 8),
+    (0x7ffc, 15),
+    (0x20, 6),
+    (0xffb, 12),
+    (0x3fc, 10),
+    (0x1ffa, 13),
+    (0x21, 6),
+    (0x5d, 7),
+    (0x5e, 7),
+    (0x5f, 7),
+    (0x60, 7),
+    (0x61, 7),
+    (0x62, 7),
+    (0x63, 7),
+    (0x64, 7),
+    (0x65, 7),
+    (0x66, 7),
+    (0x67, 7),
+    (0x68, 7),
+    (0x69, 7),
+    (0x6a, 7),
+    (0x6b, 7),
+    (0x6c, 7),
+    (0x6d, 7),
+    (0x6e, 7),
+    (0x6f, 7),
+    (0x70, 7),
+    (0x71, 7),
+    (0x72, 7),
+    (0xfc, 8),
+    (0x73, 7),
+    (0xfd, 8),
+    (0x1ffb, 13),
+    (0x7fff0, 19),
+    (0x1ffc, 13),
+    (0x3ffc, 14),
+    (0x22, 6),
+    (0x7ffd, 15),
+    (0x3, 5),
+    (0x23, 6),
+    (0x4, 5),
+    (0x24, 6),
+    (0x5, 5),
+    (0x25, 6),
+    (0x26, 6),
+    (0x27, 6),
+    (0x6, 5),
+    (0x74, 7),
+    (0x75, 7),
+    (0x28, 6),
+    (0x29, 6),
+    (0x2a, 6),
+    (0x7, 5),
+    (0x2b, 6),
+    (0x76, 7),
+    (0x2c, 6),
+    (0x8, 5),
+    (0x9, 5),
+    (0x2d, 6),
+    (0x77, 7),
+    (0x78, 7),
+    (0x79, 7),
+    (0x7a, 7),
+    (0x7b, 7),
+    (0x7ffe, 15),
+    (0x7fc, 11),
+    (0x3ffd, 14),
+    (0x1ffd, 13),
+    (0xffffffc, 28),
+    (0xfffe6, 20),
+    (0x3fffd2, 22),
+    (0xfffe7, 20),
+    (0xfffe8, 20),
+    (0x3fffd3, 22),
+    (0x3fffd4, 22),
+    (0x3fffd5, 22),
+    (0x7fffd9, 23),
+    (0x3fffd6, 22),
+    (0x7fffda, 23),
+    (0x7fffdb, 23),
+    (0x7fffdc, 23),
+    (0x7fffdd, 23),
+    (0x7fffde, 23),
+    (0xffffeb, 24),
+    (0x7fffdf, 23),
+    (0xffffec, 24),
+    (0xffffed, 24),
+    (0x3fffd7, 22),
+    (0x7fffe0, 23),
+    (0xffffee, 24),
+    (0x7fffe1, 23),
+    (0x7fffe2, 23),
+    (0x7fffe3, 23),
+    (0x7fffe4, 23),
+    (0x1fffdc, 21),
+    (0x3fffd8, 22),
+    (0x7fffe5, 23),
+    (0x3fffd9, 22),
+    (0x7fffe6, 23),
+    (0x7fffe7, 23),
+    (0xffffef, 24),
+    (0x3fffda, 22),
+    (0x1fffdd, 21),
+    (0xfffe9, 20),
+    (0x3fffdb, 22),
+    (0x3fffdc, 22),
+    (0x7fffe8, 23),
+    (0x7fffe9, 23),
+    (0x1fffde, 21),
+    (0x7fffea, 23),
+    (0x3fffdd, 22),
+    (0x3fffde, 22),
+    (0xfffff0, 24),
+    (0x1fffdf, 21),
+    (0x3fffdf, 22),
+    (0x7fffeb, 23),
+    (0x7fffec, 23),
+    (0x1fffe0, 21),
+    (0x1fffe1, 21),
+    (0x3fffe0, 22),
+    (0x1fffe2, 21),
+    (0x7fffed, 23),
+    (0x3fffe1, 22),
+    (0x7fffee, 23),
+    (0x7fffef, 23),
+    (0xfffea, 20),
+    (0x3fffe2, 22),
+    (0x3fffe3, 22),
+    (0x3fffe4, 22),
+    (0x7ffff0, 23),
+    (0x3fffe5, 22),
+    (0x3fffe6, 22),
+    (0x7ffff1, 23),
+    (0x3ffffe0, 26),
+    (0x3ffffe1, 26),
+    (0xfffeb, 20),
+    (0x7fff1, 19),
+    (0x3fffe7, 22),
+    (0x7ffff2, 23),
+    (0x3fffe8, 22),
+    (0x1ffffec, 25),
+    (0x3ffffe2, 26),
+    (0x3ffffe3, 26),
+    (0x3ffffe4, 26),
+    (0x7ffffde, 27),
+    (0x7ffffdf, 27),
+    (0x3ffffe5, 26),
+    (0xfffff1, 24),
+    (0x1ffffed, 25),
+    (0x7fff2, 19),
+    (0x1fffe3, 21),
+    (0x3ffffe6, 26),
+    (0x7ffffe0, 27),
+    (0x7ffffe1, 27),
+    (0x3ffffe7, 26),
+    (0x7ffffe2, 27),
+    (0xfffff2, 24),
+    (0x1fffe4, 21),
+    (0x1fffe5, 21),
+    (0x3ffffe8, 26),
+    (0x3ffffe9, 26),
+    (0xffffffd, 28),
+    (0x7ffffe3, 27),
+    (0x7ffffe4, 27),
+    (0x7ffffe5, 27),
+    (0xfffec, 20),
+    (0xfffff3, 24),
+    (0xfffed, 20),
+    (0x1fffe6, 21),
+    (0x3fffe9, 22),
+    (0x1fffe7, 21),
+    (0x1fffe8, 21),
+    (0x7ffff3, 23),
+    (0x3fffea, 22),
+    (0x3fffeb, 22),
+    (0x1ffffee, 25),
+    (0x1ffffef, 25),
+    (0xfffff4, 24),
+    (0xfffff5, 24),
+    (0x3ffffea, 26),
+    (0x7ffff4, 23),
+    (0x3ffffeb, 26),
+    (0x7ffffe6, 27),
+    (0x3ffffec, 26),
+    (0x3ffffed, 26),
+    (0x7ffffe7, 27),
+    (0x7ffffe8, 27),
+    (0x7ffffe9, 27),
+    (0x7ffffea, 27),
+    (0x7ffffeb, 27),
+    (0xffffffe, 28),
+    (0x7ffffec, 27),
+    (0x7ffffed, 27),
+    (0x7ffffee, 27),
+    (0x7ffffef, 27),
+    (0x7fffff0, 27),
+    (0x3ffffee, 26),
+    (0x3fffffff, 30),
+];
+
+pub type Header = (Vec<u8>, Vec<u8>);
+
+// Headers are sent as literals without indexing and without Huffman coding,
+// so that requests never depend on the state of the dynamic table
+pub fn encode(headers: &[(&str, &str)]) -> Vec<u8> {
+    let mut output = Vec::new();
+
+    for (name, value) in headers {
+        output.push(0);
+        encode_integer(&mut output, 0, 7, name.len());
+        output.extend_from_slice(name.as_bytes());
+        encode_integer(&mut output, 0, 7, value.len());
+        output.extend_from_slice(value.as_bytes());
+    }
+
+    output
+}
+
+// Integer with an n bit prefix (RFC 7541 5.1)
+fn encode_integer(output: &mut Vec<u8>, first: u8, prefix_bits: u32, mut value: usize) {
+    let max_prefix = (1 << prefix_bits) - 1;
+
+    if value < max_prefix {
+        output.push(first | value as u8);
+        return;
+    }
+
+    output.push(first | max_prefix as u8);
+    value -= max_prefix;
+    while value >= 128 {
+        output.push((value % 128) as u8 | 0x80);
+        value /= 128;
+    }
+    output.push(value as u8);
+}
+
+// Decodes the header blocks of a connection in the order they were received,
+// as every block may change the dynamic table the next ones refer to
+#[derive(Debug)]
+pub struct Decoder {
+    // Most recently added entries first
+    table: VecDeque<Header>,
+    size: usize,
+    max_size: usize,
+}
+
+impl Decoder {
+    pub fn new() -> Decoder {
+        Decoder {
+            table: VecDeque::new(),
+            size: 0,
+            max_size: MAX_TABLE_SIZE,
+        }
+    }
+
+    pub fn decode(&mut self, mut block: &[u8]) -> Result<Vec<Header>> {
+        let mut headers = Vec::new();
+
+        while let Some(&first) = block.first() {
+            match first {
+                // Indexed header field (RFC 7541 6.1)
+                _ if first & 0x80 != 0 => {
+                    let index = decode_integer(&mut block, 7)?;
+                    headers.push(self.entry(index)?);
+                }
+                // Literal with incremental indexing (RFC 7541 6.2.1)
+                _ if first & 0x40 != 0 => {
+                    let header = self.literal(&mut block, 6)?;
+                    self.insert(header.clone());
+                    headers.push(header);
+                }
+                // Dynamic table size update (RFC 7541 6.3)
+                _ if first & 0x20 != 0 => {
+                    let size = decode_integer(&mut block, 5)?;
+                    if size > MAX_TABLE_SIZE {
+                        return Err(format!("HPACK table size {} above the limit", size).into());
+                    }
+                    self.max_size = size;
+                    self.evict(0);
+                }
+                // Literal without indexing or never indexed (RFC 7541 6.2.2, 6.2.3)
+                _ => headers.push(self.literal(&mut block, 4)?),
+            }
+        }
+
+        Ok(headers)
+    }
+
+    fn entry(&self, index: usize) -> Result<Header> {
+        let entry = match index {
+            0 => None,
+            1..=61 => {
+                let (name, value) = STATIC_TABLE[index - 1];
+                Some((name.as_bytes().to_vec(), value.as_bytes().to_vec()))
+            }
+            _ => self.table.get(index - 62).cloned(),
+        };
+
+        entry.ok_or_else(|| format!("HPACK index {} out of range", index).into())
+    }
+
+    // A literal field whose name is either indexed or follows as a string
+    fn literal(&self, block: &mut &[u8], prefix_bits: u32) -> Result<Header> {
+        let name = match decode_integer(block, prefix_bits)? {
+            0 => decode_string(block)?,
+            index => self.entry(index)?.0,
+        };
+        let value = decode_string(block)?;
+
+        Ok((name, value))
+    }
+
+    // Entries larger than the whole table empty it without being added
+    // (RFC 7541 4.4)
+    fn insert(&mut self, header: Header) {
+        let size = header.0.len() + header.1.len() + ENTRY_OVERHEAD;
+        self.evict(size);
+
+        if size <= self.max_size {
+            self.size += size;
+            self.table.push_front(header);
+        }
+    }
+
+    // Drops the oldest entries until there is room for an entry of the size
+    fn evict(&mut self, room: usize) {
+        while self.size + room > self.max_size {
+            let Some((name, value)) = self.table.pop_back() else {
+                break;
+            };
+            self.size -= name.len() + value.len() + ENTRY_OVERHEAD;
+        }
+    }
+}
+
+impl Default for Decoder {
+    fn default() -> Decoder {
+        Decoder::new()
+    }
+}
+
+fn decode_integer(block: &mut &[u8], prefix_bits: u32) -> Result<usize> {
+    let max_prefix = (1 << prefix_bits) - 1;
+    let (&first, rest) = block.split_first().ok_or("Truncated HPACK integer")?;
+    *block = rest;
+
+    let mut value = (first & max_prefix) as usize;
+    if value < max_prefix as usize {
+        return Ok(value);
+    }
+
+    // Anything beyond 28 bits is way past any length or index a peer
+    // could sensibly send
+    let mut shift = 0;
+    loop {
+        let (&byte, rest) = block.split_first().ok_or("Truncated HPACK integer")?;
+        *block = rest;
+
+        if shift > 21 {
+            return Err("HPACK integer too large".into());
+        }
+        value += ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+}
+
+// String literal, Huffman coded if the first bit is set (RFC 7541 5.2)
+fn decode_string(block: &mut &[u8]) -> Result<Vec<u8>> {
+    let huffman = block.first().ok_or("Truncated HPACK string")? & 0x80 != 0;
+    let len = decode_integer(block, 7)?;
+
+    if block.len() < len {
+        return Err("Truncated HPACK string".into());
+    }
+    let (data, rest) = block.split_at(len);
+    *block = rest;
+
+    match huffman {
+        true => huffman_decode(data),
+        false => Ok(data.to_vec()),
+    }
+}
+
+fn huffman_symbols() -> &'static HashMap<(u8, u32), u16> {
+    static SYMBOLS: OnceLock<HashMap<(u8, u32), u16>> = OnceLock::new();
+
+    SYMBOLS.get_or_init(|| {
+        HUFFMAN_CODES
+            .iter()
+            .enumerate()
+            .map(|(symbol, &(code, len))| ((len, code), symbol as u16))
+            .collect()
+    })
+}
+
+// The string is padded with the most significant bits of EOS, which are
+// all ones, to a whole number of bytes
+fn huffman_decode(data: &[u8]) -> Result<Vec<u8>> {
+    let symbols = huffman_symbols();
+    let mut output = Vec::with_capacity(data.len() * 8 / 5);
+    let mut code = 0u32;
+    let mut len = 0u8;
+
+    for byte in data {
+        for shift in (0..8).rev() {
+            code = (code << 1) | ((byte >> shift) & 1) as u32;
+            len += 1;
+
+            match symbols.get(&(len, code)) {
+                Some(&EOS) => return Err("EOS in a Huffman coded string".into()),
+                Some(&symbol) => {
+                    output.push(symbol as u8);
+                    code = 0;
+                    len = 0;
+                }
+                None if len >= 30 => return Err("Invalid Huffman code".into()),
+                None => {}
+            }
+        }
+    }
+
+    if len > 7 || code != (1 << len) - 1 {
+        return Err("Invalid Huffman padding".into());
+    }
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(data: &str) -> Vec<u8> {
+        let digits: Vec<u8> = data.bytes().filter(|c| !c.is_ascii_whitespace()).collect();
+        digits
+            .chunks(2)
+            .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).unwrap(), 16).unwrap())
+            .collect()
+    }
+
+    fn headers(pairs: &[(&str, &str)]) -> Vec<Header> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.as_bytes().to_vec(), value.as_bytes().to_vec()))
+            .collect()
+    }
+
+    // RFC 7541 C.4, requests with Huffman coding sharing one dynamic table
+    #[test]
+    fn decodes_huffman_coded_requests() {
+        let mut decoder = Decoder::new();
+
+        let first = decoder
+            .decode(&hex("8286 8441 8cf1 e3c2 e5f2 3a6b a0ab 90f4 ff"))
+            .unwrap();
+        assert_eq!(
+            first,
+            headers(&[
+                (":method", "GET"),
+                (":scheme", "http"),
+                (":path", "/"),
+                (":authority", "www.example.com"),
+            ])
+        );
+        assert_eq!(decoder.size, 57);
+
+        let second = decoder
+            .decode(&hex("8286 84be 5886 a8eb 1064 9cbf"))
+            .unwrap();
+        assert_eq!(
+            second,
+            headers(&[
+                (":method", "GET"),
+                (":scheme", "http"),
+                (":path", "/"),
+                (":authority", "www.example.com"),
+                ("cache-control", "no-cache"),
+            ])
+        );
+
+        let third = decoder
+            .decode(&hex(
+                "8287 85bf 4088 25a8 49e9 5ba9 7d7f 8925 a849 e95b b8e8 b4bf",
+            ))
+            .unwrap();
+        assert_eq!(
+            third,
+            headers(&[
+                (":method", "GET"),
+                (":scheme", "https"),
+                (":path", "/index.html"),
+                (":authority", "www.example.com"),
+                ("custom-key", "custom-value"),
+            ])
+        );
+        assert_eq!(decoder.size, 164);
+    }
+
+    #[test]
+    fn decodes_what_is_encoded() {
+        let sent = [
+            (":status", "200"),
+            ("content-type", "application/dns-message"),
+        ];
+
+        let decoded = Decoder::new().decode(&encode(&sent)).unwrap();
+
+        assert_eq!(decoded, headers(&sent));
+    }
+
+    #[test]
+    fn evicts_entries_after_a_table_size_update() {
+        let mut decoder = Decoder::new();
+        decoder
+            .decode(&hex("8286 8441 8cf1 e3c2 e5f2 3a6b a0ab 90f4 ff"))
+            .unwrap();
+
+        // Shrinking the table to nothing drops the authority added above
+        decoder.decode(&hex("20")).unwrap();
+        assert_eq!(decoder.size, 0);
+        assert!(decoder.decode(&hex("be")).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_blocks() {
+        // Index 0, an index past the tables, a truncated string and a table
+        // size above the limit
+        for block in ["80", "ff00", "0085 6162", "3fe2 1f"] {
+            assert!(Decoder::new().decode(&hex(block)).is_err(), "{}", block);
+        }
+
+        // Padding longer than 7 bits and padding with a zero bit
+        assert!(huffman_decode(&hex("ffff")).is_err());
+        assert!(huffman_decode(&hex("1e")).is_err());
+    }
+}
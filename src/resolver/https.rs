@@ -0,0 +1,913 @@
+// DNS over HTTPS (RFC 8484) on HTTP/2 (RFC 9113). Queries to an upstream share
+// one connection, each on a stream of its own, and a reader thread hands the
+// response bodies back by stream ID. Only as much of HTTP/2 as a client making
+// small requests needs is implemented
+
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::debug;
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, RootCertStore};
+
+use super::hpack::{self, Decoder};
+use super::pool::{Connection, ConnectionPool};
+use super::tls::{client_config, read_chunk, TlsAuth, TlsSession};
+use super::transport::{check_reply, remaining};
+use crate::models::dns_packet::DnsPacket;
+use crate::types::Result;
+use crate::utils::byte_packet_buffer::{BytePacketBuffer, MAX_TCP_SIZE};
+use crate::utils::encoding::base64url_encode;
+
+pub const DEFAULT_HTTPS_PORT: u16 = 443;
+const DNS_MESSAGE_TYPE: &str = "application/dns-message";
+
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+const FRAME_HEADER_SIZE: usize = 9;
+// Neither side may send larger frames before the peer raised the limit
+const MAX_FRAME_SIZE: usize = 16_384;
+// Stream IDs are 31 bits, a connection that used them up is replaced
+const MAX_STREAM_ID: u32 = (1 << 31) - 1;
+
+const FRAME_DATA: u8 = 0x0;
+const FRAME_HEADERS: u8 = 0x1;
+const FRAME_RST_STREAM: u8 = 0x3;
+const FRAME_SETTINGS: u8 = 0x4;
+const FRAME_PING: u8 = 0x6;
+const FRAME_GOAWAY: u8 = 0x7;
+const FRAME_WINDOW_UPDATE: u8 = 0x8;
+const FRAME_CONTINUATION: u8 = 0x9;
+
+const FLAG_END_STREAM: u8 = 0x1;
+const FLAG_ACK: u8 = 0x1;
+const FLAG_END_HEADERS: u8 = 0x4;
+const FLAG_PADDED: u8 = 0x8;
+const FLAG_PRIORITY: u8 = 0x20;
+
+const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+const ERROR_REFUSED_STREAM: u32 = 0x7;
+const ERROR_CANCEL: u32 = 0x8;
+
+// Acknowledgements and other frames written by the reader thread
+const CONTROL_WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HttpsMethod {
+    Get,
+    Post,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpsUpstreamConfig {
+    pub server: (Ipv4Addr, u16),
+    pub authority: String,
+    pub path: String,
+    pub method: HttpsMethod,
+}
+
+impl HttpsUpstreamConfig {
+    // The URL is either a plain https URL, queried with POST, or a URI
+    // template ending in {?dns}, queried with GET (RFC 8484 3)
+    pub fn from_url(server: (Ipv4Addr, u16), url: &str) -> Result<HttpsUpstreamConfig> {
+        let rest = url
+            .strip_prefix("https://")
+            .ok_or_else(|| format!("Expected an https URL, got {}", url))?;
+
+        let (authority, path) = match rest.find('/') {
+            Some(index) => rest.split_at(index),
+            None => (rest, "/"),
+        };
+
+        if authority.is_empty() {
+            return Err(format!("Missing host in {}", url).into());
+        }
+
+        let (path, method) = match path.strip_suffix("{?dns}") {
+            Some(path) => (path, HttpsMethod::Get),
+            None => (path, HttpsMethod::Post),
+        };
+
+        Ok(HttpsUpstreamConfig {
+            server,
+            authority: authority.to_string(),
+            path: path.to_string(),
+            method,
+        })
+    }
+
+    fn host(&self) -> &str {
+        match self.authority.rsplit_once(':') {
+            Some((host, port)) if port.parse::<u16>().is_ok() => host,
+            _ => &self.authority,
+        }
+    }
+}
+
+fn frame(frame_type: u8, flags: u8, stream_id: u32, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+    frame.push(frame_type);
+    frame.push(flags);
+    frame.extend_from_slice(&stream_id.to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+// Strips the padding of a DATA or HEADERS frame, and the priority fields
+// HEADERS may carry
+fn frame_contents(flags: u8, mut payload: &[u8], has_priority: bool) -> Result<&[u8]> {
+    if flags & FLAG_PADDED != 0 {
+        let padding = *payload.first().ok_or("Malformed padded frame")? as usize;
+        payload = payload
+            .get(1..payload.len().saturating_sub(padding))
+            .ok_or("Malformed padded frame")?;
+    }
+
+    if has_priority && flags & FLAG_PRIORITY != 0 {
+        payload = payload.get(5..).ok_or("Malformed HEADERS frame")?;
+    }
+
+    Ok(payload)
+}
+
+#[derive(Default)]
+struct StreamState {
+    status: Option<String>,
+    content_type: Option<String>,
+    body: Vec<u8>,
+}
+
+enum Reply {
+    Body(Vec<u8>),
+    Failed(String),
+    // The server did not process the request, so it is safe to send it once
+    // more (RFC 9113 8.7)
+    Refused,
+}
+
+struct HttpsConnection {
+    session: TlsSession,
+    // Held while a request is written, as stream IDs have to be opened in
+    // increasing order
+    next_stream_id: Mutex<u32>,
+    pending: Mutex<HashMap<u32, Sender<Reply>>>,
+    closed: AtomicBool,
+}
+
+impl HttpsConnection {
+    fn connect(
+        config: Arc<ClientConfig>,
+        server_name: ServerName<'static>,
+        server: SocketAddr,
+        deadline: Instant,
+    ) -> Result<Arc<HttpsConnection>> {
+        let (session, reader) = TlsSession::connect(config, server_name, server, deadline)?;
+
+        if session.alpn_protocol().as_deref() != Some(b"h2") {
+            session.shutdown();
+            return Err(format!("{} does not support HTTP/2", server).into());
+        }
+
+        let mut settings = SETTINGS_ENABLE_PUSH.to_be_bytes().to_vec();
+        settings.extend_from_slice(&0u32.to_be_bytes());
+
+        let mut preface = PREFACE.to_vec();
+        preface.extend(frame(FRAME_SETTINGS, 0, 0, &settings));
+        session.write(&preface, deadline)?;
+
+        let connection = Arc::new(HttpsConnection {
+            session,
+            next_stream_id: Mutex::new(1),
+            pending: Mutex::new(HashMap::new()),
+            closed: AtomicBool::new(false),
+        });
+
+        let shared = connection.clone();
+        thread::spawn(move || {
+            if let Err(e) = shared.read_responses(reader) {
+                debug!("HTTPS connection to {} failed: {}", server, e);
+            }
+            shared.close();
+        });
+
+        Ok(connection)
+    }
+
+    // Fails every outstanding request, their receivers see the sender dropped.
+    // The connection may already be marked closed after a GOAWAY
+    fn close(&self) {
+        let mut pending = self.pending.lock().unwrap();
+        self.closed.store(true, Ordering::Release);
+        pending.clear();
+        drop(pending);
+
+        self.session.shutdown();
+    }
+
+    fn close_if_idle(&self) -> bool {
+        let pending = self.pending.lock().unwrap();
+        if !pending.is_empty() {
+            return false;
+        }
+        self.closed.store(true, Ordering::Release);
+        drop(pending);
+
+        let _ = self.write_control(&frame(FRAME_GOAWAY, 0, 0, &[0; 8]));
+        self.session.shutdown();
+        true
+    }
+
+    fn write_control(&self, frames: &[u8]) -> Result<()> {
+        self.session
+            .write(frames, Instant::now() + CONTROL_WRITE_TIMEOUT)
+    }
+
+    fn send(
+        &self,
+        upstream: &HttpsUpstreamConfig,
+        message: &[u8],
+        deadline: Instant,
+    ) -> Result<(u32, Receiver<Reply>)> {
+        let (sender, receiver) = mpsc::channel();
+        let mut next_stream_id = self.next_stream_id.lock().unwrap();
+        let stream_id = *next_stream_id;
+
+        {
+            let mut pending = self.pending.lock().unwrap();
+            if self.is_closed() || stream_id > MAX_STREAM_ID {
+                self.closed.store(true, Ordering::Release);
+                return Err("HTTPS connection closed".into());
+            }
+            pending.insert(stream_id, sender);
+        }
+        *next_stream_id += 2;
+
+        let content_length = message.len().to_string();
+        let (method, path) = match upstream.method {
+            HttpsMethod::Get => {
+                let separator = if upstream.path.contains('?') {
+                    '&'
+                } else {
+                    '?'
+                };
+                let path = format!(
+                    "{}{}dns={}",
+                    upstream.path,
+                    separator,
+                    base64url_encode(message)
+                );
+                ("GET", path)
+            }
+            HttpsMethod::Post => ("POST", upstream.path.clone()),
+        };
+
+        // Pseudo-header fields have to come first (RFC 9113 8.3)
+        let mut headers = vec![
+            (":method", method),
+            (":scheme", "https"),
+            (":authority", upstream.authority.as_str()),
+            (":path", path.as_str()),
+            ("accept", DNS_MESSAGE_TYPE),
+        ];
+        if upstream.method == HttpsMethod::Post {
+            headers.push(("content-type", DNS_MESSAGE_TYPE));
+            headers.push(("content-length", &content_length));
+        }
+
+        let block = hpack::encode(&headers);
+        let fragments: Vec<&[u8]> = block.chunks(MAX_FRAME_SIZE).collect();
+        let mut frames = Vec::new();
+        for (index, fragment) in fragments.iter().enumerate() {
+            let mut flags = 0;
+            if index + 1 == fragments.len() {
+                flags |= FLAG_END_HEADERS;
+            }
+
+            if index == 0 {
+                if upstream.method == HttpsMethod::Get {
+                    flags |= FLAG_END_STREAM;
+                }
+                frames.extend(frame(FRAME_HEADERS, flags, stream_id, fragment));
+            } else {
+                frames.extend(frame(FRAME_CONTINUATION, flags, stream_id, fragment));
+            }
+        }
+
+        // A query is well below the initial flow control window of 65535
+        // bytes, so the window the server advertises is not tracked
+        if upstream.method == HttpsMethod::Post {
+            let chunks: Vec<&[u8]> = message.chunks(MAX_FRAME_SIZE).collect();
+            for (index, chunk) in chunks.iter().enumerate() {
+                let flags = if index + 1 == chunks.len() {
+                    FLAG_END_STREAM
+                } else {
+                    0
+                };
+                frames.extend(frame(FRAME_DATA, flags, stream_id, chunk));
+            }
+        }
+
+        let result = self.session.write(&frames, deadline);
+        drop(next_stream_id);
+
+        if let Err(e) = result {
+            self.close();
+            return Err(e);
+        }
+
+        Ok((stream_id, receiver))
+    }
+
+    fn cancel(&self, stream_id: u32) {
+        if self.pending.lock().unwrap().remove(&stream_id).is_some() {
+            let reset = frame(FRAME_RST_STREAM, 0, stream_id, &ERROR_CANCEL.to_be_bytes());
+            let _ = self.write_control(&reset);
+        }
+    }
+
+    fn complete(&self, stream_id: u32, stream: StreamState) {
+        let Some(sender) = self.pending.lock().unwrap().remove(&stream_id) else {
+            return;
+        };
+
+        let reply = match (stream.status.as_deref(), stream.content_type.as_deref()) {
+            (Some("200"), Some(DNS_MESSAGE_TYPE)) => Reply::Body(stream.body),
+            (Some("200"), content_type) => Reply::Failed(format!(
+                "unexpected content type {}",
+                content_type.unwrap_or("(none)")
+            )),
+            (status, _) => Reply::Failed(format!("HTTP status {}", status.unwrap_or("(none)"))),
+        };
+        let _ = sender.send(reply);
+    }
+
+    fn fail(&self, stream_id: u32, reply: Reply) {
+        if let Some(sender) = self.pending.lock().unwrap().remove(&stream_id) {
+            let _ = sender.send(reply);
+        }
+    }
+
+    // Fails the request and tells the server to stop sending on its stream
+    fn reset(&self, stream_id: u32, reason: String) -> Result<()> {
+        self.fail(stream_id, Reply::Failed(reason));
+        self.write_control(&frame(
+            FRAME_RST_STREAM,
+            0,
+            stream_id,
+            &ERROR_CANCEL.to_be_bytes(),
+        ))
+    }
+
+    fn read_responses(&self, mut socket: TcpStream) -> Result<()> {
+        let mut decoder = Decoder::new();
+        let mut streams: HashMap<u32, StreamState> = HashMap::new();
+        // Header block of a HEADERS frame still waiting for CONTINUATION
+        let mut header_block: Option<(u32, u8, Vec<u8>)> = None;
+
+        let mut received = Vec::new();
+        let mut chunk = [0; 4096];
+
+        loop {
+            let size = match read_chunk(&mut socket, &mut chunk) {
+                Ok(Some(0)) => return Ok(()),
+                Ok(Some(size)) => size,
+                Ok(None) if self.close_if_idle() => return Ok(()),
+                Ok(None) => continue,
+                Err(_) if self.is_closed() => return Ok(()),
+                Err(e) => return Err(e),
+            };
+
+            if !self.session.receive(&chunk[..size], &mut received)? && received.is_empty() {
+                return Ok(());
+            }
+
+            while received.len() >= FRAME_HEADER_SIZE {
+                let len = u32::from_be_bytes([0, received[0], received[1], received[2]]) as usize;
+                if received.len() < FRAME_HEADER_SIZE + len {
+                    break;
+                }
+
+                let frame_type = received[3];
+                let flags = received[4];
+                let stream_id =
+                    u32::from_be_bytes([received[5], received[6], received[7], received[8]])
+                        & MAX_STREAM_ID;
+                let payload = received[FRAME_HEADER_SIZE..FRAME_HEADER_SIZE + len].to_vec();
+                received.drain(..FRAME_HEADER_SIZE + len);
+
+                // Nothing may come between a HEADERS frame and its
+                // CONTINUATION frames (RFC 9113 6.10)
+                if header_block.is_some() && frame_type != FRAME_CONTINUATION {
+                    return Err("Expected a CONTINUATION frame".into());
+                }
+
+                let mut end_headers = None;
+
+                match frame_type {
+                    FRAME_DATA => {
+                        let data = frame_contents(flags, &payload, false)?;
+                        let mut too_long = false;
+                        if let Some(stream) = streams.get_mut(&stream_id) {
+                            stream.body.extend_from_slice(data);
+                            too_long = stream.body.len() > MAX_TCP_SIZE;
+                        }
+
+                        // A DNS message is at most 65535 bytes long (RFC 1035
+                        // 4.2.2), there is no point in reading any further
+                        if too_long {
+                            streams.remove(&stream_id);
+                            self.reset(
+                                stream_id,
+                                format!("response body over {} bytes", MAX_TCP_SIZE),
+                            )?;
+                        }
+
+                        // Hand the flow control window back right away, for
+                        // the connection and the stream if it stays open
+                        if !payload.is_empty() {
+                            let increment = (payload.len() as u32).to_be_bytes();
+                            let mut update = frame(FRAME_WINDOW_UPDATE, 0, 0, &increment);
+                            if flags & FLAG_END_STREAM == 0 && !too_long {
+                                update.extend(frame(FRAME_WINDOW_UPDATE, 0, stream_id, &increment));
+                            }
+                            self.write_control(&update)?;
+                        }
+
+                        if flags & FLAG_END_STREAM != 0 {
+                            if let Some(stream) = streams.remove(&stream_id) {
+                                self.complete(stream_id, stream);
+                            }
+                        }
+                    }
+                    FRAME_HEADERS => {
+                        let fragment = frame_contents(flags, &payload, true)?.to_vec();
+                        if flags & FLAG_END_HEADERS != 0 {
+                            end_headers = Some((stream_id, flags, fragment));
+                        } else {
+                            header_block = Some((stream_id, flags, fragment));
+                        }
+                    }
+                    FRAME_CONTINUATION => {
+                        let (block_stream_id, block_flags, mut block) =
+                            header_block.take().ok_or("Unexpected CONTINUATION frame")?;
+                        if block_stream_id != stream_id {
+                            return Err("CONTINUATION frame for another stream".into());
+                        }
+
+                        block.extend_from_slice(&payload);
+                        if flags & FLAG_END_HEADERS != 0 {
+                            end_headers = Some((stream_id, block_flags, block));
+                        } else {
+                            header_block = Some((stream_id, block_flags, block));
+                        }
+                    }
+                    FRAME_RST_STREAM => {
+                        streams.remove(&stream_id);
+                        let code = payload
+                            .get(0..4)
+                            .map(|x| u32::from_be_bytes([x[0], x[1], x[2], x[3]]))
+                            .unwrap_or_default();
+                        let reply = match code {
+                            ERROR_REFUSED_STREAM => Reply::Refused,
+                            _ => Reply::Failed(format!("stream reset with error {}", code)),
+                        };
+                        self.fail(stream_id, reply);
+                    }
+                    FRAME_SETTINGS if flags & FLAG_ACK == 0 => {
+                        self.write_control(&frame(FRAME_SETTINGS, FLAG_ACK, 0, &[]))?;
+                    }
+                    FRAME_PING if flags & FLAG_ACK == 0 => {
+                        self.write_control(&frame(FRAME_PING, FLAG_ACK, 0, &payload))?;
+                    }
+                    // Streams above the last one the server processed never
+                    // will be, those below are still answered
+                    FRAME_GOAWAY => {
+                        let last_stream_id = payload
+                            .get(0..4)
+                            .map(|x| u32::from_be_bytes([x[0], x[1], x[2], x[3]]) & MAX_STREAM_ID)
+                            .unwrap_or_default();
+                        self.closed.store(true, Ordering::Release);
+
+                        let refused: Vec<u32> = self
+                            .pending
+                            .lock()
+                            .unwrap()
+                            .keys()
+                            .copied()
+                            .filter(|id| *id > last_stream_id)
+                            .collect();
+                        for id in refused {
+                            self.fail(id, Reply::Refused);
+                        }
+                    }
+                    _ => {}
+                }
+
+                if let Some((stream_id, flags, block)) = end_headers {
+                    // Every header block has to be decoded to keep the
+                    // dynamic table in sync, whichever stream it belongs to
+                    let headers = decoder
+                        .decode(&block)
+                        .map_err(|e| format!("Malformed header block: {}", e))?;
+
+                    if self.pending.lock().unwrap().contains_key(&stream_id) {
+                        let stream = streams.entry(stream_id).or_default();
+                        for (name, value) in headers {
+                            let value = String::from_utf8_lossy(&value).to_string();
+                            match name.as_slice() {
+                                // Informational responses precede the final one
+                                b":status" if !value.starts_with('1') => {
+                                    stream.status = Some(value)
+                                }
+                                b"content-type" => stream.content_type = Some(value),
+                                _ => {}
+                            }
+                        }
+
+                        if flags & FLAG_END_STREAM != 0 {
+                            if let Some(stream) = streams.remove(&stream_id) {
+                                self.complete(stream_id, stream);
+                            }
+                        }
+                    }
+                }
+            }
+
+            if self.is_closed() && self.pending.lock().unwrap().is_empty() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl Connection for HttpsConnection {
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+}
+
+struct HttpsUpstream {
+    config: HttpsUpstreamConfig,
+    tls_config: Arc<ClientConfig>,
+    server_name: ServerName<'static>,
+    pool: ConnectionPool<HttpsConnection>,
+}
+
+pub struct HttpsClient {
+    upstreams: HashMap<(Ipv4Addr, u16), HttpsUpstream>,
+}
+
+impl HttpsClient {
+    pub fn new(
+        upstreams: &[HttpsUpstreamConfig],
+        roots: &Arc<RootCertStore>,
+    ) -> Result<HttpsClient> {
+        let mut clients = HashMap::new();
+
+        for upstream in upstreams {
+            let auth = TlsAuth::Hostname(upstream.host().to_string());
+            let (tls_config, server_name) =
+                client_config(&auth, upstream.server.0, roots, &[b"h2"])?;
+
+            clients.insert(
+                upstream.server,
+                HttpsUpstream {
+                    config: upstream.clone(),
+                    tls_config,
+                    server_name,
+                    pool: ConnectionPool::new("HTTPS"),
+                },
+            );
+        }
+
+        Ok(HttpsClient { upstreams: clients })
+    }
+
+    pub fn is_https_upstream(&self, server: (Ipv4Addr, u16)) -> bool {
+        self.upstreams.contains_key(&server)
+    }
+
+    pub fn exchange(
+        &self,
+        request: &DnsPacket,
+        server: (Ipv4Addr, u16),
+        deadline: Instant,
+        exact_case: bool,
+    ) -> Result<DnsPacket> {
+        let upstream = self
+            .upstreams
+            .get(&server)
+            .ok_or_else(|| format!("No HTTPS settings for {}:{}", server.0, server.1))?;
+        let server = SocketAddr::from(server);
+
+        // The ID is redundant next to the stream and set to 0 so that the
+        // responses can be cached by HTTP caches (RFC 8484 4.1)
+        let mut request = request.clone();
+        request.header.id = 0;
+
+        let mut req_buffer = BytePacketBuffer::with_size(MAX_TCP_SIZE);
+        request.to_buffer(&mut req_buffer)?;
+        let message = &req_buffer.buf[0..req_buffer.pos];
+
+        let connect = || {
+            HttpsConnection::connect(
+                upstream.tls_config.clone(),
+                upstream.server_name.clone(),
+                server,
+                deadline,
+            )
+        };
+        // A request refused by the server, usually along with a GOAWAY
+        // closing the connection, is sent once more on a fresh one
+        let mut refused = false;
+        let body = loop {
+            let (connection, (stream_id, receiver)) =
+                upstream.pool.send(server, connect, |connection| {
+                    connection.send(&upstream.config, message, deadline)
+                })?;
+
+            match receiver.recv_timeout(remaining(deadline, server)?) {
+                Ok(Reply::Body(body)) => break body,
+                Ok(Reply::Refused) if !refused => refused = true,
+                Ok(Reply::Refused) => {
+                    return Err(format!("Request to {} refused twice", server).into())
+                }
+                Ok(Reply::Failed(e)) => {
+                    return Err(format!("Request to {} failed with {}", server, e).into())
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    connection.cancel(stream_id);
+                    return Err(format!("Timed out waiting for a reply from {}", server).into());
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(format!("HTTPS connection to {} closed", server).into())
+                }
+            }
+        };
+
+        let mut res_buffer = BytePacketBuffer::with_size(body.len());
+        res_buffer.buf.copy_from_slice(&body);
+        let response = DnsPacket::from_buffer(&mut res_buffer)?;
+
+        check_reply(&request, &response, exact_case)
+            .map_err(|e| format!("Reply from {} over HTTPS with {}", server, e))?;
+
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::sync::atomic::AtomicBool;
+
+    use super::*;
+    use crate::models::{
+        dns_question::DnsQuestion, dns_record::DnsRecord, query_class::QueryClass,
+        query_type::QueryType,
+    };
+    use crate::resolver::tls_stand_in::{
+        answer, authority, serve, StandIn, TlsStream, ANSWER_ADDR,
+    };
+
+    const SERVER_NAME: &str = "dns.test";
+
+    #[derive(Debug, Clone, Copy)]
+    enum Behavior {
+        Answer,
+        Status(&'static str),
+        ContentType(&'static str),
+        // The body comes in DATA frames of a few bytes each
+        Split,
+        // Only the first request on a connection is answered, the server
+        // then goes away
+        GoAway,
+        Oversized,
+    }
+
+    fn read_frame(stream: &mut TlsStream) -> Option<(u8, u8, u32, Vec<u8>)> {
+        let mut header = [0; FRAME_HEADER_SIZE];
+        stream.read_exact(&mut header).ok()?;
+
+        let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+        let stream_id = u32::from_be_bytes([header[5], header[6], header[7], header[8]]);
+        let mut payload = vec![0; len];
+        stream.read_exact(&mut payload).ok()?;
+
+        Some((header[3], header[4], stream_id, payload))
+    }
+
+    fn response(
+        stream_id: u32,
+        status: &str,
+        content_type: &str,
+        body: &[u8],
+        chunk: usize,
+    ) -> Vec<u8> {
+        let length = body.len().to_string();
+        let headers = hpack::encode(&[
+            (":status", status),
+            ("content-type", content_type),
+            ("content-length", &length),
+        ]);
+
+        let mut frames = frame(FRAME_HEADERS, FLAG_END_HEADERS, stream_id, &headers);
+        let chunks: Vec<&[u8]> = body.chunks(chunk).collect();
+        for (index, data) in chunks.iter().enumerate() {
+            let flags = if index + 1 == chunks.len() {
+                FLAG_END_STREAM
+            } else {
+                0
+            };
+            frames.extend(frame(FRAME_DATA, flags, stream_id, data));
+        }
+
+        frames
+    }
+
+    // Serves POST requests over HTTP/2, setting reset once the client resets
+    // a stream
+    fn serve_h2(mut stream: TlsStream, behavior: Behavior, reset: &AtomicBool) {
+        let mut preface = [0; PREFACE.len()];
+        if stream.read_exact(&mut preface).is_err() || preface != PREFACE {
+            return;
+        }
+        stream.write_all(&frame(FRAME_SETTINGS, 0, 0, &[])).unwrap();
+
+        let mut bodies: HashMap<u32, Vec<u8>> = HashMap::new();
+        let mut answered = 0;
+
+        while let Some((frame_type, flags, stream_id, payload)) = read_frame(&mut stream) {
+            let body = match frame_type {
+                FRAME_SETTINGS if flags & FLAG_ACK == 0 => {
+                    stream
+                        .write_all(&frame(FRAME_SETTINGS, FLAG_ACK, 0, &[]))
+                        .unwrap();
+                    continue;
+                }
+                FRAME_RST_STREAM => {
+                    reset.store(true, Ordering::SeqCst);
+                    continue;
+                }
+                FRAME_DATA => {
+                    let body = bodies.entry(stream_id).or_default();
+                    body.extend_from_slice(&payload);
+                    if flags & FLAG_END_STREAM == 0 {
+                        continue;
+                    }
+                    bodies.remove(&stream_id).unwrap()
+                }
+                _ => continue,
+            };
+
+            if matches!(behavior, Behavior::GoAway) && answered > 0 {
+                let mut payload = 1u32.to_be_bytes().to_vec();
+                payload.extend_from_slice(&0u32.to_be_bytes());
+                stream
+                    .write_all(&frame(FRAME_GOAWAY, 0, 0, &payload))
+                    .unwrap();
+                continue;
+            }
+            answered += 1;
+
+            let mut buffer = BytePacketBuffer::with_size(body.len());
+            buffer.buf.copy_from_slice(&body);
+            let request = DnsPacket::from_buffer(&mut buffer).unwrap();
+
+            let mut res_buffer = BytePacketBuffer::with_size(MAX_TCP_SIZE);
+            answer(&request).to_buffer(&mut res_buffer).unwrap();
+            let message = &res_buffer.buf[..res_buffer.pos];
+
+            let frames = match behavior {
+                Behavior::Answer | Behavior::GoAway => {
+                    response(stream_id, "200", DNS_MESSAGE_TYPE, message, MAX_FRAME_SIZE)
+                }
+                Behavior::Status(status) => {
+                    response(stream_id, status, DNS_MESSAGE_TYPE, message, MAX_FRAME_SIZE)
+                }
+                Behavior::ContentType(content_type) => {
+                    response(stream_id, "200", content_type, message, MAX_FRAME_SIZE)
+                }
+                Behavior::Split => response(stream_id, "200", DNS_MESSAGE_TYPE, message, 7),
+                Behavior::Oversized => response(
+                    stream_id,
+                    "200",
+                    DNS_MESSAGE_TYPE,
+                    &[0; MAX_TCP_SIZE + 1000],
+                    MAX_FRAME_SIZE,
+                ),
+            };
+            // The client resets the stream partway through an oversized body
+            let _ = stream.write_all(&frames);
+            let _ = stream.flush();
+        }
+    }
+
+    fn stand_in(behavior: Behavior) -> (StandIn, HttpsClient, Arc<AtomicBool>) {
+        let (roots, config) = authority(SERVER_NAME, &[b"h2"]);
+        let reset = Arc::new(AtomicBool::new(false));
+
+        let flag = reset.clone();
+        let stand_in = serve(config, move |stream, _| serve_h2(stream, behavior, &flag));
+
+        let url = format!("https://{}/dns-query", SERVER_NAME);
+        let upstream = HttpsUpstreamConfig::from_url(stand_in.server, &url).unwrap();
+        let client = HttpsClient::new(&[upstream], &roots).unwrap();
+
+        (stand_in, client, reset)
+    }
+
+    fn exchange(client: &HttpsClient, stand_in: &StandIn) -> Result<DnsPacket> {
+        let mut request = DnsPacket::new();
+        request.header.id = 4711;
+        request.header.recursion_desired = true;
+        request.header.questions_count = 1;
+        request.questions.push(DnsQuestion::new(
+            "www.example.com".to_string(),
+            QueryType::A,
+            QueryClass::IN,
+        ));
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        client.exchange(&request, stand_in.server, deadline, false)
+    }
+
+    fn assert_answered(response: &DnsPacket) {
+        assert!(matches!(
+            response.answers.as_slice(),
+            [DnsRecord::A { ip_v4_addr, .. }] if *ip_v4_addr == ANSWER_ADDR
+        ));
+    }
+
+    #[test]
+    fn answers_queries_over_one_connection() {
+        let (stand_in, client, _) = stand_in(Behavior::Answer);
+
+        for _ in 0..3 {
+            assert_answered(&exchange(&client, &stand_in).unwrap());
+        }
+
+        assert_eq!(stand_in.accepted(), 1);
+    }
+
+    #[test]
+    fn fails_on_a_status_other_than_200() {
+        let (stand_in, client, _) = stand_in(Behavior::Status("503"));
+
+        let error = exchange(&client, &stand_in).unwrap_err();
+
+        assert!(error.to_string().contains("HTTP status 503"), "{}", error);
+    }
+
+    #[test]
+    fn fails_on_a_wrong_content_type() {
+        let (stand_in, client, _) = stand_in(Behavior::ContentType("text/html"));
+
+        let error = exchange(&client, &stand_in).unwrap_err();
+
+        assert!(
+            error
+                .to_string()
+                .contains("unexpected content type text/html"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn reassembles_a_body_split_across_data_frames() {
+        let (stand_in, client, _) = stand_in(Behavior::Split);
+
+        assert_answered(&exchange(&client, &stand_in).unwrap());
+    }
+
+    #[test]
+    fn retries_on_a_fresh_connection_after_goaway() {
+        let (stand_in, client, _) = stand_in(Behavior::GoAway);
+
+        assert_answered(&exchange(&client, &stand_in).unwrap());
+        // The reused connection refuses the query and goes away
+        assert_answered(&exchange(&client, &stand_in).unwrap());
+
+        assert_eq!(stand_in.accepted(), 2);
+    }
+
+    #[test]
+    fn resets_the_stream_of_an_oversized_body() {
+        let (stand_in, client, reset) = stand_in(Behavior::Oversized);
+
+        let error = exchange(&client, &stand_in).unwrap_err();
+        assert!(error.to_string().contains("over 65535 bytes"), "{}", error);
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !reset.load(Ordering::SeqCst) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(reset.load(Ordering::SeqCst));
+    }
+}
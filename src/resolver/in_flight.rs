@@ -0,0 +1,121 @@
+// Coalescing of identical client queries. The first query for a question
+// resolves it, queries for the same question arriving in the meantime wait
+// for that resolution and share its result
+
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex};
+
+use crate::cache::record_cache::CacheKey;
+use crate::context::ServerContext;
+use crate::models::{dns_packet::DnsPacket, query_class::QueryClass, query_type::QueryType};
+use crate::types::Result;
+
+// Whether validation was asked to be skipped changes the answer, so such
+// queries are kept apart from the others
+type QueryKey = (CacheKey, bool);
+
+#[derive(Debug, Default)]
+struct Pending {
+    // Errors are kept as text since they have to be handed to every waiter
+    result: Mutex<Option<std::result::Result<DnsPacket, String>>>,
+    done: Condvar,
+}
+
+#[derive(Debug, Default)]
+pub struct InFlight {
+    queries: Mutex<HashMap<QueryKey, Arc<Pending>>>,
+}
+
+// Publishes the result of the resolution when dropped, or a failure should
+// the resolving thread panic, so that waiters are never left hanging
+struct Resolution<'a> {
+    in_flight: &'a InFlight,
+    key: QueryKey,
+    pending: Arc<Pending>,
+    result: Option<std::result::Result<DnsPacket, String>>,
+}
+
+impl Drop for Resolution<'_> {
+    fn drop(&mut self) {
+        {
+            let mut queries = self.in_flight.queries.lock().unwrap();
+            if queries
+                .get(&self.key)
+                .is_some_and(|pending| Arc::ptr_eq(pending, &self.pending))
+            {
+                queries.remove(&self.key);
+            }
+        }
+
+        let result = self
+            .result
+            .take()
+            .unwrap_or_else(|| Err("Resolution was aborted".to_string()));
+        *self.pending.result.lock().unwrap() = Some(result);
+        self.pending.done.notify_all();
+    }
+}
+
+impl InFlight {
+    pub fn new() -> InFlight {
+        InFlight::default()
+    }
+
+    pub fn resolve<F>(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        qclass: QueryClass,
+        checking_disabled: bool,
+        context: &ServerContext,
+        lookup: F,
+    ) -> Result<DnsPacket>
+    where
+        F: FnOnce() -> Result<DnsPacket>,
+    {
+        let key = (CacheKey::new(qname, qtype, qclass), checking_disabled);
+
+        let (pending, waiting) = {
+            let mut queries = self.queries.lock().unwrap();
+            match queries.get(&key) {
+                Some(pending) => (pending.clone(), true),
+                None => {
+                    let pending = Arc::new(Pending::default());
+                    queries.insert(key.clone(), pending.clone());
+                    (pending, false)
+                }
+            }
+        };
+
+        if waiting {
+            context.trace(format_args!(
+                "waiting for in-flight resolution of {:?} {}",
+                qtype, qname
+            ));
+
+            let mut result = pending.result.lock().unwrap();
+            loop {
+                match result.as_ref() {
+                    Some(Ok(packet)) => return Ok(packet.clone()),
+                    Some(Err(e)) => return Err(e.clone().into()),
+                    None => result = pending.done.wait(result).unwrap(),
+                }
+            }
+        }
+
+        let mut resolution = Resolution {
+            in_flight: self,
+            key,
+            pending,
+            result: None,
+        };
+
+        let result = lookup();
+        resolution.result = Some(match &result {
+            Ok(packet) => Ok(packet.clone()),
+            Err(e) => Err(e.to_string()),
+        });
+
+        result
+    }
+}
@@ -0,0 +1,849 @@
+// Resolution of queries, either iteratively from the root servers down or
+// through the configured forwarders, answering from the cache where possible
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+use rand::{thread_rng, Rng};
+
+use super::forwarder::{find_forward_zone, ForwardPolicy, ForwarderPool};
+use super::query_state::QueryState;
+use super::transport::{exchange_tcp, exchange_udp, Transport};
+use crate::cache::record_cache::{is_denial_proof, CacheKey};
+use crate::context::ServerContext;
+use crate::dnssec::validator::{Security, Validator};
+use crate::models::{
+    dns_packet::DnsPacket, dns_question::DnsQuestion, dns_record::DnsRecord,
+    query_class::QueryClass, query_type::QueryType, result_code::ResultCode,
+};
+use crate::types::{is_unavailable, Result, Unavailable};
+use crate::utils::byte_packet_buffer::EDNS_PACKET_SIZE;
+use crate::utils::domain_name::{label_count, last_labels, parent};
+
+const MAX_CNAME_CHAIN: usize = 8;
+// RFC 9156 2.3 suggests limiting the number of minimised queries per lookup
+const MAX_MINIMISED_QUERIES: usize = 10;
+// Real delegation chains are a handful of zones deep, anything longer is
+// most likely a referral loop
+const MAX_REFERRALS: usize = 20;
+// RFC 8767 5: how long a client waits for fresh data before stale data is
+// answered instead
+const STALE_ANSWER_TIMEOUT: Duration = Duration::from_millis(1800);
+
+#[derive(Debug, Clone, Copy)]
+struct LookupOptions {
+    timeout: Duration,
+    transport: Transport,
+    recursion_desired: bool,
+    randomize_case: bool,
+    dnssec_ok: bool,
+}
+
+fn lookup(
+    qname: &str,
+    qtype: QueryType,
+    qclass: QueryClass,
+    server: (Ipv4Addr, u16),
+    options: LookupOptions,
+    context: &ServerContext,
+) -> Result<DnsPacket> {
+    let upstream = server;
+    let server = SocketAddr::from(server);
+    let deadline = Instant::now() + options.timeout;
+    let randomize_case = options.randomize_case;
+
+    // With 0x20 encoding the server has to echo our random casing back,
+    // which adds a bit of entropy per letter for a spoofer to guess
+    let sent_name = if randomize_case {
+        randomize_name_case(qname)
+    } else {
+        qname.to_string()
+    };
+
+    let mut packet = DnsPacket::new();
+
+    packet.header.id = thread_rng().gen();
+    packet.header.questions_count = 1;
+    packet.header.recursion_desired = options.recursion_desired;
+    packet
+        .questions
+        .push(DnsQuestion::new(sent_name.clone(), qtype, qclass));
+    if options.dnssec_ok {
+        packet.set_edns(EDNS_PACKET_SIZE, true);
+    }
+
+    let mut response = match options.transport {
+        Transport::Udp => {
+            let response = exchange_udp(&packet, server, deadline, randomize_case)?;
+
+            if response.header.truncated_message {
+                context.trace(format_args!(
+                    "reply from {} truncated, retrying over TCP",
+                    server
+                ));
+                exchange_tcp(&packet, server, deadline, randomize_case)?
+            } else {
+                response
+            }
+        }
+        Transport::Tcp => exchange_tcp(&packet, server, deadline, randomize_case)?,
+        Transport::Tls => context
+            .tls
+            .exchange(&packet, upstream, deadline, randomize_case)?,
+        Transport::Https => context
+            .https
+            .exchange(&packet, upstream, deadline, randomize_case)?,
+    };
+
+    if randomize_case {
+        response.restore_name_case(&sent_name, qname);
+    }
+
+    // The OPT record only concerns this hop (RFC 6891 6.1.1)
+    response
+        .additionals
+        .retain(|record| record.qtype() != QueryType::OPT);
+
+    Ok(response)
+}
+
+fn randomize_name_case(name: &str) -> String {
+    let mut rng = thread_rng();
+
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphabetic() && rng.gen::<bool>() {
+                c.to_ascii_uppercase()
+            } else {
+                c.to_ascii_lowercase()
+            }
+        })
+        .collect()
+}
+
+// Servers a query can be sent to: the name servers of a zone, tried by their
+// smoothed RTT, or recursive resolvers in the order picked by the forwarding
+// strategy
+#[derive(Debug, Clone, Copy)]
+enum Upstreams<'a> {
+    NameServers(&'a [Ipv4Addr]),
+    Forwarders(&'a ForwarderPool),
+}
+
+impl Upstreams<'_> {
+    fn order(&self, context: &ServerContext) -> Vec<(Ipv4Addr, u16)> {
+        match self {
+            Upstreams::NameServers(servers) => context
+                .infra_cache
+                .order(servers)
+                .into_iter()
+                .map(|server| (server, 53))
+                .collect(),
+            Upstreams::Forwarders(forwarders) => forwarders.order(),
+        }
+    }
+
+    // Forwarders may be reached over TLS or HTTPS and are asked to recurse
+    fn options(&self, upstream: (Ipv4Addr, u16), context: &ServerContext) -> (Transport, bool) {
+        match self {
+            Upstreams::NameServers(_) => (context.config.upstream_transport, false),
+            Upstreams::Forwarders(_) => {
+                let transport = if context.tls.is_tls_upstream(upstream) {
+                    Transport::Tls
+                } else if context.https.is_https_upstream(upstream) {
+                    Transport::Https
+                } else {
+                    context.config.upstream_transport
+                };
+                (transport, true)
+            }
+        }
+    }
+
+    fn record_success(&self, upstream: (Ipv4Addr, u16), rtt: Duration, context: &ServerContext) {
+        match self {
+            Upstreams::NameServers(_) => context.infra_cache.record_success(upstream.0, rtt),
+            Upstreams::Forwarders(forwarders) => forwarders.record_success(upstream, rtt),
+        }
+    }
+
+    fn record_failure(&self, upstream: (Ipv4Addr, u16), context: &ServerContext) {
+        match self {
+            Upstreams::NameServers(_) => context.infra_cache.record_failure(upstream.0),
+            Upstreams::Forwarders(forwarders) => forwarders.record_failure(upstream),
+        }
+    }
+}
+
+// Queries the upstreams in order until one of them answers, doubling the
+// timeout after every full round
+fn query_upstreams(
+    qname: &str,
+    qtype: QueryType,
+    qclass: QueryClass,
+    upstreams: Upstreams,
+    context: &ServerContext,
+    state: &QueryState,
+) -> Result<DnsPacket> {
+    let mut timeout = context.config.query_timeout;
+
+    for attempt in 0..context.config.query_attempts {
+        for upstream in upstreams.order(context) {
+            let remaining = state.remaining()?;
+            state.count_query()?;
+
+            context.trace(format_args!(
+                "querying {}:{} for {:?} {} (attempt {})",
+                upstream.0,
+                upstream.1,
+                qtype,
+                qname,
+                attempt + 1
+            ));
+
+            let (transport, recursion_desired) = upstreams.options(upstream, context);
+            let options = LookupOptions {
+                timeout: timeout.min(remaining),
+                transport,
+                recursion_desired,
+                randomize_case: context.config.use_0x20(upstream.0),
+                dnssec_ok: context.validator.is_some(),
+            };
+
+            let started = Instant::now();
+            match lookup(qname, qtype, qclass, upstream, options, context) {
+                Ok(response)
+                    if matches!(
+                        response.header.result_code,
+                        ResultCode::SERVFAIL | ResultCode::REFUSED
+                    ) =>
+                {
+                    upstreams.record_failure(upstream, context);
+                    context.trace(format_args!(
+                        "{}:{} answered {:?}, trying next",
+                        upstream.0, upstream.1, response.header.result_code
+                    ));
+                }
+                Ok(response) => {
+                    upstreams.record_success(upstream, started.elapsed(), context);
+                    return Ok(response);
+                }
+                Err(e) => {
+                    upstreams.record_failure(upstream, context);
+                    context.trace(format_args!(
+                        "query to {}:{} failed: {}",
+                        upstream.0, upstream.1, e
+                    ));
+                }
+            }
+        }
+
+        timeout *= 2;
+    }
+
+    let message = match upstreams {
+        Upstreams::NameServers(servers) => {
+            format!("No name server answered for {} out of {:?}", qname, servers)
+        }
+        Upstreams::Forwarders(_) => format!("No upstream resolver answered for {}", qname),
+    };
+
+    Err(Unavailable(message).into())
+}
+
+// Forwarders of a zone are only trusted with names under it, so anything else
+// they send is dropped before reaching the cache, as done for referrals. The
+// global forwarders answer for the whole tree
+fn forward_lookup(
+    qname: &str,
+    qtype: QueryType,
+    qclass: QueryClass,
+    zone: &str,
+    forwarders: &ForwarderPool,
+    context: &ServerContext,
+    state: &QueryState,
+) -> Result<DnsPacket> {
+    let mut response = query_upstreams(
+        qname,
+        qtype,
+        qclass,
+        Upstreams::Forwarders(forwarders),
+        context,
+        state,
+    )?;
+    response.scrub_out_of_bailiwick(zone);
+    context.cache.insert_packet(&response);
+
+    if response.answers.is_empty() || response.header.result_code == ResultCode::NXDOMAIN {
+        context
+            .cache
+            .insert_negative(qname, qtype, qclass, &response);
+    }
+
+    Ok(response)
+}
+
+fn cached_response(
+    context: &ServerContext,
+    qname: &str,
+    qtype: QueryType,
+    qclass: QueryClass,
+    stale: bool,
+) -> Option<DnsPacket> {
+    let mut packet = DnsPacket::new();
+    packet.header.is_response = true;
+    packet
+        .questions
+        .push(DnsQuestion::new(qname.to_string(), qtype, qclass));
+
+    // Wildcard answers come with the proof that qname itself does not exist
+    let has_proof = !matches!(qtype, QueryType::NSEC | QueryType::NSEC3);
+    let mut answer = |records: Vec<DnsRecord>| {
+        packet.header.result_code = ResultCode::NOERROR;
+        for record in records {
+            if has_proof && is_denial_proof(&record) {
+                packet.authorities.push(record);
+            } else {
+                packet.answers.push(record);
+            }
+        }
+    };
+
+    let get = |qtype: QueryType| match stale {
+        true => context.cache.get_stale(qname, qtype, qclass),
+        false => context.cache.get(qname, qtype, qclass),
+    };
+
+    if let Some(records) = get(qtype) {
+        answer(records);
+    } else if let Some(records) = get(QueryType::CNAME).filter(|_| qtype != QueryType::CNAME) {
+        answer(records);
+    } else {
+        let negative = match stale {
+            true => context.cache.get_negative_stale(qname, qtype, qclass),
+            false => context.cache.get_negative(qname, qtype, qclass),
+        }?;
+        packet.header.result_code = negative.result_code;
+        packet.authorities.extend(negative.records);
+    }
+
+    Some(packet)
+}
+
+pub fn recursive_lookup(
+    qname: &str,
+    qtype: QueryType,
+    qclass: QueryClass,
+    context: &ServerContext,
+    state: &QueryState,
+) -> Result<DnsPacket> {
+    let _lookup = state.enter(qname, qtype)?;
+
+    let mut chain: Vec<DnsRecord> = Vec::new();
+    let mut visited = vec![qname.to_ascii_lowercase()];
+    let mut name = qname.to_string();
+
+    loop {
+        let mut response = iterative_lookup(&name, qtype, qclass, context, state)?;
+
+        let links: Vec<DnsRecord> = response
+            .get_cname_chain(&name)
+            .into_iter()
+            .cloned()
+            .collect();
+
+        let target = match links.last() {
+            Some(DnsRecord::CNAME { host, .. }) if qtype != QueryType::CNAME => host.clone(),
+            _ => name.clone(),
+        };
+
+        let answered = response
+            .answers
+            .iter()
+            .any(|record| record.qtype() == qtype && record.domain().eq_ignore_ascii_case(&target));
+
+        if target.eq_ignore_ascii_case(&name)
+            || answered
+            || response.header.result_code != ResultCode::NOERROR
+        {
+            chain.append(&mut response.answers);
+            response.answers = chain;
+            response.questions = vec![DnsQuestion::new(qname.to_string(), qtype, qclass)];
+
+            return Ok(response);
+        }
+
+        for link in links {
+            let host = match &link {
+                DnsRecord::CNAME { host, .. } => host.to_ascii_lowercase(),
+                _ => continue,
+            };
+
+            if visited.contains(&host) {
+                return Err(format!("CNAME loop detected at {}", host).into());
+            }
+
+            visited.push(host);
+            chain.push(link);
+        }
+
+        // The signatures over the aliases are needed to validate the chain
+        chain.extend(
+            response
+                .answers
+                .iter()
+                .filter(|record| {
+                    matches!(
+                        record,
+                        DnsRecord::RRSIG {
+                            type_covered: QueryType::CNAME,
+                            ..
+                        }
+                    )
+                })
+                .cloned(),
+        );
+
+        let links = chain
+            .iter()
+            .filter(|record| matches!(record, DnsRecord::CNAME { .. }))
+            .count();
+        if links > MAX_CNAME_CHAIN {
+            return Err(format!(
+                "CNAME chain for {} exceeds {} links",
+                qname, MAX_CNAME_CHAIN
+            )
+            .into());
+        }
+
+        context.trace(format_args!("following CNAME from {} to {}", name, target));
+        name = target;
+    }
+}
+
+fn iterative_lookup(
+    qname: &str,
+    qtype: QueryType,
+    qclass: QueryClass,
+    context: &ServerContext,
+    state: &QueryState,
+) -> Result<DnsPacket> {
+    let forward_zone = find_forward_zone(&context.forward_zones, qname);
+
+    if !state.bypasses_cache(qname, qtype) {
+        if let Some(packet) = cached_response(context, qname, qtype, qclass, false) {
+            context.trace(format_args!("cache hit for {:?} {}", qtype, qname));
+            return Ok(packet);
+        }
+
+        // Validated NSEC and NSEC3 records may already prove the name or type
+        // does not exist (RFC 8198). Not so for forwarded zones, which the
+        // public tree commonly denies the existence of
+        if let Some(packet) = context
+            .validator
+            .as_ref()
+            .filter(|_| qclass == QueryClass::IN && forward_zone.is_none())
+            .and_then(|validator| validator.synthesize(qname, qtype))
+        {
+            context.trace(format_args!(
+                "synthesized {:?} for {:?} {} from cached NSEC records",
+                packet.header.result_code, qtype, qname
+            ));
+            return Ok(packet);
+        }
+    }
+
+    if let Some(rule) = forward_zone {
+        match forward_lookup(qname, qtype, qclass, &rule.zone, &rule.pool, context, state) {
+            Ok(response) => return Ok(response),
+            Err(e) if rule.policy == ForwardPolicy::First => {
+                context.trace(format_args!(
+                    "forwarding {} for zone {:?} failed, resolving normally: {}",
+                    qname, rule.zone, e
+                ));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    if let Some(forwarders) = &context.forwarders {
+        return forward_lookup(qname, qtype, qclass, "", forwarders, context, state);
+    }
+
+    // DS records are served by the parent side of a zone cut (RFC 4035 3.1.4.1)
+    let start = match qtype {
+        QueryType::DS => parent(qname).unwrap_or_default(),
+        _ => qname,
+    };
+
+    let (mut zone, mut servers) = match context.cache.closest_ns(start) {
+        Some((zone, addrs)) => {
+            context.trace(format_args!(
+                "starting lookup of {} at cached zone cut {:?}",
+                qname, zone
+            ));
+            (zone, addrs)
+        }
+        None => (String::new(), context.root_hints.ip_v4_addrs()),
+    };
+
+    // QNAME minimisation as per RFC 9156: each zone is only asked about the
+    // next label below it until the zone cut above qname is found
+    let mut minimise = context.config.qname_minimisation;
+    let mut minimised_queries = 0;
+    let mut extra_labels = 1;
+    let mut referrals = 0;
+    // Name servers of the zone without glue that were not resolved yet
+    let mut unresolved_ns: Vec<String> = Vec::new();
+
+    loop {
+        let child = last_labels(qname, label_count(&zone) + extra_labels);
+        let minimised = minimise && child != qname && minimised_queries < MAX_MINIMISED_QUERIES;
+
+        let mut response = if minimised {
+            minimised_queries += 1;
+
+            match query_upstreams(
+                child,
+                QueryType::A,
+                qclass,
+                Upstreams::NameServers(&servers),
+                context,
+                state,
+            ) {
+                Ok(response) if response.header.result_code == ResultCode::NOERROR => response,
+                // Broken servers answer NXDOMAIN or errors for empty non-terminals
+                Ok(response) => {
+                    context.trace(format_args!(
+                        "minimised query for {} answered {:?}, falling back to {}",
+                        child, response.header.result_code, qname
+                    ));
+                    minimise = false;
+                    continue;
+                }
+                Err(e) => {
+                    context.trace(format_args!(
+                        "minimised query for {} failed, falling back to {}: {}",
+                        child, qname, e
+                    ));
+                    minimise = false;
+                    continue;
+                }
+            }
+        } else {
+            match query_upstreams(
+                qname,
+                qtype,
+                qclass,
+                Upstreams::NameServers(&servers),
+                context,
+                state,
+            ) {
+                Ok(response) => response,
+                // The other name servers of the zone may still answer
+                Err(e) if is_unavailable(&e) && !unresolved_ns.is_empty() => {
+                    context.trace(format_args!(
+                        "name servers of {:?} failed, resolving the others: {}",
+                        zone, e
+                    ));
+                    servers = resolve_ns(&zone, &mut unresolved_ns, context, state)?;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            }
+        };
+
+        response.scrub_out_of_bailiwick(&zone);
+        context.cache.insert_packet(&response);
+
+        if !minimised {
+            if !response.answers.is_empty() && response.header.result_code == ResultCode::NOERROR {
+                return Ok(response);
+            }
+
+            if response.header.result_code == ResultCode::NXDOMAIN {
+                context
+                    .cache
+                    .insert_negative(qname, qtype, qclass, &response);
+                return Ok(response);
+            }
+        }
+
+        let delegation = match response.get_delegation(qname, &zone) {
+            Some(x) => x.to_string(),
+            // No zone cut at child, so move on to the next label. An alias
+            // in the way means the full name has to be asked for instead
+            None if minimised => {
+                if response.get_cname_chain(child).is_empty() {
+                    extra_labels += 1;
+                } else {
+                    minimise = false;
+                }
+                continue;
+            }
+            None => {
+                context
+                    .cache
+                    .insert_negative(qname, qtype, qclass, &response);
+                return Ok(response);
+            }
+        };
+
+        referrals += 1;
+        if referrals > MAX_REFERRALS {
+            return Err(format!(
+                "Referral limit of {} reached resolving {} at {:?}",
+                MAX_REFERRALS, qname, zone
+            )
+            .into());
+        }
+
+        context.trace(format_args!(
+            "following referral from {:?} to {:?}",
+            zone, delegation
+        ));
+
+        extra_labels = 1;
+
+        unresolved_ns = response
+            .get_unresolved_ns(&delegation)
+            .into_iter()
+            .map(|host| host.to_string())
+            .collect();
+
+        servers = response.get_resolved_ns(&delegation);
+        if servers.is_empty() {
+            servers = resolve_ns(&delegation, &mut unresolved_ns, context, state)?;
+        }
+
+        zone = delegation;
+    }
+}
+
+// Looks up the addresses of name servers of the zone that came without glue,
+// one at a time until one of them has any. The names left are kept in case
+// the servers found do not answer
+fn resolve_ns(
+    zone: &str,
+    names: &mut Vec<String>,
+    context: &ServerContext,
+    state: &QueryState,
+) -> Result<Vec<Ipv4Addr>> {
+    let mut failure = None;
+
+    while !names.is_empty() {
+        let name = names.remove(0);
+
+        match recursive_lookup(&name, QueryType::A, QueryClass::IN, context, state) {
+            Ok(response) if !response.get_a_addrs().is_empty() => {
+                return Ok(response.get_a_addrs());
+            }
+            Ok(_) => {}
+            Err(e) => {
+                context.trace(format_args!("failed to resolve ns {}: {}", name, e));
+                failure = Some(e);
+            }
+        }
+    }
+
+    Err(match failure {
+        Some(e) => {
+            let message = format!("No name server of {:?} could be resolved: {}", zone, e);
+            match is_unavailable(&e) {
+                true => Unavailable(message).into(),
+                false => message.into(),
+            }
+        }
+        None => format!("No name server of {:?} has an address", zone).into(),
+    })
+}
+
+// The validator to check answers with, unless validation is off or the
+// client set CD
+fn active_validator(
+    context: &ServerContext,
+    qclass: QueryClass,
+    checking_disabled: bool,
+) -> Option<&Validator> {
+    context
+        .validator
+        .as_ref()
+        .filter(|_| !checking_disabled && qclass == QueryClass::IN)
+}
+
+// Resolves the query and, unless validation is off or the client set CD,
+// checks the answer with DNSSEC. Data that fails validation may come from a
+// lame or out of sync server, so it is dropped and fetched again once before
+// answering SERVFAIL (RFC 4035 5.5)
+pub fn validated_lookup(
+    qname: &str,
+    qtype: QueryType,
+    qclass: QueryClass,
+    checking_disabled: bool,
+    context: &ServerContext,
+    state: &QueryState,
+) -> Result<DnsPacket> {
+    let Some(validator) = active_validator(context, qclass, checking_disabled) else {
+        return recursive_lookup(qname, qtype, qclass, context, state);
+    };
+
+    let fetch = |name: &str, qtype: QueryType| {
+        recursive_lookup(name, qtype, QueryClass::IN, context, state)
+    };
+
+    let mut retried = false;
+    loop {
+        let mut response = recursive_lookup(qname, qtype, qclass, context, state)?;
+
+        match validator.validate(qname, qtype, &response, &fetch) {
+            Ok(security) => {
+                response.header.authed_data = security == Security::Secure;
+                return Ok(response);
+            }
+            Err(e) => {
+                // Bogus data must not linger in the cache, where it would
+                // be served to CD clients and used as stale data
+                context.cache.remove_packet(qname, qtype, qclass, &response);
+
+                if retried {
+                    return Err(format!("DNSSEC validation of {} failed: {}", qname, e).into());
+                }
+                retried = true;
+            }
+        }
+    }
+}
+
+// Answers from cache entries that expired less than the stale window ago,
+// following CNAMEs through them as well. The aliases the expired data is
+// reached through may still be fresh
+fn stale_response(
+    context: &ServerContext,
+    qname: &str,
+    qtype: QueryType,
+    qclass: QueryClass,
+) -> Option<DnsPacket> {
+    let mut response = cached_response(context, qname, qtype, qclass, true)?;
+    let mut name = qname.to_string();
+
+    for _ in 0..MAX_CNAME_CHAIN {
+        let target = match response.get_cname_chain(&name).last() {
+            Some(DnsRecord::CNAME { host, .. }) if qtype != QueryType::CNAME => host.clone(),
+            _ => break,
+        };
+
+        let next = cached_response(context, &target, qtype, qclass, true)
+            .or_else(|| cached_response(context, &target, qtype, qclass, false))?;
+        response.header.result_code = next.header.result_code;
+        response.answers.extend(next.answers);
+        response.authorities = next.authorities;
+        name = target;
+    }
+
+    Some(response)
+}
+
+// Resolves a client query. With serve-stale on and expired data at hand, the
+// client only waits STALE_ANSWER_TIMEOUT for fresh data before getting the
+// stale data while the cache is refreshed in the background (RFC 8767 5).
+// Entries queried shortly before they expire are refreshed ahead of time
+pub fn resolve(
+    qname: &str,
+    qtype: QueryType,
+    qclass: QueryClass,
+    checking_disabled: bool,
+    context: &ServerContext,
+) -> Result<DnsPacket> {
+    let key = CacheKey::new(qname, qtype, qclass);
+
+    if context.config.prefetch && context.cache.is_expiring(qname, qtype, qclass) {
+        context.refresh_queue.push(key.clone());
+    }
+
+    let stale = match context.config.serve_stale {
+        Some(_) => stale_response(context, qname, qtype, qclass),
+        None => None,
+    };
+
+    let Some(mut stale) = stale else {
+        let state = QueryState::new(&context.config);
+        return validated_lookup(qname, qtype, qclass, checking_disabled, context, &state);
+    };
+
+    let result = if context.refresh_queue.recently_failed(&key) {
+        Err(Unavailable("Refreshing failed recently".to_string()).into())
+    } else {
+        let state = QueryState::with_budget(STALE_ANSWER_TIMEOUT.min(context.config.query_budget));
+        validated_lookup(qname, qtype, qclass, checking_disabled, context, &state)
+    };
+
+    // Stale data only stands in for answers that could not be had, never
+    // for ones that failed validation or hit a resolution limit
+    let failure = match result {
+        Ok(response) if response.header.result_code != ResultCode::SERVFAIL => return Ok(response),
+        Err(e) if !is_unavailable(&e) => return Err(e),
+        failure => failure,
+    };
+
+    // The stale data is held to the same standard as fresh data
+    if let Some(validator) = active_validator(context, qclass, checking_disabled) {
+        let state = QueryState::with_budget(STALE_ANSWER_TIMEOUT.min(context.config.query_budget));
+        let fetch = |name: &str, qtype: QueryType| {
+            recursive_lookup(name, qtype, QueryClass::IN, context, &state)
+        };
+
+        match validator.validate(qname, qtype, &stale, &fetch) {
+            Ok(security) => stale.header.authed_data = security == Security::Secure,
+            Err(e) => {
+                warn!("stale data for {:?} {} is bogus: {}", qtype, qname, e);
+                return failure;
+            }
+        }
+    }
+
+    context.refresh_queue.push(key);
+
+    Ok(stale)
+}
+
+// Resolves the queued questions again, replacing what the cache holds for
+// them, until the queue is closed
+pub fn refresh_cache(context: &ServerContext) {
+    while let Some(key) = context.refresh_queue.pop() {
+        let state = QueryState::refresh(&context.config, &key.name, key.qtype);
+
+        let success =
+            match validated_lookup(&key.name, key.qtype, key.qclass, false, context, &state) {
+                Ok(response) => response.header.result_code != ResultCode::SERVFAIL,
+                Err(e) => {
+                    debug!("refreshing {:?} {} failed: {}", key.qtype, key.name, e);
+                    false
+                }
+            };
+
+        context.refresh_queue.finish(&key, success);
+    }
+}
+
+pub fn prime_root_hints(context: &ServerContext) -> Result<()> {
+    let servers = context.root_hints.ip_v4_addrs();
+
+    info!("priming root hints");
+
+    let state = QueryState::new(&context.config);
+    let response = query_upstreams(
+        "",
+        QueryType::NS,
+        QueryClass::IN,
+        Upstreams::NameServers(&servers),
+        context,
+        &state,
+    )?;
+    context.cache.insert_packet(&response);
+
+    let count = context.root_hints.update_from_priming(&response);
+    info!("primed {} root servers", count);
+
+    Ok(())
+}
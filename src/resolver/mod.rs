@@ -0,0 +1,17 @@
+pub mod api;
+pub mod forwarder;
+pub mod hosts;
+pub mod hpack;
+pub mod https;
+pub mod in_flight;
+pub mod lookup;
+pub mod pool;
+pub mod query_state;
+pub mod refresh_queue;
+pub mod resolv_conf;
+pub mod root_hints;
+pub mod stub;
+pub mod tls;
+#[cfg(test)]
+mod tls_stand_in;
+pub mod transport;
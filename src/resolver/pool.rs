@@ -0,0 +1,73 @@
+// Connection to an upstream shared by all queries sent to it, as done for TLS
+// and HTTPS, which is replaced by a fresh one once closed
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use log::debug;
+
+use crate::types::Result;
+
+pub trait Connection {
+    fn is_closed(&self) -> bool;
+}
+
+pub struct ConnectionPool<C> {
+    // Name of the protocol, for messages
+    protocol: &'static str,
+    current: Mutex<Option<Arc<C>>>,
+}
+
+impl<C: Connection> ConnectionPool<C> {
+    pub fn new(protocol: &'static str) -> ConnectionPool<C> {
+        ConnectionPool {
+            protocol,
+            current: Mutex::new(None),
+        }
+    }
+
+    // Returns the open connection, if any, or establishes a new one. The flag
+    // tells whether the connection was reused
+    fn connection<F>(&self, server: SocketAddr, connect: F) -> Result<(Arc<C>, bool)>
+    where
+        F: FnOnce() -> Result<Arc<C>>,
+    {
+        let mut current = self.current.lock().unwrap();
+
+        if let Some(connection) = current.as_ref().filter(|x| !x.is_closed()) {
+            return Ok((connection.clone(), true));
+        }
+
+        debug!("opening {} connection to {}", self.protocol, server);
+        let connection = connect()?;
+        *current = Some(connection.clone());
+
+        Ok((connection, false))
+    }
+
+    // Sends a query over the open connection. The server may have closed a
+    // reused connection in the meantime, so a failed send on one is retried
+    // once on a fresh connection, send being expected to close the
+    // connection it failed on
+    pub fn send<T, F, S>(&self, server: SocketAddr, connect: F, send: S) -> Result<(Arc<C>, T)>
+    where
+        F: Fn() -> Result<Arc<C>>,
+        S: Fn(&C) -> Result<T>,
+    {
+        let (connection, reused) = self.connection(server, &connect)?;
+
+        match send(&connection) {
+            Ok(sent) => Ok((connection, sent)),
+            Err(e) if reused => {
+                debug!(
+                    "reused {} connection to {} failed: {}",
+                    self.protocol, server, e
+                );
+                let (connection, _) = self.connection(server, &connect)?;
+                let sent = send(&connection)?;
+                Ok((connection, sent))
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
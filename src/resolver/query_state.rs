@@ -0,0 +1,127 @@
+// Per client query bookkeeping, shared by every upstream query made on its
+// behalf. Besides the time budget this bounds the work a single client query
+// can cause, so that broken or malicious delegations cannot keep the resolver
+// busy indefinitely
+
+use std::cell::{Cell, RefCell};
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::models::query_type::QueryType;
+use crate::types::{Result, Unavailable};
+
+// Nested lookups for name server addresses and DNSSEC records
+const MAX_LOOKUP_DEPTH: usize = 8;
+// BIND limits recursive queries per client query to 100 by default
+const MAX_UPSTREAM_QUERIES: usize = 100;
+
+#[derive(Debug)]
+pub struct QueryState {
+    deadline: Instant,
+    // Question whose cached answer is being replaced, so it must not be
+    // answered from the cache itself
+    refresh: Option<(String, QueryType)>,
+    queries: Cell<usize>,
+    // Lookups in progress, the client query first
+    lookups: RefCell<Vec<(String, QueryType)>>,
+}
+
+// Marks a lookup as in progress until dropped
+pub struct LookupGuard<'a> {
+    state: &'a QueryState,
+}
+
+impl Drop for LookupGuard<'_> {
+    fn drop(&mut self) {
+        self.state.lookups.borrow_mut().pop();
+    }
+}
+
+impl QueryState {
+    pub fn new(config: &Config) -> QueryState {
+        QueryState::with_budget(config.query_budget)
+    }
+
+    pub fn with_budget(budget: Duration) -> QueryState {
+        QueryState {
+            deadline: Instant::now() + budget,
+            refresh: None,
+            queries: Cell::new(0),
+            lookups: RefCell::new(Vec::new()),
+        }
+    }
+
+    pub fn refresh(config: &Config, qname: &str, qtype: QueryType) -> QueryState {
+        QueryState {
+            refresh: Some((qname.to_string(), qtype)),
+            ..QueryState::new(config)
+        }
+    }
+
+    // Time left before the client query has to be answered with SERVFAIL
+    pub fn remaining(&self) -> Result<Duration> {
+        let now = Instant::now();
+        if now >= self.deadline {
+            return Err(Unavailable("Time budget for the query exhausted".to_string()).into());
+        }
+
+        Ok(self.deadline - now)
+    }
+
+    pub fn bypasses_cache(&self, qname: &str, qtype: QueryType) -> bool {
+        matches!(&self.refresh, Some((name, refresh_type)) if *refresh_type == qtype && name.eq_ignore_ascii_case(qname))
+    }
+
+    // Accounts for a query about to be sent upstream
+    pub fn count_query(&self) -> Result<()> {
+        let queries = self.queries.get() + 1;
+        if queries > MAX_UPSTREAM_QUERIES {
+            return Err(format!(
+                "Query limit of {} upstream queries exhausted",
+                MAX_UPSTREAM_QUERIES
+            )
+            .into());
+        }
+
+        self.queries.set(queries);
+
+        Ok(())
+    }
+
+    // Starts a nested lookup. Needing the answer to a lookup that is still in
+    // progress means the delegations depend on each other, as with zones
+    // whose name servers only live in one another
+    pub fn enter(&self, qname: &str, qtype: QueryType) -> Result<LookupGuard<'_>> {
+        let mut lookups = self.lookups.borrow_mut();
+
+        if lookups
+            .iter()
+            .any(|(name, lookup_type)| *lookup_type == qtype && name.eq_ignore_ascii_case(qname))
+        {
+            let path: Vec<String> = lookups
+                .iter()
+                .map(|(name, lookup_type)| format!("{:?} {}", lookup_type, name))
+                .collect();
+
+            return Err(format!(
+                "Delegation cycle: {} -> {:?} {}",
+                path.join(" -> "),
+                qtype,
+                qname
+            )
+            .into());
+        }
+
+        if lookups.len() >= MAX_LOOKUP_DEPTH {
+            return Err(format!(
+                "Lookup depth limit of {} reached at {:?} {}",
+                MAX_LOOKUP_DEPTH, qtype, qname
+            )
+            .into());
+        }
+
+        lookups.push((qname.to_string(), qtype));
+
+        Ok(LookupGuard { state: self })
+    }
+}
@@ -0,0 +1,93 @@
+// Questions to resolve again in the background, either because their cache
+// entries were answered stale (RFC 8767) or are about to expire
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::cache::record_cache::CacheKey;
+
+const MAX_PENDING: usize = 1000;
+// RFC 8767 4: after refreshing failed, stale data is answered right away
+// instead of trying again for every client query
+const FAILURE_RECHECK: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Default)]
+struct Queue {
+    pending: VecDeque<CacheKey>,
+    // Questions pending or being refreshed
+    active: HashSet<CacheKey>,
+    failures: HashMap<CacheKey, Instant>,
+    closed: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct RefreshQueue {
+    queue: Mutex<Queue>,
+    ready: Condvar,
+}
+
+impl RefreshQueue {
+    pub fn new() -> RefreshQueue {
+        RefreshQueue::default()
+    }
+
+    // Queues the question unless it is queued or being refreshed already
+    pub fn push(&self, key: CacheKey) {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.active.len() >= MAX_PENDING || !queue.active.insert(key.clone()) {
+            return;
+        }
+
+        queue.pending.push_back(key);
+        self.ready.notify_one();
+    }
+
+    // Waits for the next question to refresh, or returns None once the queue
+    // is closed
+    pub fn pop(&self) -> Option<CacheKey> {
+        let mut queue = self.queue.lock().unwrap();
+
+        loop {
+            if queue.closed {
+                return None;
+            }
+
+            if let Some(key) = queue.pending.pop_front() {
+                return Some(key);
+            }
+
+            queue = self.ready.wait(queue).unwrap();
+        }
+    }
+
+    // Wakes up the workers waiting for questions so that they stop
+    pub fn close(&self) {
+        self.queue.lock().unwrap().closed = true;
+        self.ready.notify_all();
+    }
+
+    pub fn finish(&self, key: &CacheKey, success: bool) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.active.remove(key);
+
+        if success {
+            queue.failures.remove(key);
+            return;
+        }
+
+        if queue.failures.len() >= MAX_PENDING {
+            queue
+                .failures
+                .retain(|_, failed_at| failed_at.elapsed() < FAILURE_RECHECK);
+        }
+        queue.failures.insert(key.clone(), Instant::now());
+    }
+
+    // Whether refreshing the question failed too recently to try again
+    pub fn recently_failed(&self, key: &CacheKey) -> bool {
+        let queue = self.queue.lock().unwrap();
+
+        matches!(queue.failures.get(key), Some(failed_at) if failed_at.elapsed() < FAILURE_RECHECK)
+    }
+}
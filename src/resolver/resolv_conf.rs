@@ -0,0 +1,231 @@
+// Stub resolver settings from /etc/resolv.conf, read the way the GNU C
+// library does: the LOCALDOMAIN and RES_OPTIONS environment variables
+// override the search list and options of the file, and a missing file
+// means querying the local name server
+
+use std::env;
+use std::fs;
+use std::io::ErrorKind;
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::types::Result;
+
+// MAXNS, RES_MAXNDOTS, RES_MAXRETRANS and RES_MAXRETRY of resolv.h
+const MAX_NAMESERVERS: usize = 3;
+const MAX_NDOTS: usize = 15;
+pub const MAX_TIMEOUT: u64 = 30;
+pub const MAX_ATTEMPTS: u32 = 5;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolvConf {
+    pub nameservers: Vec<Ipv4Addr>,
+    pub search: Vec<String>,
+    pub ndots: usize,
+    pub timeout: Duration,
+    pub attempts: u32,
+    pub rotate: bool,
+}
+
+impl ResolvConf {
+    pub fn new() -> ResolvConf {
+        ResolvConf {
+            nameservers: Vec::new(),
+            search: Vec::new(),
+            ndots: 1,
+            timeout: Duration::from_secs(5),
+            attempts: 2,
+            rotate: false,
+        }
+    }
+
+    pub fn from_system() -> Result<ResolvConf> {
+        let mut conf = ResolvConf::from_file(Path::new("/etc/resolv.conf"))?;
+
+        if let Ok(domains) = env::var("LOCALDOMAIN") {
+            conf.search = parse_search(domains.split_whitespace());
+        }
+        if let Ok(options) = env::var("RES_OPTIONS") {
+            conf.parse_options(options.split_whitespace());
+        }
+
+        Ok(conf)
+    }
+
+    pub fn from_file(path: &Path) -> Result<ResolvConf> {
+        match fs::read_to_string(path) {
+            Ok(data) => Ok(ResolvConf::parse(&data)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(ResolvConf::parse("")),
+            Err(e) => Err(format!("Failed to read {}: {}", path.display(), e).into()),
+        }
+    }
+
+    // Unknown keywords and options are ignored. Name servers are only
+    // reached over IPv4, so IPv6 ones are skipped
+    pub fn parse(data: &str) -> ResolvConf {
+        let mut conf = ResolvConf::new();
+
+        for line in data.lines() {
+            if line.starts_with(['#', ';']) {
+                continue;
+            }
+
+            let mut tokens = line.split_whitespace();
+            match tokens.next() {
+                Some("nameserver") => {
+                    if let Some(Ok(addr)) = tokens.next().map(|token| token.parse()) {
+                        if conf.nameservers.len() < MAX_NAMESERVERS {
+                            conf.nameservers.push(addr);
+                        }
+                    }
+                }
+                // Whichever of domain and search comes last wins
+                Some("domain") => conf.search = parse_search(tokens.take(1)),
+                Some("search") => conf.search = parse_search(tokens),
+                Some("options") => conf.parse_options(tokens),
+                _ => {}
+            }
+        }
+
+        if conf.nameservers.is_empty() {
+            conf.nameservers.push(Ipv4Addr::LOCALHOST);
+        }
+
+        conf
+    }
+
+    fn parse_options<'a>(&mut self, options: impl Iterator<Item = &'a str>) {
+        for option in options {
+            let (name, value) = match option.split_once(':') {
+                Some((name, value)) => (name, value.parse::<u64>().ok()),
+                None => (option, None),
+            };
+
+            match (name, value) {
+                ("ndots", Some(n)) => self.ndots = (n as usize).min(MAX_NDOTS),
+                ("timeout", Some(n)) => self.timeout = Duration::from_secs(n.clamp(1, MAX_TIMEOUT)),
+                ("attempts", Some(n)) => self.attempts = (n as u32).clamp(1, MAX_ATTEMPTS),
+                ("rotate", _) => self.rotate = true,
+                _ => {}
+            }
+        }
+    }
+
+    // Names to query for the given one in order: names with fewer than ndots
+    // dots are tried in the search domains before being tried as they are,
+    // other names the other way round. A trailing dot disables the search
+    pub fn candidates(&self, name: &str) -> Vec<String> {
+        if let Some(absolute) = name.strip_suffix('.') {
+            return vec![absolute.to_string()];
+        }
+
+        let as_is_first = name.matches('.').count() >= self.ndots;
+        let mut candidates = Vec::new();
+
+        if as_is_first {
+            candidates.push(name.to_string());
+        }
+        for domain in &self.search {
+            candidates.push(format!("{}.{}", name, domain));
+        }
+        if !as_is_first {
+            candidates.push(name.to_string());
+        }
+
+        candidates
+    }
+}
+
+impl Default for ResolvConf {
+    fn default() -> ResolvConf {
+        ResolvConf::new()
+    }
+}
+
+fn parse_search<'a>(domains: impl Iterator<Item = &'a str>) -> Vec<String> {
+    domains
+        .map(|domain| domain.trim_end_matches('.').to_ascii_lowercase())
+        .filter(|domain| !domain.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_file() {
+        let conf = ResolvConf::parse(
+            "# comment\n\
+             ; nameserver 192.0.2.99\n\
+             nameserver 192.0.2.1\n\
+             nameserver 2001:db8::1\n\
+             nameserver 192.0.2.2\n\
+             nameserver 192.0.2.3\n\
+             nameserver 192.0.2.4\n\
+             search Corp.Example. lab.example\n\
+             options ndots:20 timeout:0 attempts:9 rotate unknown:1\n",
+        );
+
+        assert_eq!(
+            conf,
+            ResolvConf {
+                nameservers: vec![
+                    Ipv4Addr::new(192, 0, 2, 1),
+                    Ipv4Addr::new(192, 0, 2, 2),
+                    Ipv4Addr::new(192, 0, 2, 3),
+                ],
+                search: vec!["corp.example".to_string(), "lab.example".to_string()],
+                ndots: MAX_NDOTS,
+                timeout: Duration::from_secs(1),
+                attempts: MAX_ATTEMPTS,
+                rotate: true,
+            }
+        );
+    }
+
+    #[test]
+    fn uses_the_last_of_domain_and_search() {
+        let conf = ResolvConf::parse("search a.example b.example\ndomain c.example d.example\n");
+        assert_eq!(conf.search, vec!["c.example".to_string()]);
+
+        let conf = ResolvConf::parse("domain c.example\nsearch a.example b.example\n");
+        assert_eq!(conf.search, vec!["a.example", "b.example"]);
+    }
+
+    #[test]
+    fn defaults_to_the_local_name_server() {
+        assert_eq!(
+            ResolvConf::parse(""),
+            ResolvConf {
+                nameservers: vec![Ipv4Addr::LOCALHOST],
+                ..ResolvConf::new()
+            }
+        );
+    }
+
+    #[test]
+    fn lists_candidates_by_ndots() {
+        let conf = ResolvConf {
+            search: vec!["corp.example".to_string(), "example".to_string()],
+            ndots: 2,
+            ..ResolvConf::new()
+        };
+
+        assert_eq!(
+            conf.candidates("www.lab"),
+            vec!["www.lab.corp.example", "www.lab.example", "www.lab"]
+        );
+        assert_eq!(
+            conf.candidates("www.lab.example"),
+            vec![
+                "www.lab.example",
+                "www.lab.example.corp.example",
+                "www.lab.example.example"
+            ]
+        );
+        // A trailing dot makes the name absolute
+        assert_eq!(conf.candidates("www."), vec!["www"]);
+    }
+}
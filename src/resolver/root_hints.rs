@@ -0,0 +1,188 @@
+// Root server hints loaded from a named.root style file, refreshed at startup
+// with a priming query as per RFC 8109
+
+use std::fs;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+use std::sync::RwLock;
+
+use crate::models::{dns_packet::DnsPacket, dns_record::DnsRecord};
+use crate::types::Result;
+
+const DEFAULT_ROOT_HINTS: &str = include_str!("named.root");
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RootServer {
+    pub name: String,
+    pub ip_v4_addrs: Vec<Ipv4Addr>,
+    pub ip_v6_addrs: Vec<Ipv6Addr>,
+}
+
+#[derive(Debug)]
+pub struct RootHints {
+    servers: RwLock<Vec<RootServer>>,
+}
+
+impl RootHints {
+    pub fn new() -> RootHints {
+        RootHints::parse(DEFAULT_ROOT_HINTS).expect("bundled root hints are valid")
+    }
+
+    pub fn from_file(path: &Path) -> Result<RootHints> {
+        let data = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read root hints {}: {}", path.display(), e))?;
+
+        RootHints::parse(&data)
+    }
+
+    pub fn parse(data: &str) -> Result<RootHints> {
+        let mut servers: Vec<RootServer> = Vec::new();
+
+        for (idx, line) in data.lines().enumerate() {
+            let line = match line.find(';') {
+                Some(pos) => &line[..pos],
+                None => line,
+            };
+
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            if tokens.is_empty() {
+                continue;
+            }
+
+            // owner [ttl] [class] type rdata
+            let type_pos = match tokens.iter().skip(1).position(|token| {
+                matches!(token.to_ascii_uppercase().as_str(), "NS" | "A" | "AAAA")
+            }) {
+                Some(pos) => pos + 1,
+                None => continue,
+            };
+
+            let owner = normalize_name(tokens[0]);
+            let rdata = match tokens.get(type_pos + 1) {
+                Some(x) => *x,
+                None => return Err(format!("Missing rdata on root hints line {}", idx + 1).into()),
+            };
+
+            match tokens[type_pos].to_ascii_uppercase().as_str() {
+                "NS" => {
+                    if !owner.is_empty() {
+                        return Err(
+                            format!("Unexpected NS owner on root hints line {}", idx + 1).into(),
+                        );
+                    }
+
+                    let name = normalize_name(rdata);
+                    if !servers.iter().any(|server| server.name == name) {
+                        servers.push(RootServer {
+                            name,
+                            ip_v4_addrs: Vec::new(),
+                            ip_v6_addrs: Vec::new(),
+                        });
+                    }
+                }
+                "A" => {
+                    let addr = rdata.parse::<Ipv4Addr>().map_err(|e| {
+                        format!("Invalid address on root hints line {}: {}", idx + 1, e)
+                    })?;
+
+                    if let Some(server) = servers.iter_mut().find(|server| server.name == owner) {
+                        server.ip_v4_addrs.push(addr);
+                    }
+                }
+                _ => {
+                    let addr = rdata.parse::<Ipv6Addr>().map_err(|e| {
+                        format!("Invalid address on root hints line {}: {}", idx + 1, e)
+                    })?;
+
+                    if let Some(server) = servers.iter_mut().find(|server| server.name == owner) {
+                        server.ip_v6_addrs.push(addr);
+                    }
+                }
+            }
+        }
+
+        servers.retain(|server| !server.ip_v4_addrs.is_empty() || !server.ip_v6_addrs.is_empty());
+        if servers.is_empty() {
+            return Err("Root hints contain no usable name servers".into());
+        }
+
+        Ok(RootHints {
+            servers: RwLock::new(servers),
+        })
+    }
+
+    pub fn ip_v4_addrs(&self) -> Vec<Ipv4Addr> {
+        self.servers
+            .read()
+            .unwrap()
+            .iter()
+            .flat_map(|server| server.ip_v4_addrs.iter().copied())
+            .collect()
+    }
+
+    // Replaces the hints with the root NS set from a priming response. Names
+    // without addresses in the additional section keep their hinted addresses
+    pub fn update_from_priming(&self, response: &DnsPacket) -> usize {
+        let mut servers = self.servers.write().unwrap();
+
+        let primed: Vec<RootServer> = response
+            .answers
+            .iter()
+            .filter_map(|record| match record {
+                DnsRecord::NS { domain, host, .. } if domain.is_empty() => {
+                    Some(host.to_ascii_lowercase())
+                }
+                _ => None,
+            })
+            .map(|name| {
+                let mut server = RootServer {
+                    ip_v4_addrs: Vec::new(),
+                    ip_v6_addrs: Vec::new(),
+                    name,
+                };
+
+                for record in &response.additionals {
+                    match record {
+                        DnsRecord::A {
+                            domain, ip_v4_addr, ..
+                        } if domain.eq_ignore_ascii_case(&server.name) => {
+                            server.ip_v4_addrs.push(*ip_v4_addr)
+                        }
+                        DnsRecord::AAAA {
+                            domain, ip_v6_addr, ..
+                        } if domain.eq_ignore_ascii_case(&server.name) => {
+                            server.ip_v6_addrs.push(*ip_v6_addr)
+                        }
+                        _ => {}
+                    }
+                }
+
+                if server.ip_v4_addrs.is_empty() && server.ip_v6_addrs.is_empty() {
+                    if let Some(hint) = servers.iter().find(|hint| hint.name == server.name) {
+                        server.ip_v4_addrs = hint.ip_v4_addrs.clone();
+                        server.ip_v6_addrs = hint.ip_v6_addrs.clone();
+                    }
+                }
+
+                server
+            })
+            .filter(|server| !server.ip_v4_addrs.is_empty() || !server.ip_v6_addrs.is_empty())
+            .collect();
+
+        if !primed.is_empty() {
+            *servers = primed;
+        }
+
+        servers.len()
+    }
+}
+
+impl Default for RootHints {
+    fn default() -> RootHints {
+        RootHints::new()
+    }
+}
+
+fn normalize_name(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
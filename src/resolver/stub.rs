@@ -0,0 +1,226 @@
+// Stub resolver for client programs, behaving like the C library resolver:
+// names are looked up in the hosts file first, then sent to the name servers
+// of resolv.conf with the search list applied
+
+use std::path::Path;
+use std::time::Duration;
+
+use super::api::Resolver;
+use super::forwarder::ForwardStrategy;
+use super::hosts::Hosts;
+use super::resolv_conf::{ResolvConf, MAX_ATTEMPTS, MAX_TIMEOUT};
+use crate::models::{
+    dns_packet::DnsPacket, query_class::QueryClass, query_type::QueryType, result_code::ResultCode,
+};
+use crate::types::Result;
+
+pub struct StubResolver {
+    resolver: Resolver,
+    conf: ResolvConf,
+    hosts: Hosts,
+}
+
+impl StubResolver {
+    pub fn new(conf: ResolvConf, hosts: Hosts) -> Result<StubResolver> {
+        StubResolver::with_port(conf, hosts, 53)
+    }
+
+    fn with_port(mut conf: ResolvConf, hosts: Hosts, port: u16) -> Result<StubResolver> {
+        // The fields may have been set without going through the parser,
+        // which keeps them within the limits of resolv.h
+        conf.attempts = conf.attempts.clamp(1, MAX_ATTEMPTS);
+        conf.timeout = conf
+            .timeout
+            .clamp(Duration::from_secs(1), Duration::from_secs(MAX_TIMEOUT));
+
+        let strategy = match conf.rotate {
+            true => ForwardStrategy::RoundRobin,
+            false => ForwardStrategy::Sequential,
+        };
+
+        // Enough time for every attempt on every name server, the timeout
+        // doubling after each round
+        let rounds = (1u32 << conf.attempts) - 1;
+        let budget = conf.timeout * rounds * conf.nameservers.len() as u32;
+
+        let mut builder = Resolver::builder()
+            .forward_strategy(strategy)
+            .query_timeout(conf.timeout)
+            .query_attempts(conf.attempts)
+            .query_budget(budget);
+        for nameserver in &conf.nameservers {
+            builder = builder.forwarder(*nameserver, port);
+        }
+
+        Ok(StubResolver {
+            resolver: builder.build()?,
+            conf,
+            hosts,
+        })
+    }
+
+    // Uses /etc/resolv.conf and /etc/hosts, either of which may be missing
+    pub fn from_system() -> Result<StubResolver> {
+        let hosts_path = Path::new("/etc/hosts");
+        let hosts = match hosts_path.exists() {
+            true => Hosts::from_file(hosts_path)?,
+            false => Hosts::new(),
+        };
+
+        StubResolver::new(ResolvConf::from_system()?, hosts)
+    }
+
+    pub fn conf(&self) -> &ResolvConf {
+        &self.conf
+    }
+
+    // Tries the candidate names of the search list until one has records of
+    // the type. Like the C library, the search goes on past names that do not
+    // exist or lack the type, and stops when the name servers cannot be
+    // reached. Without any answer, a name that exists is preferred over one
+    // that does not
+    pub fn resolve(&self, name: &str, qtype: QueryType, qclass: QueryClass) -> Result<DnsPacket> {
+        if let Some(packet) = self
+            .hosts
+            .answer(name, qtype, qclass)
+            .filter(|packet| !packet.answers.is_empty())
+        {
+            return Ok(packet);
+        }
+
+        let mut nodata = None;
+        let mut last = None;
+
+        for candidate in self.conf.candidates(name) {
+            let response = self.resolver.resolve(&candidate, qtype, qclass)?;
+
+            match response.header.result_code {
+                ResultCode::NOERROR if !response.answers.is_empty() => return Ok(response),
+                ResultCode::NOERROR => {
+                    nodata.get_or_insert(response);
+                }
+                _ => last = Some(response),
+            }
+        }
+
+        nodata
+            .or(last)
+            .ok_or_else(|| format!("No name to query for {:?}", name).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, UdpSocket};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    use super::*;
+    use crate::models::dns_record::DnsRecord;
+    use crate::utils::byte_packet_buffer::BytePacketBuffer;
+
+    // Name server on a local port where www.corp.example has an address,
+    // www.lab.example exists without one and every other name does not
+    // exist. Returns the port along with the names queried
+    fn name_server() -> (u16, Arc<Mutex<Vec<String>>>) {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = socket.local_addr().unwrap().port();
+        let queried = Arc::new(Mutex::new(Vec::new()));
+
+        let names = queried.clone();
+        thread::spawn(move || loop {
+            let mut buffer = BytePacketBuffer::new();
+            let (_, src) = socket.recv_from(&mut buffer.buf).unwrap();
+            let request = DnsPacket::from_buffer(&mut buffer).unwrap();
+            let question = &request.questions[0];
+            names
+                .lock()
+                .unwrap()
+                .push(question.name.to_ascii_lowercase());
+
+            let mut response = DnsPacket::new();
+            response.header.id = request.header.id;
+            response.header.is_response = true;
+            response.header.recursion_desired = true;
+            response.header.recursion_available = true;
+            response.header.questions_count = 1;
+            response.questions = request.questions.clone();
+
+            match question.name.to_ascii_lowercase().as_str() {
+                "www.corp.example" if question.qtype == QueryType::A => {
+                    response.header.answers_count = 1;
+                    response.answers.push(DnsRecord::A {
+                        domain: question.name.clone(),
+                        ip_v4_addr: Ipv4Addr::new(192, 0, 2, 1),
+                        ttl: 300,
+                    });
+                }
+                "www.corp.example" | "www.lab.example" => {}
+                _ => response.header.result_code = ResultCode::NXDOMAIN,
+            }
+
+            let mut res_buffer = BytePacketBuffer::new();
+            response.to_buffer(&mut res_buffer).unwrap();
+            socket
+                .send_to(&res_buffer.buf[..res_buffer.pos()], src)
+                .unwrap();
+        });
+
+        (port, queried)
+    }
+
+    fn stub(port: u16) -> StubResolver {
+        let conf = ResolvConf {
+            nameservers: vec![Ipv4Addr::LOCALHOST],
+            search: vec![
+                "missing.example".to_string(),
+                "lab.example".to_string(),
+                "corp.example".to_string(),
+            ],
+            attempts: 1,
+            ..ResolvConf::new()
+        };
+
+        StubResolver::with_port(conf, Hosts::new(), port).unwrap()
+    }
+
+    #[test]
+    fn searches_past_missing_names_and_types() {
+        let (port, queried) = name_server();
+
+        let response = stub(port)
+            .resolve("www", QueryType::A, QueryClass::IN)
+            .unwrap();
+
+        assert_eq!(response.answers[0].domain(), "www.corp.example");
+        assert_eq!(
+            *queried.lock().unwrap(),
+            vec!["www.missing.example", "www.lab.example", "www.corp.example"]
+        );
+    }
+
+    #[test]
+    fn prefers_names_that_exist_without_an_answer() {
+        let (port, queried) = name_server();
+
+        let response = stub(port)
+            .resolve("www", QueryType::MX, QueryClass::IN)
+            .unwrap();
+
+        assert_eq!(response.header.result_code, ResultCode::NOERROR);
+        assert!(response.answers.is_empty());
+        assert_eq!(queried.lock().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn keeps_attempts_within_limits() {
+        let conf = ResolvConf {
+            attempts: 40,
+            ..ResolvConf::new()
+        };
+
+        let stub = StubResolver::new(conf, Hosts::new()).unwrap();
+
+        assert_eq!(stub.conf().attempts, MAX_ATTEMPTS);
+    }
+}
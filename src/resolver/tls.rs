@@ -0,0 +1,668 @@
+// DNS over TLS (RFC 7858). A connection to an upstream is kept open and shared
+// between queries, which are pipelined over it while a reader thread matches
+// the replies to them by ID, as they may come back out of order (RFC 7766 6.2.1.1)
+
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr, TcpStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::debug;
+use ring::digest;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{
+    ring as ring_provider, verify_tls12_signature, verify_tls13_signature,
+    WebPkiSupportedAlgorithms,
+};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{
+    CertificateError, ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore,
+    SignatureScheme,
+};
+
+use super::pool::{Connection, ConnectionPool};
+use super::transport::{check_reply, remaining, write_tcp_message};
+use crate::models::dns_packet::DnsPacket;
+use crate::types::Result;
+use crate::utils::byte_packet_buffer::{BytePacketBuffer, MAX_TCP_SIZE};
+
+pub const DEFAULT_TLS_PORT: u16 = 853;
+// Connections without outstanding queries are closed after this long
+const TLS_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TlsAuth {
+    Hostname(String),
+    // SHA-256 digests of the SubjectPublicKeyInfo the server may present,
+    // as in the out-of-band key-pinned profile (RFC 7858 4.2)
+    SpkiPins(Vec<Vec<u8>>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsUpstreamConfig {
+    pub server: (Ipv4Addr, u16),
+    pub auth: TlsAuth,
+}
+
+#[derive(Debug)]
+struct SpkiPinVerifier {
+    pins: Vec<Vec<u8>>,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ServerCertVerifier for SpkiPinVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        let spki = subject_public_key_info(end_entity).ok_or(rustls::Error::InvalidCertificate(
+            CertificateError::BadEncoding,
+        ))?;
+        let hash = digest::digest(&digest::SHA256, spki);
+
+        if self.pins.iter().any(|pin| pin.as_slice() == hash.as_ref()) {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+// Splits off the first DER element of data into its tag, contents and
+// whatever follows it
+fn der_element(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let tag = *data.first()?;
+    let first = *data.get(1)? as usize;
+
+    let (len, header) = if first < 0x80 {
+        (first, 2)
+    } else {
+        let count = first & 0x7f;
+        if count == 0 || count > 4 {
+            return None;
+        }
+
+        let len = data
+            .get(2..2 + count)?
+            .iter()
+            .fold(0, |len, byte| (len << 8) | *byte as usize);
+        (len, 2 + count)
+    };
+
+    let contents = data.get(header..header.checked_add(len)?)?;
+    Some((tag, contents, &data[header + len..]))
+}
+
+// Returns the encoded subjectPublicKeyInfo of an X.509 certificate, the 7th
+// field of tbsCertificate when the optional version is present (RFC 5280 4.1)
+fn subject_public_key_info(cert: &[u8]) -> Option<&[u8]> {
+    let (_, certificate, _) = der_element(cert)?;
+    let (_, mut fields, _) = der_element(certificate)?;
+
+    if fields.first() == Some(&0xa0) {
+        fields = der_element(fields)?.2;
+    }
+
+    // serialNumber, signature, issuer, validity and subject
+    for _ in 0..5 {
+        fields = der_element(fields)?.2;
+    }
+
+    let (_, _, rest) = der_element(fields)?;
+    Some(&fields[..fields.len() - rest.len()])
+}
+
+// A TLS session over TCP that several threads may write to, while a single
+// reader thread reads from its own clone of the socket
+pub struct TlsSession {
+    tls: Mutex<ClientConnection>,
+    socket: TcpStream,
+}
+
+impl TlsSession {
+    pub fn connect(
+        config: Arc<ClientConfig>,
+        server_name: ServerName<'static>,
+        server: SocketAddr,
+        deadline: Instant,
+    ) -> Result<(TlsSession, TcpStream)> {
+        let mut socket = TcpStream::connect_timeout(&server, remaining(deadline, server)?)?;
+        socket.set_nodelay(true)?;
+
+        let mut tls = ClientConnection::new(config, server_name)?;
+        while tls.is_handshaking() {
+            socket.set_read_timeout(Some(remaining(deadline, server)?))?;
+            socket.set_write_timeout(Some(remaining(deadline, server)?))?;
+            tls.complete_io(&mut socket)?;
+        }
+
+        let reader = socket.try_clone()?;
+        let session = TlsSession {
+            tls: Mutex::new(tls),
+            socket,
+        };
+
+        Ok((session, reader))
+    }
+
+    pub fn alpn_protocol(&self) -> Option<Vec<u8>> {
+        self.tls.lock().unwrap().alpn_protocol().map(|x| x.to_vec())
+    }
+
+    pub fn write(&self, data: &[u8], deadline: Instant) -> Result<()> {
+        let server = self.socket.peer_addr()?;
+        let mut tls = self.tls.lock().unwrap();
+
+        tls.writer().write_all(data)?;
+        self.socket
+            .set_write_timeout(Some(remaining(deadline, server)?))?;
+        while tls.wants_write() {
+            tls.write_tls(&mut &self.socket)?;
+        }
+
+        Ok(())
+    }
+
+    // Decrypts what the reader got from the socket and appends the plaintext
+    // to received. Returns false once the peer has closed the session
+    pub fn receive(&self, mut data: &[u8], received: &mut Vec<u8>) -> Result<bool> {
+        let mut tls = self.tls.lock().unwrap();
+        let mut open = true;
+
+        // read_tls may take only part of the data, as rustls buffers a
+        // limited amount of records until they are processed
+        while !data.is_empty() {
+            tls.read_tls(&mut data)?;
+            let state = tls.process_new_packets()?;
+
+            let start = received.len();
+            received.resize(start + state.plaintext_bytes_to_read(), 0);
+            tls.reader().read_exact(&mut received[start..])?;
+
+            if state.peer_has_closed() {
+                open = false;
+                break;
+            }
+        }
+
+        // Alerts and key updates may need an answer
+        while tls.wants_write() {
+            tls.write_tls(&mut &self.socket)?;
+        }
+
+        Ok(open)
+    }
+
+    pub fn shutdown(&self) {
+        let mut tls = self.tls.lock().unwrap();
+        tls.send_close_notify();
+        let _ = tls.write_tls(&mut &self.socket);
+        let _ = self.socket.shutdown(Shutdown::Both);
+    }
+}
+
+// Reads the next chunk for a reader thread. None means the read timed out,
+// which happens after TLS_IDLE_TIMEOUT without traffic
+pub fn read_chunk(socket: &mut TcpStream, chunk: &mut [u8]) -> Result<Option<usize>> {
+    socket.set_read_timeout(Some(TLS_IDLE_TIMEOUT))?;
+
+    match socket.read(chunk) {
+        Ok(size) => Ok(Some(size)),
+        Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Certificates are verified against the given CA file, or the bundled Mozilla
+// roots when there is none
+pub fn load_roots(ca_file: Option<&Path>) -> Result<Arc<RootCertStore>> {
+    let mut roots = RootCertStore::empty();
+
+    match ca_file {
+        Some(path) => {
+            for cert in CertificateDer::pem_file_iter(path)? {
+                roots.add(cert?)?;
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+
+    Ok(Arc::new(roots))
+}
+
+pub fn client_config(
+    auth: &TlsAuth,
+    server: Ipv4Addr,
+    roots: &Arc<RootCertStore>,
+    alpn_protocols: &[&[u8]],
+) -> Result<(Arc<ClientConfig>, ServerName<'static>)> {
+    let provider = Arc::new(ring_provider::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+
+    let (mut config, server_name) = match auth {
+        TlsAuth::Hostname(name) => (
+            builder
+                .with_root_certificates(roots.clone())
+                .with_no_client_auth(),
+            ServerName::try_from(name.clone())?,
+        ),
+        TlsAuth::SpkiPins(pins) => (
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(SpkiPinVerifier {
+                    pins: pins.clone(),
+                    algorithms: provider.signature_verification_algorithms,
+                }))
+                .with_no_client_auth(),
+            ServerName::from(IpAddr::V4(server)),
+        ),
+    };
+    config.alpn_protocols = alpn_protocols.iter().map(|x| x.to_vec()).collect();
+
+    Ok((Arc::new(config), server_name))
+}
+
+struct TlsConnection {
+    session: TlsSession,
+    pending: Mutex<HashMap<u16, Sender<BytePacketBuffer>>>,
+    closed: AtomicBool,
+}
+
+impl TlsConnection {
+    fn connect(
+        config: Arc<ClientConfig>,
+        server_name: ServerName<'static>,
+        server: SocketAddr,
+        deadline: Instant,
+    ) -> Result<Arc<TlsConnection>> {
+        let (session, reader) = TlsSession::connect(config, server_name, server, deadline)?;
+        let connection = Arc::new(TlsConnection {
+            session,
+            pending: Mutex::new(HashMap::new()),
+            closed: AtomicBool::new(false),
+        });
+
+        let shared = connection.clone();
+        thread::spawn(move || {
+            if let Err(e) = shared.read_replies(reader) {
+                debug!("TLS connection to {} failed: {}", server, e);
+            }
+            shared.close();
+        });
+
+        Ok(connection)
+    }
+
+    // Fails every outstanding query, their receivers see the sender dropped
+    fn close(&self) {
+        let mut pending = self.pending.lock().unwrap();
+        if self.closed.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        pending.clear();
+        drop(pending);
+
+        self.session.shutdown();
+    }
+
+    // Marking the connection closed under the lock keeps a query from
+    // slipping in between the check and the shutdown
+    fn close_if_idle(&self) -> bool {
+        let pending = self.pending.lock().unwrap();
+        if !pending.is_empty() {
+            return false;
+        }
+        self.closed.store(true, Ordering::Release);
+        drop(pending);
+
+        self.session.shutdown();
+        true
+    }
+
+    fn send(&self, id: u16, data: &[u8], deadline: Instant) -> Result<Receiver<BytePacketBuffer>> {
+        let (sender, receiver) = mpsc::channel();
+
+        {
+            let mut pending = self.pending.lock().unwrap();
+            if self.is_closed() {
+                return Err("TLS connection closed".into());
+            }
+            if pending.contains_key(&id) {
+                return Err(format!("Query ID {} already in flight", id).into());
+            }
+            pending.insert(id, sender);
+        }
+
+        let mut message = Vec::with_capacity(data.len() + 2);
+        write_tcp_message(&mut message, data)?;
+
+        if let Err(e) = self.session.write(&message, deadline) {
+            self.close();
+            return Err(e);
+        }
+
+        Ok(receiver)
+    }
+
+    fn cancel(&self, id: u16) {
+        self.pending.lock().unwrap().remove(&id);
+    }
+
+    fn read_replies(&self, mut socket: TcpStream) -> Result<()> {
+        let mut received = Vec::new();
+        let mut chunk = [0; 4096];
+
+        loop {
+            let size = match read_chunk(&mut socket, &mut chunk) {
+                Ok(Some(0)) => return Ok(()),
+                Ok(Some(size)) => size,
+                Ok(None) if self.close_if_idle() => return Ok(()),
+                Ok(None) => continue,
+                Err(_) if self.is_closed() => return Ok(()),
+                Err(e) => return Err(e),
+            };
+
+            if !self.session.receive(&chunk[..size], &mut received)? && received.is_empty() {
+                return Ok(());
+            }
+
+            while received.len() >= 2 {
+                let len = u16::from_be_bytes([received[0], received[1]]) as usize;
+                if received.len() < len + 2 {
+                    break;
+                }
+
+                let mut buffer = BytePacketBuffer::with_size(len);
+                buffer.buf.copy_from_slice(&received[2..len + 2]);
+                received.drain(..len + 2);
+
+                if len < 2 {
+                    continue;
+                }
+
+                let id = u16::from_be_bytes([buffer.buf[0], buffer.buf[1]]);
+                match self.pending.lock().unwrap().remove(&id) {
+                    Some(sender) => {
+                        let _ = sender.send(buffer);
+                    }
+                    None => debug!("discarding TLS reply with unexpected id {}", id),
+                }
+            }
+        }
+    }
+}
+
+impl Connection for TlsConnection {
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+}
+
+struct TlsUpstream {
+    config: Arc<ClientConfig>,
+    server_name: ServerName<'static>,
+    pool: ConnectionPool<TlsConnection>,
+}
+
+pub struct TlsClient {
+    upstreams: HashMap<(Ipv4Addr, u16), TlsUpstream>,
+}
+
+impl TlsClient {
+    pub fn new(upstreams: &[TlsUpstreamConfig], roots: &Arc<RootCertStore>) -> Result<TlsClient> {
+        let mut clients = HashMap::new();
+
+        for upstream in upstreams {
+            let (config, server_name) =
+                client_config(&upstream.auth, upstream.server.0, roots, &[])?;
+
+            clients.insert(
+                upstream.server,
+                TlsUpstream {
+                    config,
+                    server_name,
+                    pool: ConnectionPool::new("TLS"),
+                },
+            );
+        }
+
+        Ok(TlsClient { upstreams: clients })
+    }
+
+    pub fn is_tls_upstream(&self, server: (Ipv4Addr, u16)) -> bool {
+        self.upstreams.contains_key(&server)
+    }
+
+    pub fn exchange(
+        &self,
+        request: &DnsPacket,
+        server: (Ipv4Addr, u16),
+        deadline: Instant,
+        exact_case: bool,
+    ) -> Result<DnsPacket> {
+        let upstream = self
+            .upstreams
+            .get(&server)
+            .ok_or_else(|| format!("No TLS settings for {}:{}", server.0, server.1))?;
+        let server = SocketAddr::from(server);
+
+        let mut req_buffer = BytePacketBuffer::with_size(MAX_TCP_SIZE);
+        request.to_buffer(&mut req_buffer)?;
+        let data = &req_buffer.buf[0..req_buffer.pos];
+        let id = request.header.id;
+
+        let connect = || {
+            TlsConnection::connect(
+                upstream.config.clone(),
+                upstream.server_name.clone(),
+                server,
+                deadline,
+            )
+        };
+        let (connection, receiver) = upstream.pool.send(server, connect, |connection| {
+            connection.send(id, data, deadline)
+        })?;
+
+        let mut res_buffer = match receiver.recv_timeout(remaining(deadline, server)?) {
+            Ok(buffer) => buffer,
+            Err(RecvTimeoutError::Timeout) => {
+                connection.cancel(id);
+                return Err(format!("Timed out waiting for a reply from {}", server).into());
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(format!("TLS connection to {} closed", server).into())
+            }
+        };
+
+        let response = DnsPacket::from_buffer(&mut res_buffer)?;
+        check_reply(request, &response, exact_case)
+            .map_err(|e| format!("Reply from {} over TLS with {}", server, e))?;
+
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::net::Ipv4Addr;
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use crate::models::{
+        dns_question::DnsQuestion, dns_record::DnsRecord, query_class::QueryClass,
+        query_type::QueryType,
+    };
+    use crate::resolver::tls_stand_in::{answer, authority, serve, TlsStream, ANSWER_ADDR};
+    use crate::resolver::transport::read_tcp_message;
+
+    const SERVER_NAME: &str = "dns.test";
+
+    fn query(id: u16, name: &str) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.header.id = id;
+        packet.header.recursion_desired = true;
+        packet.header.questions_count = 1;
+        packet.questions.push(DnsQuestion::new(
+            name.to_string(),
+            QueryType::A,
+            QueryClass::IN,
+        ));
+        packet
+    }
+
+    // Answers up to limit queries on the connection, then closes it
+    fn answer_queries(mut stream: TlsStream, limit: usize) {
+        for _ in 0..limit {
+            let Ok(mut buffer) = read_tcp_message(&mut stream) else {
+                return;
+            };
+            let request = DnsPacket::from_buffer(&mut buffer).unwrap();
+
+            let mut response = answer(&request);
+            // Names starting with "big" get a reply spanning several TLS records
+            if request.questions[0].name.starts_with("big") {
+                let record = response.answers[0].clone();
+                response.answers = vec![record; 2000];
+                response.header.answers_count = 2000;
+            }
+
+            let mut res_buffer = BytePacketBuffer::with_size(MAX_TCP_SIZE);
+            response.to_buffer(&mut res_buffer).unwrap();
+            write_tcp_message(&mut stream, &res_buffer.buf[..res_buffer.pos]).unwrap();
+            stream.flush().unwrap();
+        }
+
+        stream.conn.send_close_notify();
+        let _ = stream.flush();
+    }
+
+    fn client(name: &str, server: (Ipv4Addr, u16), roots: &Arc<RootCertStore>) -> TlsClient {
+        let upstream = TlsUpstreamConfig {
+            server,
+            auth: TlsAuth::Hostname(name.to_string()),
+        };
+        TlsClient::new(&[upstream], roots).unwrap()
+    }
+
+    fn deadline() -> Instant {
+        Instant::now() + Duration::from_secs(5)
+    }
+
+    #[test]
+    fn answers_queries_over_one_connection() {
+        let (roots, config) = authority(SERVER_NAME, &[]);
+        let stand_in = serve(config, |stream, _| answer_queries(stream, usize::MAX));
+        let client = client(SERVER_NAME, stand_in.server, &roots);
+
+        for id in 1..=3 {
+            let request = query(id, "www.example.com");
+            let response = client
+                .exchange(&request, stand_in.server, deadline(), false)
+                .unwrap();
+
+            assert_eq!(response.header.id, id);
+            assert!(matches!(
+                response.answers.as_slice(),
+                [DnsRecord::A { ip_v4_addr, .. }] if *ip_v4_addr == ANSWER_ADDR
+            ));
+        }
+
+        assert_eq!(stand_in.accepted(), 1);
+    }
+
+    #[test]
+    fn reassembles_replies_spanning_several_records() {
+        let (roots, config) = authority(SERVER_NAME, &[]);
+        let stand_in = serve(config, |stream, _| answer_queries(stream, usize::MAX));
+        let client = client(SERVER_NAME, stand_in.server, &roots);
+
+        let response = client
+            .exchange(
+                &query(7, "big.example.com"),
+                stand_in.server,
+                deadline(),
+                false,
+            )
+            .unwrap();
+
+        assert_eq!(response.answers.len(), 2000);
+    }
+
+    #[test]
+    fn reconnects_once_the_server_closed_the_connection() {
+        let (roots, config) = authority(SERVER_NAME, &[]);
+        let stand_in = serve(config, |stream, _| answer_queries(stream, 1));
+        let client = client(SERVER_NAME, stand_in.server, &roots);
+
+        for id in 1..=2 {
+            let response = client
+                .exchange(
+                    &query(id, "www.example.com"),
+                    stand_in.server,
+                    deadline(),
+                    false,
+                )
+                .unwrap();
+            assert_eq!(response.header.id, id);
+
+            // Wait for the reader thread to see the connection closed
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        assert_eq!(stand_in.accepted(), 2);
+    }
+
+    #[test]
+    fn rejects_a_certificate_for_another_name() {
+        let (roots, config) = authority(SERVER_NAME, &[]);
+        let stand_in = serve(config, |stream, _| answer_queries(stream, usize::MAX));
+        let client = client("other.test", stand_in.server, &roots);
+
+        let error = client
+            .exchange(
+                &query(1, "www.example.com"),
+                stand_in.server,
+                deadline(),
+                false,
+            )
+            .unwrap_err();
+
+        assert!(error.to_string().contains("certificate"), "{}", error);
+    }
+}
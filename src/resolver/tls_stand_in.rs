@@ -0,0 +1,116 @@
+// Local TLS server standing in for DNS over TLS and HTTPS upstreams in tests,
+// with a certificate issued by a CA made up for the occasion
+
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+use rustls::crypto::ring as ring_provider;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use rustls::{RootCertStore, ServerConfig, ServerConnection, StreamOwned};
+
+use crate::models::{dns_packet::DnsPacket, dns_record::DnsRecord, result_code::ResultCode};
+
+pub type TlsStream = StreamOwned<ServerConnection, TcpStream>;
+
+pub const ANSWER_ADDR: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+
+// Roots trusting the made up CA, and the server settings presenting a
+// certificate for name signed by it
+pub fn authority(name: &str, alpn_protocols: &[&[u8]]) -> (Arc<RootCertStore>, Arc<ServerConfig>) {
+    let ca_key = KeyPair::generate().unwrap();
+    let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca = ca_params.self_signed(&ca_key).unwrap();
+
+    let key = KeyPair::generate().unwrap();
+    let cert = CertificateParams::new(vec![name.to_string()])
+        .unwrap()
+        .signed_by(&key, &ca, &ca_key)
+        .unwrap();
+
+    let mut roots = RootCertStore::empty();
+    roots.add(CertificateDer::from(ca.der().to_vec())).unwrap();
+
+    let mut config =
+        ServerConfig::builder_with_provider(Arc::new(ring_provider::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![CertificateDer::from(cert.der().to_vec())],
+                PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.serialize_der())),
+            )
+            .unwrap();
+    config.alpn_protocols = alpn_protocols.iter().map(|x| x.to_vec()).collect();
+
+    (Arc::new(roots), Arc::new(config))
+}
+
+pub struct StandIn {
+    pub server: (Ipv4Addr, u16),
+    // Connections accepted so far
+    pub accepted: Arc<AtomicUsize>,
+}
+
+impl StandIn {
+    pub fn accepted(&self) -> usize {
+        self.accepted.load(Ordering::SeqCst)
+    }
+}
+
+// Accepts connections on a local port, handing each to handle on a thread of
+// its own along with its index
+pub fn serve<F>(config: Arc<ServerConfig>, handle: F) -> StandIn
+where
+    F: Fn(TlsStream, usize) + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let accepted = Arc::new(AtomicUsize::new(0));
+
+    let counter = accepted.clone();
+    let handle = Arc::new(handle);
+    thread::spawn(move || {
+        for socket in listener.incoming() {
+            let Ok(socket) = socket else {
+                continue;
+            };
+            let index = counter.fetch_add(1, Ordering::SeqCst);
+            let connection = ServerConnection::new(config.clone()).unwrap();
+            let handle = handle.clone();
+            thread::spawn(move || handle(StreamOwned::new(connection, socket), index));
+        }
+    });
+
+    StandIn {
+        server: (Ipv4Addr::LOCALHOST, port),
+        accepted,
+    }
+}
+
+// A NOERROR response to the query with a single A record
+pub fn answer(request: &DnsPacket) -> DnsPacket {
+    let mut response = DnsPacket::new();
+    response.header.id = request.header.id;
+    response.header.is_response = true;
+    response.header.recursion_desired = request.header.recursion_desired;
+    response.header.recursion_available = true;
+    response.header.result_code = ResultCode::NOERROR;
+    response.questions = request.questions.clone();
+    response.answers = request
+        .questions
+        .iter()
+        .map(|question| DnsRecord::A {
+            domain: question.name.clone(),
+            ip_v4_addr: ANSWER_ADDR,
+            ttl: 300,
+        })
+        .collect();
+    response.header.questions_count = response.questions.len() as u16;
+    response.header.answers_count = response.answers.len() as u16;
+
+    response
+}
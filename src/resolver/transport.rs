@@ -0,0 +1,172 @@
+// Transports used to exchange a query with an upstream server. UDP comes
+// first, TCP is used when the reply does not fit into a datagram (RFC 7766).
+// Upstreams configured for it are reached over TLS or HTTPS instead, see
+// tls.rs and https.rs
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream, UdpSocket};
+use std::time::{Duration, Instant};
+
+use log::debug;
+use rand::{thread_rng, Rng};
+
+use crate::models::dns_packet::DnsPacket;
+use crate::types::Result;
+use crate::utils::byte_packet_buffer::{BytePacketBuffer, MAX_TCP_SIZE};
+
+const MAX_BIND_ATTEMPTS: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Udp,
+    Tcp,
+    Tls,
+    Https,
+}
+
+// Binds to a random source port so that replies cannot be spoofed without
+// guessing it along with the query ID (RFC 5452)
+fn bind_random_port() -> Result<UdpSocket> {
+    let mut rng = thread_rng();
+
+    for _ in 0..MAX_BIND_ATTEMPTS {
+        let port = rng.gen_range(1024..=u16::MAX);
+        if let Ok(socket) = UdpSocket::bind(("0.0.0.0", port)) {
+            return Ok(socket);
+        }
+    }
+
+    Ok(UdpSocket::bind(("0.0.0.0", 0))?)
+}
+
+pub fn remaining(deadline: Instant, server: SocketAddr) -> Result<Duration> {
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    if remaining.is_zero() {
+        return Err(format!("Timed out waiting for a reply from {}", server).into());
+    }
+
+    Ok(remaining)
+}
+
+// A reply has to carry our ID and echo the question back. With 0x20 encoding
+// the echoed name must match the casing that was sent exactly
+pub fn check_reply(request: &DnsPacket, response: &DnsPacket, exact_case: bool) -> Result<()> {
+    if !response.header.is_response || response.header.id != request.header.id {
+        return Err("mismatched id".into());
+    }
+
+    let question_matches = response.questions.len() == request.questions.len()
+        && response
+            .questions
+            .iter()
+            .zip(request.questions.iter())
+            .all(|(received, sent)| {
+                received.qtype == sent.qtype
+                    && received.qclass == sent.qclass
+                    && if exact_case {
+                        received.name == sent.name
+                    } else {
+                        received.name.eq_ignore_ascii_case(&sent.name)
+                    }
+            });
+
+    if !question_matches {
+        return Err("mismatched question".into());
+    }
+
+    Ok(())
+}
+
+pub fn exchange_udp(
+    request: &DnsPacket,
+    server: SocketAddr,
+    deadline: Instant,
+    exact_case: bool,
+) -> Result<DnsPacket> {
+    let socket = bind_random_port()?;
+
+    let mut req_buffer = BytePacketBuffer::new();
+    request.to_buffer(&mut req_buffer)?;
+    socket.send_to(&req_buffer.buf[0..req_buffer.pos], server)?;
+
+    // Keep reading until a datagram that matches the outstanding query arrives,
+    // anything else is either stale or spoofed
+    loop {
+        socket.set_read_timeout(Some(remaining(deadline, server)?))?;
+
+        let mut res_buffer = BytePacketBuffer::with_size(MAX_TCP_SIZE);
+        let (size, src) = socket.recv_from(&mut res_buffer.buf)?;
+
+        if src != server {
+            debug!("discarding reply from unexpected source {}", src);
+            continue;
+        }
+
+        let mut response = match DnsPacket::from_buffer(&mut res_buffer) {
+            Ok(x) => x,
+            Err(e) => {
+                debug!("discarding malformed reply from {}: {}", src, e);
+                continue;
+            }
+        };
+
+        if let Err(e) = check_reply(request, &response, exact_case) {
+            debug!("discarding reply from {} with {}", src, e);
+            continue;
+        }
+
+        // Anything larger than we are prepared to accept over UDP is handled
+        // as if it had been truncated
+        if size > request.udp_payload_size() {
+            response.header.truncated_message = true;
+        }
+
+        return Ok(response);
+    }
+}
+
+pub fn exchange_tcp(
+    request: &DnsPacket,
+    server: SocketAddr,
+    deadline: Instant,
+    exact_case: bool,
+) -> Result<DnsPacket> {
+    let mut stream = TcpStream::connect_timeout(&server, remaining(deadline, server)?)?;
+    stream.set_write_timeout(Some(remaining(deadline, server)?))?;
+
+    let mut req_buffer = BytePacketBuffer::with_size(MAX_TCP_SIZE);
+    request.to_buffer(&mut req_buffer)?;
+    write_tcp_message(&mut stream, &req_buffer.buf[0..req_buffer.pos])?;
+
+    stream.set_read_timeout(Some(remaining(deadline, server)?))?;
+    let mut res_buffer = read_tcp_message(&mut stream)?;
+    let response = DnsPacket::from_buffer(&mut res_buffer)?;
+
+    check_reply(request, &response, exact_case)
+        .map_err(|e| format!("Reply from {} over TCP with {}", server, e))?;
+
+    Ok(response)
+}
+
+pub fn write_tcp_message<W: Write>(stream: &mut W, data: &[u8]) -> Result<()> {
+    if data.len() > MAX_TCP_SIZE {
+        return Err("Message too large for TCP".into());
+    }
+
+    let mut message = Vec::with_capacity(data.len() + 2);
+    message.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    message.extend_from_slice(data);
+    stream.write_all(&message)?;
+
+    Ok(())
+}
+
+pub fn read_tcp_message<R: Read>(stream: &mut R) -> Result<BytePacketBuffer> {
+    let mut len = [0; 2];
+    stream.read_exact(&mut len)?;
+
+    let mut buffer = BytePacketBuffer::with_size(u16::from_be_bytes(len) as usize);
+    stream.read_exact(&mut buffer.buf)?;
+
+    Ok(buffer)
+}
@@ -0,0 +1,333 @@
+// DNS server answering clients over UDP and TCP with a Resolver, resolving
+// their queries concurrently
+
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::{Condvar, Mutex};
+use std::thread::{self, Scope};
+use std::time::Duration;
+
+use log::{error, info, warn};
+
+use crate::context::ServerContext;
+use crate::models::{
+    dns_packet::DnsPacket, dns_record::DnsRecord, query_type::QueryType, result_code::ResultCode,
+};
+use crate::resolver::api::Resolver;
+use crate::resolver::transport::{read_tcp_message, write_tcp_message};
+use crate::types::Result;
+use crate::utils::byte_packet_buffer::{BytePacketBuffer, EDNS_PACKET_SIZE, MAX_TCP_SIZE};
+
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_TCP_CONNECTIONS: usize = 100;
+
+// Counts the work in progress against an upper bound, each slot being
+// released when its guard is dropped
+struct ConcurrencyLimit {
+    max: usize,
+    active: Mutex<usize>,
+    released: Condvar,
+}
+
+struct Slot<'a> {
+    limit: &'a ConcurrencyLimit,
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        *self.limit.active.lock().unwrap() -= 1;
+        self.limit.released.notify_one();
+    }
+}
+
+impl ConcurrencyLimit {
+    fn new(max: usize) -> ConcurrencyLimit {
+        ConcurrencyLimit {
+            max,
+            active: Mutex::new(0),
+            released: Condvar::new(),
+        }
+    }
+
+    fn try_acquire(&self) -> Option<Slot<'_>> {
+        let mut active = self.active.lock().unwrap();
+        if *active >= self.max {
+            return None;
+        }
+        *active += 1;
+
+        Some(Slot { limit: self })
+    }
+
+    // Waits for a slot to become free
+    fn acquire(&self) -> Slot<'_> {
+        let mut active = self.active.lock().unwrap();
+        while *active >= self.max {
+            active = self.released.wait(active).unwrap();
+        }
+        *active += 1;
+
+        Slot { limit: self }
+    }
+}
+
+fn build_response(resolver: &Resolver, mut request: DnsPacket) -> DnsPacket {
+    let mut packet = DnsPacket::new();
+    packet.header.id = request.header.id;
+    packet.header.recursion_desired = true;
+    packet.header.recursion_available = true;
+    packet.header.is_response = true;
+    packet.header.checking_disabled = request.header.checking_disabled;
+
+    let context = resolver.context();
+    let dnssec_ok = request.dnssec_ok();
+
+    if let Some(question) = request.questions.pop() {
+        context.trace(format_args!("Received query: {:?}", question));
+
+        // Names from the hosts files are ours to answer
+        let local = context
+            .hosts
+            .answer(&question.name, question.qtype, question.qclass);
+        packet.header.authoritative_answer = local.is_some();
+
+        let result = match local {
+            Some(answer) => Ok(answer),
+            None => resolver.resolve_with(
+                &question.name,
+                question.qtype,
+                question.qclass,
+                request.header.checking_disabled,
+            ),
+        };
+        let qtype = question.qtype;
+        packet.questions.push(question);
+
+        if let Ok(result) = result {
+            packet.header.result_code = result.header.result_code;
+            // AD only goes to clients showing they understand it (RFC 6840 5.7)
+            packet.header.authed_data =
+                result.header.authed_data && (dnssec_ok || request.header.authed_data);
+
+            // DNSSEC records are left out unless the client set DO or asked
+            // for them by type (RFC 4035 3.2.1)
+            let wanted = |record: &DnsRecord| {
+                dnssec_ok
+                    || record.qtype() == qtype
+                    || !matches!(
+                        record.qtype(),
+                        QueryType::RRSIG | QueryType::NSEC | QueryType::NSEC3
+                    )
+            };
+
+            for rec in result.answers.into_iter().filter(wanted) {
+                context.trace(format_args!("Answer: {:?}", rec));
+                packet.answers.push(rec);
+            }
+            for rec in result.authorities.into_iter().filter(wanted) {
+                context.trace(format_args!("Authority: {:?}", rec));
+                packet.authorities.push(rec);
+            }
+            for rec in result.additionals.into_iter().filter(wanted) {
+                context.trace(format_args!("Resource: {:?}", rec));
+                packet.additionals.push(rec);
+            }
+        } else if let Err(e) = result {
+            warn!("Resolution failed: {}", e);
+            packet.header.result_code = ResultCode::SERVFAIL;
+        }
+    } else {
+        packet.header.result_code = ResultCode::FORMERR;
+    }
+
+    // Clients using EDNS get an OPT record back (RFC 6891 7)
+    if request.edns().is_some() {
+        packet.set_edns(EDNS_PACKET_SIZE, dnssec_ok);
+    }
+
+    packet.header.questions_count = packet.questions.len() as u16;
+    packet.header.answers_count = packet.answers.len() as u16;
+    packet.header.authority_records_count = packet.authorities.len() as u16;
+    packet.header.additional_records_count = packet.additionals.len() as u16;
+
+    context.trace(format_args!("{:#?}", packet));
+
+    packet
+}
+
+fn handle_query(
+    socket: &UdpSocket,
+    resolver: &Resolver,
+    mut req_buffer: BytePacketBuffer,
+    src: SocketAddr,
+) -> Result<()> {
+    let request = DnsPacket::from_buffer(&mut req_buffer)?;
+    let size = request.udp_payload_size().min(EDNS_PACKET_SIZE);
+    let mut packet = build_response(resolver, request);
+
+    // Replies that do not fit into a datagram are sent with only the question
+    // and the TC bit set, so that the client retries over TCP
+    let mut res_buffer = BytePacketBuffer::with_size(size);
+    if packet.to_buffer(&mut res_buffer).is_err() {
+        packet.header.truncated_message = true;
+        packet.answers.clear();
+        packet.authorities.clear();
+        packet
+            .additionals
+            .retain(|record| record.qtype() == QueryType::OPT);
+        packet.header.answers_count = 0;
+        packet.header.authority_records_count = 0;
+        packet.header.additional_records_count = packet.additionals.len() as u16;
+
+        res_buffer = BytePacketBuffer::with_size(size);
+        packet.to_buffer(&mut res_buffer)?;
+    }
+
+    let len = res_buffer.pos();
+    let data = res_buffer.get_range(0, len)?;
+
+    socket.send_to(data, src)?;
+
+    Ok(())
+}
+
+fn handle_tcp_connection(
+    mut stream: TcpStream,
+    resolver: &Resolver,
+    queries: &ConcurrencyLimit,
+) -> Result<()> {
+    stream.set_read_timeout(Some(TCP_IDLE_TIMEOUT))?;
+
+    // A client may send several queries over the same connection
+    loop {
+        let mut req_buffer = match read_tcp_message(&mut stream) {
+            Ok(x) => x,
+            Err(_) => return Ok(()),
+        };
+
+        let request = DnsPacket::from_buffer(&mut req_buffer)?;
+        let packet = {
+            let _slot = queries.acquire();
+            build_response(resolver, request)
+        };
+
+        let mut res_buffer = BytePacketBuffer::with_size(MAX_TCP_SIZE);
+        packet.to_buffer(&mut res_buffer)?;
+
+        let len = res_buffer.pos();
+        write_tcp_message(&mut stream, res_buffer.get_range(0, len)?)?;
+    }
+}
+
+// Every query is resolved on a thread of its own. Datagrams arriving while
+// the limit is reached are dropped, the client will retry
+fn serve_udp<'scope>(
+    scope: &'scope Scope<'scope, '_>,
+    socket: &'scope UdpSocket,
+    resolver: &'scope Resolver,
+    queries: &'scope ConcurrencyLimit,
+) -> ! {
+    loop {
+        let mut req_buffer = BytePacketBuffer::new();
+        let src = match socket.recv_from(&mut req_buffer.buf) {
+            Ok((_, src)) => src,
+            Err(e) => {
+                warn!("An error occurred: {}", e);
+                continue;
+            }
+        };
+
+        let Some(slot) = queries.try_acquire() else {
+            warn!(
+                "dropping query from {}, {} queries in flight",
+                src, queries.max
+            );
+            continue;
+        };
+
+        let spawned = thread::Builder::new().spawn_scoped(scope, move || {
+            let _slot = slot;
+            if let Err(e) = handle_query(socket, resolver, req_buffer, src) {
+                warn!("An error occurred: {}", e);
+            }
+        });
+        if let Err(e) = spawned {
+            error!("Failed to start a thread for the query from {}: {}", src, e);
+        }
+    }
+}
+
+// Connections are served on threads of their own, their queries counting
+// towards the same limit as those over UDP
+fn serve_tcp<'scope>(
+    scope: &'scope Scope<'scope, '_>,
+    listener: &'scope TcpListener,
+    resolver: &'scope Resolver,
+    queries: &'scope ConcurrencyLimit,
+    connections: &'scope ConcurrencyLimit,
+) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("An error occurred on a TCP connection: {}", e);
+                continue;
+            }
+        };
+
+        let Some(slot) = connections.try_acquire() else {
+            warn!(
+                "closing TCP connection, {} connections open",
+                connections.max
+            );
+            continue;
+        };
+
+        let spawned = thread::Builder::new().spawn_scoped(scope, move || {
+            let _slot = slot;
+            if let Err(e) = handle_tcp_connection(stream, resolver, queries) {
+                warn!("An error occurred on a TCP connection: {}", e);
+            }
+        });
+        if let Err(e) = spawned {
+            error!("Failed to start a thread for a TCP connection: {}", e);
+        }
+    }
+}
+
+fn dump_infra_cache(context: &ServerContext, interval: Duration) {
+    loop {
+        thread::sleep(interval);
+
+        info!("infrastructure cache:");
+        for (addr, stats) in context.infra_cache.snapshot() {
+            info!(
+                "  {:<15} srtt {:>6}ms  failures {:>3}  successes {:>6}",
+                addr,
+                stats.srtt.as_millis(),
+                stats.failures,
+                stats.successes
+            );
+        }
+    }
+}
+
+// Serves clients on the configured port until the process is stopped
+pub fn run(resolver: &Resolver) -> Result<()> {
+    let context = resolver.context();
+
+    let socket = UdpSocket::bind(("0.0.0.0", context.config.port))?;
+    let listener = TcpListener::bind(("0.0.0.0", context.config.port))?;
+
+    let queries = ConcurrencyLimit::new(context.config.max_concurrent_queries);
+    let connections = ConcurrencyLimit::new(MAX_TCP_CONNECTIONS);
+
+    thread::scope(|scope| {
+        scope.spawn(|| serve_tcp(scope, &listener, resolver, &queries, &connections));
+
+        if let Some(interval) = context.config.infra_dump_interval {
+            scope.spawn(move || dump_infra_cache(context, interval));
+        }
+
+        serve_udp(scope, &socket, resolver, &queries)
+    })
+}
@@ -1,2 +1,21 @@
+use std::fmt;
+
 pub type Error = Box<dyn std::error::Error>;
 pub type Result<T> = std::result::Result<T, Error>;
+
+// No answer could be had from upstream in time, as opposed to an answer that
+// was wrong. Only these failures may be covered up with stale data
+#[derive(Debug)]
+pub struct Unavailable(pub String);
+
+impl fmt::Display for Unavailable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Unavailable {}
+
+pub fn is_unavailable(e: &Error) -> bool {
+    e.is::<Unavailable>()
+}
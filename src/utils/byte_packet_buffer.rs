@@ -1,14 +1,26 @@
 use crate::types::Result;
 
+// Classic DNS over UDP limit (RFC 1035 4.2.1), TCP messages carry a two byte
+// length prefix and may be up to 65535 bytes long (RFC 1035 4.2.2)
+pub const MAX_UDP_SIZE: usize = 512;
+pub const MAX_TCP_SIZE: usize = 65535;
+// UDP payload size advertised with EDNS, small enough to avoid IP
+// fragmentation as recommended by DNS Flag Day 2020
+pub const EDNS_PACKET_SIZE: usize = 1232;
+
 pub struct BytePacketBuffer {
-    pub buf: [u8; 512],
+    pub buf: Vec<u8>,
     pub pos: usize,
 }
 
 impl BytePacketBuffer {
     pub fn new() -> BytePacketBuffer {
+        BytePacketBuffer::with_size(MAX_UDP_SIZE)
+    }
+
+    pub fn with_size(size: usize) -> BytePacketBuffer {
         BytePacketBuffer {
-            buf: [0; 512],
+            buf: vec![0; size],
             pos: 0,
         }
     }
@@ -30,13 +42,16 @@ impl BytePacketBuffer {
     }
 
     pub fn get(&mut self, pos: usize) -> Result<u8> {
-        if self.pos >= 512 {
+        if pos >= self.buf.len() {
             return Err("Buffer overflow".into());
         }
         Ok(self.buf[pos])
     }
 
     pub fn set_u8(&mut self, pos: usize, val: u8) -> Result<()> {
+        if pos >= self.buf.len() {
+            return Err("Buffer overflow".into());
+        }
         self.buf[pos] = val;
 
         Ok(())
@@ -50,14 +65,14 @@ impl BytePacketBuffer {
     }
 
     pub fn get_range(&mut self, start: usize, len: usize) -> Result<&[u8]> {
-        if self.pos >= 512 {
+        if start + len > self.buf.len() {
             return Err("Buffer overflow".into());
         }
         Ok(&self.buf[start..start + len])
     }
 
     pub fn read_u8(&mut self) -> Result<u8> {
-        if self.pos >= 512 {
+        if self.pos >= self.buf.len() {
             return Err("Buffer overflow".into());
         }
         let res = self.buf[self.pos];
@@ -67,7 +82,7 @@ impl BytePacketBuffer {
     }
 
     pub fn read_u16(&mut self) -> Result<u16> {
-        if self.pos >= 512 {
+        if self.pos >= self.buf.len() {
             return Err("Buffer overflow".into());
         }
         let res = ((self.read_u8()? as u16) << 8) | (self.read_u8()? as u16);
@@ -76,7 +91,7 @@ impl BytePacketBuffer {
     }
 
     pub fn read_u32(&mut self) -> Result<u32> {
-        if self.pos >= 512 {
+        if self.pos >= self.buf.len() {
             return Err("Buffer overflow".into());
         }
         let res = ((self.read_u8()? as u32) << 24)
@@ -88,7 +103,7 @@ impl BytePacketBuffer {
     }
 
     pub fn write_u8(&mut self, val: u8) -> Result<()> {
-        if self.pos >= 512 {
+        if self.pos >= self.buf.len() {
             return Err("Buffer overflow".into());
         }
         self.buf[self.pos] = val;
@@ -153,7 +168,7 @@ impl BytePacketBuffer {
             domain.push_str(delim);
 
             let str_buffer = self.get_range(pos, len as usize)?;
-            domain.push_str(&String::from_utf8_lossy(str_buffer).to_lowercase());
+            domain.push_str(&String::from_utf8_lossy(str_buffer));
 
             delim = ".";
 
@@ -167,8 +182,8 @@ impl BytePacketBuffer {
         Ok(domain)
     }
 
-    pub fn write_qname(&mut self, qname: &String) -> Result<()> {
-        for label in qname.split('.') {
+    pub fn write_qname(&mut self, qname: &str) -> Result<()> {
+        for label in qname.split('.').filter(|label| !label.is_empty()) {
             let len = label.len();
             if len > 63 {
                 return Err("Label max length of 63 exeeded".into());
@@ -185,3 +200,9 @@ impl BytePacketBuffer {
         Ok(())
     }
 }
+
+impl Default for BytePacketBuffer {
+    fn default() -> BytePacketBuffer {
+        BytePacketBuffer::new()
+    }
+}
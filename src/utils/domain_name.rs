@@ -0,0 +1,95 @@
+// Helpers for comparing domain names label by label. Names are in the
+// dotted form produced by BytePacketBuffer::read_name, the root being ""
+
+use std::cmp::Ordering;
+
+pub fn is_subdomain(name: &str, zone: &str) -> bool {
+    if zone.is_empty() {
+        return true;
+    }
+
+    let name = name.as_bytes();
+    let zone = zone.as_bytes();
+
+    if name.len() < zone.len() || !name[name.len() - zone.len()..].eq_ignore_ascii_case(zone) {
+        return false;
+    }
+
+    name.len() == zone.len() || name[name.len() - zone.len() - 1] == b'.'
+}
+
+pub fn parent(name: &str) -> Option<&str> {
+    if name.is_empty() {
+        return None;
+    }
+
+    match name.find('.') {
+        Some(idx) => Some(&name[idx + 1..]),
+        None => Some(""),
+    }
+}
+
+pub fn label_count(name: &str) -> usize {
+    if name.is_empty() {
+        return 0;
+    }
+
+    name.split('.').count()
+}
+
+// Returns the suffix of name made of its last count labels
+pub fn last_labels(name: &str, count: usize) -> &str {
+    let mut suffix = name;
+
+    for _ in count..label_count(name) {
+        suffix = match parent(suffix) {
+            Some(x) => x,
+            None => break,
+        };
+    }
+
+    suffix
+}
+
+// Longest suffix of whole labels shared by both names
+pub fn common_ancestor<'a>(name: &'a str, other: &str) -> &'a str {
+    let mut ancestor = name;
+
+    while !is_subdomain(other, ancestor) {
+        ancestor = match parent(ancestor) {
+            Some(x) => x,
+            None => break,
+        };
+    }
+
+    ancestor
+}
+
+// Canonical DNS name order (RFC 4034 6.1): names are compared label by label
+// from the root down, each label as a lowercase octet string, and a name
+// sorts before its descendants
+pub fn canonical_cmp(name: &str, other: &str) -> Ordering {
+    let labels = |name: &str| -> Vec<Vec<u8>> {
+        name.split('.')
+            .filter(|label| !label.is_empty())
+            .rev()
+            .map(|label| label.to_ascii_lowercase().into_bytes())
+            .collect()
+    };
+
+    labels(name).cmp(&labels(other))
+}
+
+// Uncompressed wire format of the name in lowercase, as hashed and signed
+// by DNSSEC (RFC 4034 6.2)
+pub fn canonical_wire(name: &str) -> Vec<u8> {
+    let mut wire = Vec::with_capacity(name.len() + 2);
+
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        wire.push(label.len() as u8);
+        wire.extend(label.bytes().map(|byte| byte.to_ascii_lowercase()));
+    }
+    wire.push(0);
+
+    wire
+}
@@ -0,0 +1,133 @@
+use crate::types::Result;
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+const BASE64URL_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+const BASE32HEX_ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHIJKLMNOPQRSTUV";
+
+fn encode_base64(data: &[u8], alphabet: &[u8; 64], padding: bool) -> String {
+    let mut output = String::with_capacity(data.len().div_ceil(3) * 4);
+
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let group = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+
+        for index in 0..=chunk.len() {
+            output.push(alphabet[(group >> (18 - 6 * index) & 0x3f) as usize] as char);
+        }
+        if padding {
+            for _ in chunk.len()..3 {
+                output.push('=');
+            }
+        }
+    }
+
+    output
+}
+
+// Standard base64 with padding (RFC 4648 4), as in the presentation format
+// of DNSKEY and RRSIG records
+pub fn base64_encode(data: &[u8]) -> String {
+    encode_base64(data, BASE64_ALPHABET, true)
+}
+
+// The URL and filename safe variant without padding, as used in the dns
+// parameter of DNS over HTTPS GET requests (RFC 8484 4.1)
+pub fn base64url_encode(data: &[u8]) -> String {
+    encode_base64(data, BASE64URL_ALPHABET, false)
+}
+
+// Standard base64 with padding (RFC 4648 4), whitespace is ignored
+pub fn base64_decode(text: &str) -> Result<Vec<u8>> {
+    let mut output = Vec::with_capacity(text.len() * 3 / 4);
+    let mut accumulator = 0u32;
+    let mut bits = 0;
+    let mut padding = 0;
+
+    for c in text.bytes().filter(|c| !c.is_ascii_whitespace()) {
+        if c == b'=' {
+            padding += 1;
+            continue;
+        }
+
+        if padding > 0 {
+            return Err(format!("Invalid base64 {}: data after padding", text).into());
+        }
+
+        let value = BASE64_ALPHABET
+            .iter()
+            .position(|&x| x == c)
+            .ok_or_else(|| format!("Invalid base64 {}: unexpected {:?}", text, c as char))?;
+
+        accumulator = (accumulator << 6) | value as u32;
+        bits += 6;
+
+        if bits >= 8 {
+            bits -= 8;
+            output.push((accumulator >> bits) as u8);
+        }
+    }
+
+    if padding > 2 || bits >= 6 {
+        return Err(format!("Invalid base64 {}: truncated", text).into());
+    }
+
+    Ok(output)
+}
+
+// Base32 with the extended hex alphabet and without padding, as used for the
+// hashed owner names of NSEC3 records (RFC 5155 3.3)
+pub fn base32hex_encode(data: &[u8]) -> String {
+    let mut output = String::with_capacity(data.len().div_ceil(5) * 8);
+    let mut accumulator = 0u32;
+    let mut bits = 0;
+
+    for byte in data {
+        accumulator = (accumulator << 8) | *byte as u32;
+        bits += 8;
+
+        while bits >= 5 {
+            bits -= 5;
+            output.push(BASE32HEX_ALPHABET[(accumulator >> bits & 0x1f) as usize] as char);
+        }
+    }
+
+    if bits > 0 {
+        output.push(BASE32HEX_ALPHABET[(accumulator << (5 - bits) & 0x1f) as usize] as char);
+    }
+
+    output
+}
+
+pub fn hex_encode(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02X}", byte)).collect()
+}
+
+// Whitespace is ignored, as the digest of a DS record may be split in
+// presentation format
+pub fn hex_decode(text: &str) -> Result<Vec<u8>> {
+    let digits: Vec<u8> = text
+        .bytes()
+        .filter(|c| !c.is_ascii_whitespace())
+        .map(|c| {
+            (c as char)
+                .to_digit(16)
+                .map(|digit| digit as u8)
+                .ok_or_else(|| format!("Invalid hex {}: unexpected {:?}", text, c as char))
+        })
+        .collect::<std::result::Result<Vec<u8>, String>>()?;
+
+    if !digits.len().is_multiple_of(2) {
+        return Err(format!("Invalid hex {}: odd number of digits", text).into());
+    }
+
+    Ok(digits
+        .chunks(2)
+        .map(|pair| (pair[0] << 4) | pair[1])
+        .collect())
+}
@@ -1 +1,3 @@
-pub mod byte_packet_buffer;
\ No newline at end of file
+pub mod byte_packet_buffer;
+pub mod domain_name;
+pub mod encoding;